@@ -0,0 +1,143 @@
+//! FHIR [Bundle](<https://hl7.org/fhir/bundle.html>) responses.
+//!
+//! Only the parts of the Bundle resource that are used by the API are modelled here.
+
+use axum::{
+    Json,
+    http::{HeaderMap, HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+/// The media type of FHIR resources in JSON format.
+pub const FHIR_JSON: &str = "application/fhir+json";
+
+/// [BundleType](<https://hl7.org/fhir/valueset-bundle-type.html>)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "kebab-case")]
+pub enum BundleType {
+    Searchset,
+}
+
+/// [SearchEntryMode](<https://hl7.org/fhir/valueset-search-entry-mode.html>)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchEntryMode {
+    /// The entry matched the search parameters.
+    Match,
+
+    /// The entry was added, because it is referenced by a matching entry.
+    Include,
+
+    /// The entry contains information about the search, like warnings.
+    Outcome,
+}
+
+/// A link to another page of a Bundle, e.g. the `next` page of a search.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BundleLink {
+    /// The relation of the link, like `self`, `next` or `previous`.
+    pub relation: String,
+    pub url: String,
+}
+
+/// Information about why an entry is part of a search result.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+pub struct BundleEntrySearch {
+    pub mode: SearchEntryMode,
+}
+
+/// A single entry of a Bundle.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleEntry {
+    /// The absolute URL of the resource.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub full_url: Option<String>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub resource: Option<Value>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub search: Option<BundleEntrySearch>,
+}
+
+/// A FHIR Bundle resource.
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct Bundle {
+    /// Always `Bundle`.
+    pub resource_type: String,
+
+    #[serde(rename = "type")]
+    pub type_: BundleType,
+
+    /// The total number of matches, if it was requested.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<i64>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub link: Vec<BundleLink>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub entry: Vec<BundleEntry>,
+}
+
+impl Bundle {
+    /// Creates an empty Bundle of the given type.
+    pub fn new(type_: BundleType) -> Self {
+        Self {
+            resource_type: "Bundle".to_string(),
+            type_,
+            total: None,
+            link: Vec::new(),
+            entry: Vec::new(),
+        }
+    }
+
+    /// Adds a link with the given relation.
+    pub fn push_link(&mut self, relation: &str, url: String) {
+        self.link.push(BundleLink {
+            relation: relation.to_string(),
+            url,
+        });
+    }
+}
+
+impl IntoResponse for Bundle {
+    fn into_response(self) -> Response {
+        (
+            [(header::CONTENT_TYPE, HeaderValue::from_static(FHIR_JSON))],
+            Json(self),
+        )
+            .into_response()
+    }
+}
+
+/// Checks if the client asked for FHIR responses via the `Accept` header.
+pub fn accepts_fhir_json(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|media_type| media_type.trim().starts_with(FHIR_JSON))
+}
+
+/// The base URL of the server, as seen by the client.
+///
+/// Respects the `X-Forwarded-Proto` header, so the URL is correct behind a proxy.
+pub fn base_url(headers: &HeaderMap) -> String {
+    let scheme = headers
+        .get("x-forwarded-proto")
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("http");
+    let host = headers
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or("localhost");
+
+    format!("{scheme}://{host}")
+}
@@ -7,6 +7,11 @@ pub struct Configuration {
     pub env: Environment,
 
     pub database_url: String,
+
+    /// Allows the `_explain` search parameter, which exposes the generated SQL
+    /// and query plan of searches. This should only be enabled for administrators.
+    #[serde(default)]
+    pub search_explain: bool,
 }
 
 impl Configuration {
@@ -2,13 +2,24 @@ use std::sync::Arc;
 
 use axum::{
     BoxError, Json,
-    http::StatusCode,
+    http::{HeaderValue, StatusCode, header},
     response::{IntoResponse, Response},
 };
 use serde::Serialize;
+use serde_json::{Value, json};
+
+use crate::bundle::FHIR_JSON;
 
 pub type Result<T, E = AppError> = std::result::Result<T, E>;
 
+/// The SQLSTATE of errors caused by invalid request parameters, like `_summary=true`
+/// for a resource type whose summary elements are not known.
+const INVALID_PARAMETER_VALUE: &str = "22023";
+
+/// The SQLSTATE of the errors returned by the functions of the extension, like an
+/// unknown search key or an invalid search value.
+const DATA_EXCEPTION: &str = "22000";
+
 /// JSON error response structure.
 #[derive(Debug, Serialize)]
 pub struct ErrorResponse {
@@ -24,6 +35,22 @@ pub enum AppError {
     #[error("bad request")]
     BadRequest(Option<&'static str>),
 
+    /// The request is invalid, described by the contained `OperationOutcome`.
+    #[error("bad request")]
+    Invalid(Value),
+
+    /// The `Accept` header asks for a FHIR version that is not supported.
+    #[error("not acceptable")]
+    NotAcceptable,
+
+    /// The request body uses a FHIR version that is not supported.
+    #[error("unsupported media type")]
+    UnsupportedMediaType,
+
+    /// The resource is invalid, described by the contained `OperationOutcome`.
+    #[error("unprocessable entity")]
+    Unprocessable(Value),
+
     #[error("internal error")]
     Internal(
         #[source]
@@ -43,14 +70,65 @@ pub enum AppError {
     ),
 }
 
+impl AppError {
+    /// Converts a database error, returning `400 Bad Request` with an
+    /// `OperationOutcome` if the extension rejected a parameter of the request.
+    pub fn from_query(error: sqlx::Error) -> Self {
+        let issue = error.as_database_error().and_then(|error| {
+            let code = match error.code().as_deref() {
+                Some(INVALID_PARAMETER_VALUE) => "not-supported",
+                Some(DATA_EXCEPTION) => "invalid",
+                _ => return None,
+            };
+            Some((code, error.message().to_string()))
+        });
+
+        match issue {
+            Some((code, message)) => AppError::Invalid(json!({
+                "resourceType": "OperationOutcome",
+                "issue": [{
+                    "severity": "error",
+                    "code": code,
+                    "diagnostics": message,
+                }],
+            })),
+            None => AppError::Database(error),
+        }
+    }
+}
+
 impl IntoResponse for AppError {
     fn into_response(self) -> Response {
-        let (status, message) = match self {
+        let (status, message) = match &self {
             AppError::Internal(..) | AppError::InternalBoxed(..) | AppError::Database(..) => {
                 (StatusCode::INTERNAL_SERVER_ERROR, "internal server error")
             }
             AppError::NotFound => (StatusCode::NOT_FOUND, "not found"),
             AppError::BadRequest(msg) => (StatusCode::BAD_REQUEST, msg.unwrap_or("bad request")),
+            AppError::NotAcceptable => (
+                StatusCode::NOT_ACCEPTABLE,
+                "the requested FHIR version is not supported",
+            ),
+            AppError::UnsupportedMediaType => (
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "the FHIR version of the request is not supported",
+            ),
+            AppError::Invalid(outcome) | AppError::Unprocessable(outcome) => {
+                let status = if matches!(self, AppError::Invalid(..)) {
+                    StatusCode::BAD_REQUEST
+                } else {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                };
+                let mut response = (
+                    status,
+                    [(header::CONTENT_TYPE, HeaderValue::from_static(FHIR_JSON))],
+                    Json(outcome.clone()),
+                )
+                    .into_response();
+                response.extensions_mut().insert(Arc::new(self));
+
+                return response;
+            }
         };
 
         let mut response = (
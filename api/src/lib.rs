@@ -2,11 +2,13 @@ use std::sync::Arc;
 
 use sqlx::PgPool;
 
-use crate::config::Configuration;
+use crate::{config::Configuration, version::BundledVersion};
 
+pub mod bundle;
 pub mod config;
 pub mod error;
 pub mod routes;
+pub mod version;
 
 /// Central application state that is shared across all parts of the API.
 #[derive(Clone)]
@@ -16,4 +18,11 @@ pub struct AppState {
 
     /// The database connection pool.
     pub db: PgPool,
+
+    /// The full number of the FHIR version of the database, like `4.3.0`.
+    pub fhir_version: Arc<str>,
+
+    /// All FHIR versions that are bundled in the extension, which can be requested
+    /// using the `fhirVersion` parameter of the FHIR media type.
+    pub fhir_versions: Arc<[BundledVersion]>,
 }
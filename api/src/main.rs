@@ -1,16 +1,22 @@
 use std::sync::Arc;
 
-use api::{AppState, config::Configuration, error::AppError, routes};
+use api::{
+    AppState,
+    config::Configuration,
+    error::AppError,
+    routes,
+    version::{self, BundledVersion},
+};
 use axum::{
     Router,
     extract::Request,
-    middleware::{Next, from_fn},
+    middleware::{Next, from_fn, from_fn_with_state},
     response::Response,
 };
 use axum_tracing_opentelemetry::middleware::{OtelAxumLayer, OtelInResponseLayer};
 use eyre::{Context as _, Result};
 use init_tracing_opentelemetry::TracingConfig;
-use sqlx::postgres::PgPoolOptions;
+use sqlx::postgres::{PgConnectOptions, PgPoolOptions};
 use tokio::net::TcpListener;
 use tower_http::trace::TraceLayer;
 use tracing::{info, warn};
@@ -49,9 +55,13 @@ async fn main() -> Result<()> {
     let _guard = tracing_config.init_subscriber()?;
 
     // connect to database
+    let connect_options = config
+        .database_url
+        .parse::<PgConnectOptions>()
+        .wrap_err("invalid database url")?;
     let db = PgPoolOptions::new()
         .max_connections(10)
-        .connect(&config.database_url)
+        .connect_with(connect_options.clone())
         .await
         .wrap_err("could not initialize database connection")?;
 
@@ -61,9 +71,39 @@ async fn main() -> Result<()> {
         .wrap_err("health check to database failed")?;
     info!("database connection established");
 
+    let fhir_version: String = sqlx::query_scalar("SELECT fhir_version()")
+        .fetch_one(&db)
+        .await
+        .wrap_err("failed to query the FHIR version of the database")?;
+    info!(fhir_version, "using FHIR version");
+
+    // Other versions get their own connections, which are only opened when a request
+    // asks for that version.
+    let bundled: Vec<(String, String)> =
+        sqlx::query_as("SELECT name, number FROM fhir_bundled_versions()")
+            .fetch_all(&db)
+            .await
+            .wrap_err("failed to query the bundled FHIR versions")?;
+    let fhir_versions = bundled
+        .into_iter()
+        .map(|(name, number)| {
+            let db = if number == fhir_version {
+                db.clone()
+            } else {
+                PgPoolOptions::new()
+                    .max_connections(10)
+                    .connect_lazy_with(connect_options.clone().options([("fhir.version", &name)]))
+            };
+
+            BundledVersion { name, number, db }
+        })
+        .collect();
+
     let state = AppState {
         config: Arc::new(config),
         db,
+        fhir_version: fhir_version.into(),
+        fhir_versions,
     };
 
     // construct the axum router
@@ -73,6 +113,10 @@ async fn main() -> Result<()> {
         .layer(TraceLayer::new_for_http())
         .merge(routes::build_router())
         // must be after route registration, in order to run correctly
+        .layer(from_fn_with_state(
+            state.clone(),
+            version::negotiate_fhir_version,
+        ))
         .layer(from_fn(log_app_error))
         .with_state(state);
 
@@ -1,18 +1,22 @@
 //! The create FHIR resource route.
 
-use axum::{
-    Json,
-    extract::{Path, State},
-};
+use axum::{Json, extract::Path};
 use eyre::eyre;
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use sqlx::query;
+use sqlx::{postgres::PgDatabaseError, query};
 use tracing::instrument;
 use utoipa::ToSchema;
 use uuid::Uuid;
 
-use crate::{AppState, error::Result};
+use crate::{
+    AppState,
+    error::{AppError, Result},
+    version::VersionedState,
+};
+
+/// The SQLSTATE of the error that `fhir_put` raises for invalid entities.
+const CHECK_VIOLATION: &str = "23514";
 
 /// Entity successfully created.
 #[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
@@ -24,6 +28,9 @@ pub struct CreateResponse {
 ///
 /// The resource type path parameter will be inserted into the body as the `resourceType` key.
 /// If an existing `resourceType` field already exists in the data, the value will be overwritten.
+/// If the entity does not match the FHIR schema, an `OperationOutcome` describing the
+/// validation errors is returned, unless the validation mode of the resource type is
+/// `warn` or `off`.
 #[utoipa::path(
     post,
     path = "/fhir/{resource}",
@@ -32,21 +39,34 @@ pub struct CreateResponse {
         ("resource", description = "The FHIR resource type to insert"),
     ),
     responses(
-        (status = 201, description = "Entity inserted successfully", body = inline(CreateResponse))
+        (status = 201, description = "Entity inserted successfully", body = inline(CreateResponse)),
+        (status = 422, description = "The entity is invalid, returns an OperationOutcome", content(
+            (Value = "application/fhir+json"),
+        )),
     )
 )]
 #[instrument(skip(db))]
-#[axum::debug_handler]
+#[axum::debug_handler(state = AppState)]
 pub async fn fhir_create(
-    State(AppState { db, .. }): State<AppState>,
+    VersionedState(AppState { db, .. }): VersionedState,
     Path(resource): Path<String>,
     Json(mut body): Json<serde_json::Map<String, Value>>,
 ) -> Result<Json<CreateResponse>> {
-    body.insert("resourceType".to_string(), resource.into());
+    body.insert("resourceType".to_string(), resource.clone().into());
+    let body = Value::Object(body);
 
-    let inserted = query!("SELECT fhir_put($1) as id", Value::Object(body))
+    // Invalid entities are only rejected in the `strict` validation mode, with the
+    // `OperationOutcome` of the errors as the detail of the error.
+    let inserted = match query!("SELECT fhir_put($1) as id", body)
         .fetch_one(&db)
-        .await?;
+        .await
+    {
+        Ok(inserted) => inserted,
+        Err(error) => match rejection_outcome(&error) {
+            Some(outcome) => return Err(AppError::Unprocessable(outcome)),
+            None => return Err(error.into()),
+        },
+    };
 
     let Some(id) = inserted.id else {
         return Err(eyre!("`fhir_put` did not return a row").into());
@@ -54,3 +74,14 @@ pub async fn fhir_create(
 
     Ok(Json(CreateResponse { id }))
 }
+
+/// Returns the `OperationOutcome` of an entity that was rejected by `fhir_put`.
+fn rejection_outcome(error: &sqlx::Error) -> Option<Value> {
+    let error = error.as_database_error()?;
+    if error.code().as_deref() != Some(CHECK_VIOLATION) {
+        return None;
+    }
+
+    let detail = error.try_downcast_ref::<PgDatabaseError>()?.detail()?;
+    serde_json::from_str(detail).ok()
+}
@@ -0,0 +1,233 @@
+//! The Patient `$everything` operation.
+
+use axum::{
+    extract::{OriginalUri, Path, Query},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::json;
+use sqlx::query;
+use time::OffsetDateTime;
+use tracing::instrument;
+use utoipa::IntoParams;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    bundle::{self, Bundle, BundleEntry, BundleEntrySearch, BundleType, SearchEntryMode},
+    error::{AppError, Result},
+    routes::list::{default_count, deserialize_count, page_url, paging_cursor, self_url},
+    version::VersionedState,
+};
+
+/// Query parameters for the `$everything` operation.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct EverythingQueryParams {
+    /// The number of resources per page, which is clamped to `1..=100`.
+    #[serde(rename = "_count")]
+    #[serde(default = "default_count", deserialize_with = "deserialize_count")]
+    #[param(minimum = 1, maximum = 100, default = 20)]
+    count: i64,
+
+    /// Continue after the given cursor, taken from the `next` link.
+    #[serde(rename = "_cursor")]
+    cursor: Option<String>,
+
+    /// Return the page right before the given cursor, taken from the `previous` link.
+    #[serde(rename = "_before")]
+    before: Option<String>,
+
+    /// Only return resources that were changed at or after this time.
+    #[serde(rename = "_since")]
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    since: Option<OffsetDateTime>,
+
+    /// Comma separated list of the resource types to return, e.g. `Observation,Encounter`.
+    #[serde(rename = "_type")]
+    types: Option<String>,
+}
+
+/// Get everything about a patient
+///
+/// Returns a `searchset` Bundle with the patient, all resources in its compartment,
+/// and the practitioners, organizations and medications they reference.
+/// The Bundle is paginated using the `previous` and `next` links.
+///
+/// Resource types of the compartment whose linking search parameters are not indexed
+/// can't be returned. They are listed in an `OperationOutcome` entry with the search
+/// mode `outcome`.
+#[utoipa::path(
+    get,
+    path = "/fhir/Patient/{id}/$everything",
+    params(
+        ("id", description = "The unique UUID identifier of the patient"),
+        EverythingQueryParams,
+    ),
+    responses(
+        (status = 200, description = "Returns a paginated Bundle of the patient's resources", content(
+            (Bundle = "application/fhir+json"),
+        )),
+        (status = 404, description = "The patient does not exist"),
+    )
+)]
+#[instrument(skip(db))]
+#[axum::debug_handler(state = AppState)]
+pub async fn fhir_patient_everything(
+    VersionedState(AppState { db, .. }): VersionedState,
+    Path(id): Path<Uuid>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    Query(params): Query<EverythingQueryParams>,
+) -> Result<Response> {
+    let patient = query!("SELECT fhir_get($1, $2) as entity", "Patient", id)
+        .fetch_one(&db)
+        .await?;
+    if patient.entity.is_none() {
+        return Err(AppError::NotFound);
+    }
+
+    let (cursor, backwards) = paging_cursor(params.cursor, params.before)?;
+    let has_cursor = cursor.is_some();
+
+    let types = params.types.map(|types| {
+        types
+            .split(',')
+            .filter(|ty| !ty.is_empty())
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+    });
+
+    // Fetch one more entity than requested, to know if there is another page.
+    let mut entities = query!(
+        r#"
+    SELECT
+        id,
+        resource_type,
+        mode,
+        fhir_get(resource_type, id) as entity,
+        cursor
+    FROM
+        fhir_patient_everything($1, $2, $3, $4, $5, $6)
+    ORDER BY idx
+    "#,
+        id,
+        params.since,
+        types.as_deref(),
+        params.count + 1,
+        cursor,
+        backwards,
+    )
+    .fetch_all(&db)
+    .await?;
+
+    // When paging backwards, the additional entity is the first one.
+    let count = usize::try_from(params.count).unwrap_or_default();
+    let has_more = entities.len() > count;
+    if has_more && backwards {
+        entities.drain(..entities.len() - count);
+    } else if has_more {
+        entities.truncate(count);
+    }
+
+    let unindexed = query!("SELECT fhir_compartment_unindexed_types('Patient') as types")
+        .fetch_one(&db)
+        .await?
+        .types
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|ty| types.as_ref().is_none_or(|types| types.contains(ty)))
+        .collect::<Vec<_>>();
+
+    let path = format!("/fhir/Patient/{id}/$everything");
+    let query = uri.query().unwrap_or("");
+    let base_url = bundle::base_url(&headers);
+
+    let mut bundle = Bundle::new(BundleType::Searchset);
+    bundle.push_link("self", self_url(&base_url, &path, query));
+    bundle.push_link("first", page_url(&base_url, &path, query, None)?);
+    let previous_cursor = entities
+        .first()
+        .and_then(|e| e.cursor.clone())
+        .filter(|_| if backwards { has_more } else { has_cursor });
+    if let Some(cursor) = previous_cursor {
+        bundle.push_link(
+            "previous",
+            page_url(&base_url, &path, query, Some(("_before", &cursor)))?,
+        );
+    }
+    let next_cursor = entities
+        .last()
+        .and_then(|e| e.cursor.clone())
+        .filter(|_| has_more || backwards);
+    if let Some(cursor) = next_cursor {
+        bundle.push_link(
+            "next",
+            page_url(&base_url, &path, query, Some(("_cursor", &cursor)))?,
+        );
+    }
+
+    bundle.entry = entities
+        .into_iter()
+        .filter_map(|e| {
+            let mode = match e.mode?.as_str() {
+                "include" => SearchEntryMode::Include,
+                _ => SearchEntryMode::Match,
+            };
+
+            Some(BundleEntry {
+                full_url: Some(format!("{base_url}/fhir/{}/{}", e.resource_type?, e.id?)),
+                resource: Some(e.entity?),
+                search: Some(BundleEntrySearch { mode }),
+            })
+        })
+        .collect();
+    if !unindexed.is_empty() {
+        bundle.entry.push(unindexed_outcome(&unindexed));
+    }
+
+    Ok(bundle.into_response())
+}
+
+/// Builds the `outcome` entry, which lists the resource types of the compartment
+/// that are not returned, because they are not indexed.
+fn unindexed_outcome(types: &[String]) -> BundleEntry {
+    BundleEntry {
+        full_url: None,
+        resource: Some(json!({
+            "resourceType": "OperationOutcome",
+            "issue": [{
+                "severity": "warning",
+                "code": "incomplete",
+                "diagnostics": format!(
+                    "the following resource types of the Patient compartment are not indexed, and are not returned: {}",
+                    types.join(", ")
+                ),
+            }],
+        })),
+        search: Some(BundleEntrySearch {
+            mode: SearchEntryMode::Outcome,
+        }),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outcome_entry() {
+        let entry = unindexed_outcome(&["Condition".to_string(), "Encounter".to_string()]);
+        let json = serde_json::to_value(entry).unwrap();
+
+        assert_eq!(json["search"]["mode"], "outcome");
+        assert_eq!(json["resource"]["issue"][0]["code"], "incomplete");
+        assert!(
+            json["resource"]["issue"][0]["diagnostics"]
+                .as_str()
+                .unwrap()
+                .ends_with("Condition, Encounter")
+        );
+        assert!(json.get("fullUrl").is_none());
+    }
+}
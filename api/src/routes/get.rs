@@ -2,39 +2,109 @@
 
 use axum::{
     Json,
-    extract::{Path, State},
+    extract::{Path, Query},
 };
+use serde::Deserialize;
 use serde_json::Value;
 use sqlx::query;
 use tracing::instrument;
+use utoipa::{IntoParams, ToSchema};
 use uuid::Uuid;
 
 use crate::{
     AppState,
     error::{AppError, Result},
+    version::VersionedState,
 };
 
+/// Which parts of the entities are returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SummaryMode {
+    /// Only the elements that are marked as summary.
+    True,
+
+    /// Only the narrative and mandatory elements.
+    Text,
+
+    /// All elements except the narrative.
+    Data,
+
+    /// Only the total number of matches, without any entities.
+    ///
+    /// This is only supported by searches.
+    Count,
+
+    /// All elements.
+    False,
+}
+
+impl SummaryMode {
+    /// The value of the mode, as expected by `fhir_get`.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            SummaryMode::True => "true",
+            SummaryMode::Text => "text",
+            SummaryMode::Data => "data",
+            SummaryMode::Count => "count",
+            SummaryMode::False => "false",
+        }
+    }
+}
+
+/// Query parameters for the get operation.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct GetQueryParams {
+    /// Only return a summary of the entity.
+    #[serde(rename = "_summary")]
+    summary: Option<SummaryMode>,
+
+    /// Comma separated list of the elements to return, e.g. `name,birthDate`.
+    #[serde(rename = "_elements")]
+    elements: Option<String>,
+}
+
 /// Gets a FHIR entity by it's UUID
+///
+/// If the entity is reduced by `_summary` or `_elements`, it is tagged as `SUBSETTED`.
 #[utoipa::path(
     get,
     path = "/fhir/{resource}/{id}",
     params(
         ("resource", description = "The FHIR resource type to insert"),
+        GetQueryParams,
     ),
     responses(
         (status = 200, description = "Returns the found FHIR entity"),
+        (status = 400, description = "The summary elements of the resource type are not known, returns an OperationOutcome", content(
+            (Value = "application/fhir+json"),
+        )),
         (status = 404, description = "The entity does not exist"),
     )
 )]
 #[instrument(skip(db))]
-#[axum::debug_handler]
+#[axum::debug_handler(state = AppState)]
 pub async fn fhir_get(
-    State(AppState { db, .. }): State<AppState>,
+    VersionedState(AppState { db, .. }): VersionedState,
     Path((resource, id)): Path<(String, Uuid)>,
+    Query(params): Query<GetQueryParams>,
 ) -> Result<Json<Value>> {
-    let entity = query!("SELECT fhir_get($1, $2) as entity", resource, id)
-        .fetch_one(&db)
-        .await?;
+    if params.summary == Some(SummaryMode::Count) {
+        return Err(AppError::BadRequest(Some(
+            "`_summary=count` is only supported by searches",
+        )));
+    }
+
+    let entity = query!(
+        "SELECT fhir_get($1, $2, $3, $4) as entity",
+        resource,
+        id,
+        params.summary.map(SummaryMode::as_str),
+        params.elements,
+    )
+    .fetch_one(&db)
+    .await
+    .map_err(AppError::from_query)?;
 
     entity.entity.ok_or(AppError::NotFound).map(Json)
 }
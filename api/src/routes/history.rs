@@ -3,10 +3,7 @@
 //! This module provides functionality to retrieve the complete history of a FHIR entity,
 //! including all insert, update, and delete operations that have occurred over time.
 
-use axum::{
-    Json,
-    extract::{Path, State},
-};
+use axum::{Json, extract::Path};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use sqlx::query;
@@ -18,6 +15,7 @@ use uuid::Uuid;
 use crate::{
     AppState,
     error::{AppError, Result},
+    version::VersionedState,
 };
 
 /// Represents a single operation that was performed on a FHIR entity.
@@ -83,9 +81,9 @@ pub struct EntityHistoryResponse {
     )
 )]
 #[instrument(skip(db))]
-#[axum::debug_handler]
+#[axum::debug_handler(state = AppState)]
 pub async fn fhir_get_history(
-    State(AppState { db, .. }): State<AppState>,
+    VersionedState(AppState { db, .. }): VersionedState,
     Path((resource, id)): Path<(String, Uuid)>,
 ) -> Result<Json<EntityHistoryResponse>> {
     let current_entity = query!("SELECT fhir_get($1, $2) as entity", resource, id)
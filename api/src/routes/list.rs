@@ -3,36 +3,111 @@
 use std::collections::HashMap;
 
 use axum::{
-    Json,
-    extract::{Path, Query, State},
+    Form, Json,
+    extract::{OriginalUri, Path, Query},
+    http::{HeaderMap, HeaderValue, Uri},
+    response::{IntoResponse, Response},
 };
-use serde::Deserialize;
+use eyre::Context as _;
+use serde::{Deserialize, Deserializer};
 use serde_json::Value;
-use sqlx::query;
+use sqlx::{PgPool, query, query_as};
 use tracing::instrument;
-use utoipa::IntoParams;
+use utoipa::{IntoParams, ToSchema};
+use uuid::Uuid;
 
 use crate::{
     AppState,
+    bundle::{self, Bundle, BundleEntry, BundleEntrySearch, BundleType, SearchEntryMode},
     error::{AppError, Result},
+    routes::get::SummaryMode,
+    version::VersionedState,
 };
 
-const fn default_count() -> i64 {
+/// Response header that contains the cursor for the next page.
+const NEXT_CURSOR_HEADER: &str = "x-next-cursor";
+
+/// Query parameter that restricts a system search to some resource types.
+const TYPE_PARAM: &str = "_type";
+
+/// The maximum number of entities on a single page.
+const MAX_COUNT: i64 = 100;
+
+pub(super) const fn default_count() -> i64 {
     20
 }
 
+/// Deserializes `_count`, which is clamped to `1..=100`.
+pub(super) fn deserialize_count<'de, D>(deserializer: D) -> std::result::Result<i64, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Ok(i64::deserialize(deserializer)?.clamp(1, MAX_COUNT))
+}
+
+/// How the total number of matches is calculated.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum TotalMode {
+    /// The total is not calculated.
+    #[default]
+    None,
+
+    /// The total is estimated using the Postgres planner statistics.
+    Estimate,
+
+    /// All matches are counted.
+    Accurate,
+}
+
 /// Query parameters for list and search operations.
 #[derive(Debug, Deserialize, IntoParams)]
 pub struct ListQueryParams {
+    /// The number of entities per page, which is clamped to `1..=100`.
     #[serde(rename = "_count")]
-    #[serde(default = "default_count")]
+    #[serde(default = "default_count", deserialize_with = "deserialize_count")]
     #[param(minimum = 1, maximum = 100, default = 20)]
     count: i64,
 
-    #[serde(rename = "_offset")]
+    /// Continue the search after the given cursor.
+    ///
+    /// The cursor for the next page is returned in the `x-next-cursor` response header.
+    #[serde(rename = "_cursor")]
+    cursor: Option<String>,
+
+    /// Return the page right before the given cursor.
+    #[serde(rename = "_before")]
+    before: Option<String>,
+
+    /// Comma separated list of search parameters to sort by.
+    ///
+    /// Prefix a parameter with `-` to sort in descending order, e.g. `family,-birth_date`.
+    #[serde(rename = "_sort")]
+    sort: Option<String>,
+
+    /// How the total number of matches is calculated.
+    ///
+    /// The total is only part of Bundle responses.
+    #[serde(rename = "_total")]
+    #[serde(default)]
+    total: TotalMode,
+
+    /// Only return a summary of the entities.
+    ///
+    /// With `count`, only the total number of matches is returned as a Bundle.
+    #[serde(rename = "_summary")]
+    summary: Option<SummaryMode>,
+
+    /// Comma separated list of the elements to return, e.g. `name,birthDate`.
+    #[serde(rename = "_elements")]
+    elements: Option<String>,
+
+    /// Return the generated SQL and query plan of the search, instead of the results.
+    ///
+    /// This must be enabled using the `FHIR_SEARCH_EXPLAIN` configuration.
+    #[serde(rename = "_explain")]
     #[serde(default)]
-    #[param(minimum = 0, default = 0)]
-    offset: i64,
+    explain: bool,
 
     /// Search parameters as query string parameters.
     #[serde(flatten)]
@@ -40,6 +115,13 @@ pub struct ListQueryParams {
 }
 
 /// Search FHIR entities
+///
+/// If the `Accept` header contains `application/fhir+json`, the result is returned
+/// as a FHIR `searchset` Bundle, including paging links. Otherwise a plain list of
+/// entities is returned.
+///
+/// Entities referenced by `_include` and `_revinclude` parameters are returned after
+/// the matching entities, and do not count towards `_count`.
 #[utoipa::path(
     get,
     path = "/fhir/{resource}",
@@ -48,65 +130,941 @@ pub struct ListQueryParams {
         ListQueryParams,
     ),
     responses(
-        (status = 200, description = "Returns a paginated list of FHIR entities", body = Vec<Value>),
+        (status = 200, description = "Returns a paginated list of FHIR entities", content(
+            (Vec<Value> = "application/json"),
+            (Bundle = "application/fhir+json"),
+        ), headers(
+            ("x-next-cursor" = String, description = "Cursor to fetch the next page, if there are more results"),
+        )),
     )
 )]
-#[instrument(skip(db))]
-#[axum::debug_handler]
+#[instrument(skip(state))]
+#[axum::debug_handler(state = AppState)]
 pub async fn fhir_list(
-    State(AppState { db, .. }): State<AppState>,
+    VersionedState(state): VersionedState,
     Path(resource): Path<String>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    Query(params): Query<ListQueryParams>,
+) -> Result<Response> {
+    search(
+        state,
+        &SearchTarget::Type(&resource),
+        &headers,
+        params,
+        uri.query().unwrap_or(""),
+        &uri,
+    )
+    .await
+}
+
+/// Search FHIR entities of all resource types
+///
+/// Accepts the same parameters as the search of a single resource type, but only
+/// the common search parameters, like `_id` or `_lastUpdated`, can be used.
+/// The results can contain entities of different resource types.
+#[utoipa::path(
+    get,
+    path = "/fhir",
+    params(
+        ("_type" = Option<String>, Query, description = "Comma separated list of the resource types to search, e.g. `Patient,Observation`. All resource types are searched if not set"),
+        ListQueryParams,
+    ),
+    responses(
+        (status = 200, description = "Returns a paginated list of FHIR entities", content(
+            (Vec<Value> = "application/json"),
+            (Bundle = "application/fhir+json"),
+        ), headers(
+            ("x-next-cursor" = String, description = "Cursor to fetch the next page, if there are more results"),
+        )),
+    )
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler(state = AppState)]
+pub async fn fhir_system_search(
+    VersionedState(state): VersionedState,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    Query(mut params): Query<ListQueryParams>,
+) -> Result<Response> {
+    let types = params
+        .search_params
+        .remove(TYPE_PARAM)
+        .map(|types| {
+            types
+                .split(',')
+                .filter(|ty| !ty.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+
+    search(
+        state,
+        &SearchTarget::System(types),
+        &headers,
+        params,
+        uri.query().unwrap_or(""),
+        &uri,
+    )
+    .await
+}
+
+/// Search FHIR entities in a compartment
+///
+/// Accepts the same parameters as the search of a single resource type, but only
+/// returns the entities that are part of the compartment of the given entity,
+/// e.g. the observations of a patient. Only the `Patient` compartment is supported.
+#[utoipa::path(
+    get,
+    path = "/fhir/{resource}/{id}/{type}",
+    params(
+        ("resource", description = "The resource type of the compartment (e.g., Patient)"),
+        ("id", description = "The unique UUID identifier of the compartment entity"),
+        ("type", description = "The FHIR resource type to search (e.g., Observation)"),
+        ListQueryParams,
+    ),
+    responses(
+        (status = 200, description = "Returns a paginated list of FHIR entities", content(
+            (Vec<Value> = "application/json"),
+            (Bundle = "application/fhir+json"),
+        ), headers(
+            ("x-next-cursor" = String, description = "Cursor to fetch the next page, if there are more results"),
+        )),
+    )
+)]
+#[instrument(skip(state))]
+#[axum::debug_handler(state = AppState)]
+pub async fn fhir_compartment_search(
+    VersionedState(state): VersionedState,
+    Path((compartment, id, resource)): Path<(String, Uuid, String)>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
     Query(params): Query<ListQueryParams>,
-) -> Result<Json<Vec<Value>>> {
+) -> Result<Response> {
+    search(
+        state,
+        &SearchTarget::Compartment {
+            compartment: &compartment,
+            id,
+            resource: &resource,
+        },
+        &headers,
+        params,
+        uri.query().unwrap_or(""),
+        &uri,
+    )
+    .await
+}
+
+/// Search FHIR entities using a form body
+///
+/// Accepts the same parameters as the `GET` search as an
+/// `application/x-www-form-urlencoded` body, so they don't appear in the URL.
+/// Parameters in the query string are combined with the parameters of the body.
+///
+/// The paging links of the Bundle only contain the parameters of the query string,
+/// so the body must be sent again with every page, e.g. to the `next` link.
+#[utoipa::path(
+    post,
+    path = "/fhir/{resource}/_search",
+    params(
+        ("resource", description = "The FHIR resource type (e.g., Patient, Observation)"),
+    ),
+    request_body(
+        content_type = "application/x-www-form-urlencoded",
+        description = "The search parameters, see the `GET` search",
+    ),
+    responses(
+        (status = 200, description = "Returns a paginated list of FHIR entities", content(
+            (Vec<Value> = "application/json"),
+            (Bundle = "application/fhir+json"),
+        ), headers(
+            ("x-next-cursor" = String, description = "Cursor to fetch the next page, if there are more results"),
+        )),
+    )
+)]
+#[instrument(skip(state, body))]
+#[axum::debug_handler(state = AppState)]
+pub async fn fhir_search(
+    VersionedState(state): VersionedState,
+    Path(resource): Path<String>,
+    OriginalUri(uri): OriginalUri,
+    headers: HeaderMap,
+    Form(body): Form<Vec<(String, String)>>,
+) -> Result<Response> {
+    let query = combine_params(uri.query().unwrap_or(""), body)?;
+    let params = serde_urlencoded::from_str::<ListQueryParams>(&query)
+        .map_err(|_| AppError::BadRequest(Some("invalid search parameters")))?;
+
+    search(
+        state,
+        &SearchTarget::Type(&resource),
+        &headers,
+        params,
+        &query,
+        &uri,
+    )
+    .await
+}
+
+/// Combines the query string and the form body of a `POST` search into the
+/// url-encoded parameters of the search.
+fn combine_params(query: &str, body: Vec<(String, String)>) -> Result<String> {
+    let mut params = serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .wrap_err("failed to parse query string")?;
+    params.extend(body);
+
+    Ok(serde_urlencoded::to_string(params).wrap_err("failed to encode query string")?)
+}
+
+/// Splits the comparison prefix, like `ge` in `ge2020-01-01`, from a search value,
+/// and returns the search operator together with the remaining value.
+///
+/// Values without a known prefix are compared for equality.
+fn split_prefix(value: &str) -> (&'static str, &str) {
+    const PREFIXES: &[(&str, &str)] = &[
+        ("eq", "="),
+        ("ne", "!="),
+        ("gt", ">"),
+        ("ge", ">="),
+        ("lt", "<"),
+        ("le", "<="),
+        // These are non-standard operators, used for testing performance
+        // and nicer usage
+        ("like", "~"),
+        ("trgm", "%"),
+    ];
+
+    PREFIXES
+        .iter()
+        .find_map(|(prefix, op)| Some((*op, value.strip_prefix(prefix)?)))
+        .unwrap_or(("=", value))
+}
+
+/// The resource types that are searched.
+#[derive(Debug)]
+enum SearchTarget<'a> {
+    /// Search a single resource type.
+    Type(&'a str),
+
+    /// Search the given resource types, or all resource types if empty.
+    System(Vec<String>),
+
+    /// Search a single resource type in the compartment of an entity.
+    Compartment {
+        compartment: &'a str,
+        id: Uuid,
+        resource: &'a str,
+    },
+}
+
+/// A single entity returned by a search.
+struct SearchRow {
+    id: Option<Uuid>,
+    resource_type: Option<String>,
+    mode: Option<String>,
+    entity: Option<Value>,
+    cursor: Option<String>,
+}
+
+/// Searches for entities, and builds the response of the search routes.
+///
+/// `query` is the url-encoded query string that contains all parameters,
+/// which is used for the repeatable include parameters. The paging links are
+/// built from the request `uri`, so parameters in the form body of a `POST`
+/// search never end up in a URL.
+async fn search(
+    AppState { db, config, .. }: AppState,
+    target: &SearchTarget<'_>,
+    headers: &HeaderMap,
+    params: ListQueryParams,
+    query: &str,
+    uri: &Uri,
+) -> Result<Response> {
     let (key, original_value) = params
         .search_params
         .iter()
-        .next()
+        .find(|(key, _)| !is_include_param(key))
         .ok_or(AppError::BadRequest(Some(
             "exactly one search parameter must be provided",
         )))?;
 
-    let (op, mut value) = original_value.split_at(2);
-    let search_op = match op {
-        "eq" => "=",
-        "ne" => "!=",
-        "gt" => ">",
-        "ge" => ">=",
-        "lt" => "<",
-        "le" => "<=",
-        // This is a non-standard operator, used for testing performance
-        // and nicer usage
-        "like" => "~",
-        "trgm" => "%",
-        _ => {
-            value = original_value;
-            "="
+    // Include parameters can be repeated, so they are read from the raw query string.
+    let includes = serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .wrap_err("failed to parse query string")?
+        .into_iter()
+        .filter(|(key, _)| is_include_param(key))
+        .map(|(key, value)| format!("{key}={value}"))
+        .collect::<Vec<_>>();
+
+    let (search_op, value) = split_prefix(original_value);
+
+    let (cursor, backwards) = paging_cursor(params.cursor, params.before)?;
+    let has_cursor = cursor.is_some();
+    let path = uri.path();
+    let link_query = uri.query().unwrap_or("");
+
+    if params.explain {
+        if !config.search_explain {
+            return Err(AppError::BadRequest(Some("`_explain` is not enabled")));
         }
-    };
 
-    // pagination is stable, because we order by `id` and the id
-    // is an uuid v7, which is prefixed by timestmap.
-    let entities = query!(
-        r#"
+        let SearchTarget::Type(resource) = target else {
+            return Err(AppError::BadRequest(Some(
+                "`_explain` is only supported for resource type searches",
+            )));
+        };
+
+        let explain = query!(
+            "SELECT fhir_search_explain($1, $2, $3, $4, $5, $6, $7, $8, $9) as explain",
+            resource,
+            key,
+            search_op,
+            value,
+            params.sort,
+            params.count,
+            cursor,
+            backwards,
+            &includes,
+        )
+        .fetch_one(&db)
+        .await?;
+
+        return Ok(Json(explain.explain).into_response());
+    }
+
+    if params.summary == Some(SummaryMode::Count) {
+        let estimate = params.total == TotalMode::Estimate;
+
+        let mut bundle = Bundle::new(BundleType::Searchset);
+        bundle.total = search_total(&db, target, key, search_op, value, estimate).await?;
+        bundle.push_link(
+            "self",
+            page_url(&bundle::base_url(headers), path, link_query, None)?,
+        );
+
+        return Ok(bundle.into_response());
+    }
+
+    // Pagination is stable, because `fhir_search` always breaks ties using
+    // the `id`, which is an uuid v7 that is prefixed by timestmap.
+    let summary = params.summary.map(SummaryMode::as_str);
+    let rows = match target {
+        SearchTarget::Type(resource) => query_as!(
+            SearchRow,
+            r#"
     SELECT
-        fhir_get($1, id) as entity
+        id,
+        resource_type,
+        mode,
+        fhir_get(resource_type, id, $10, $11) as entity,
+        cursor
     FROM
-        fhir_search($1, $2, $3, $4)
-    ORDER BY id
-    LIMIT $5
-    OFFSET $6
+        fhir_search($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    ORDER BY idx
     "#,
-        resource,
-        key,
-        search_op,
-        value,
-        params.count,
-        params.offset,
-    )
-    .fetch_all(&db)
-    .await?;
+            resource,
+            key,
+            search_op,
+            value,
+            params.sort,
+            params.count,
+            cursor,
+            backwards,
+            &includes,
+            summary,
+            params.elements,
+        )
+        .fetch_all(&db)
+        .await
+        .map_err(AppError::from_query)?,
+        SearchTarget::System(types) => query_as!(
+            SearchRow,
+            r#"
+    SELECT
+        id,
+        resource_type,
+        mode,
+        fhir_get(resource_type, id, $10, $11) as entity,
+        cursor
+    FROM
+        fhir_search_system($1, $2, $3, $4, $5, $6, $7, $8, $9)
+    ORDER BY idx
+    "#,
+            types,
+            key,
+            search_op,
+            value,
+            params.sort,
+            params.count,
+            cursor,
+            backwards,
+            &includes,
+            summary,
+            params.elements,
+        )
+        .fetch_all(&db)
+        .await
+        .map_err(AppError::from_query)?,
+        SearchTarget::Compartment {
+            compartment,
+            id,
+            resource,
+        } => query_as!(
+            SearchRow,
+            r#"
+    SELECT
+        id,
+        resource_type,
+        mode,
+        fhir_get(resource_type, id, $12, $13) as entity,
+        cursor
+    FROM
+        fhir_search($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+    ORDER BY idx
+    "#,
+            compartment,
+            id,
+            resource,
+            key,
+            search_op,
+            value,
+            params.sort,
+            params.count,
+            cursor,
+            backwards,
+            &includes,
+            summary,
+            params.elements,
+        )
+        .fetch_all(&db)
+        .await
+        .map_err(AppError::from_query)?,
+    };
+
+    // Whether there is another page is checked with a separate search for a single
+    // match, so that the includes of that match are not part of this page.
+    let matches = rows
+        .iter()
+        .filter(|row| row.mode.as_deref() == Some("match"))
+        .collect::<Vec<_>>();
+    let boundary = if backwards {
+        matches.first()
+    } else {
+        matches.last()
+    };
+    let is_full = matches.len() >= usize::try_from(params.count).unwrap_or_default();
+    let has_more = match boundary.and_then(|row| row.cursor.as_deref()) {
+        Some(cursor) if is_full => {
+            search_has_more(
+                &db,
+                target,
+                key,
+                search_op,
+                value,
+                params.sort.as_deref(),
+                cursor,
+                backwards,
+            )
+            .await?
+        }
+        _ => false,
+    };
+
+    let page = SearchPage::new(rows, has_more, backwards, has_cursor);
+
+    if !bundle::accepts_fhir_json(headers) {
+        let mut headers = HeaderMap::new();
+        if let Some(cursor) = page.next_cursor {
+            let cursor =
+                HeaderValue::from_str(&cursor).wrap_err("cursor is not a valid header value")?;
+            headers.insert(NEXT_CURSOR_HEADER, cursor);
+        }
+
+        let entities = page
+            .entities
+            .into_iter()
+            .chain(page.included)
+            .filter_map(|e| e.entity)
+            .collect();
+        return Ok((headers, Json::<Vec<Value>>(entities)).into_response());
+    }
+
+    let total = if params.total == TotalMode::None {
+        None
+    } else {
+        let estimate = params.total == TotalMode::Estimate;
+        search_total(&db, target, key, search_op, value, estimate).await?
+    };
+
+    Ok(page
+        .into_bundle(&bundle::base_url(headers), path, link_query, total)?
+        .into_response())
+}
+
+/// A single page of search results.
+struct SearchPage {
+    entities: Vec<SearchRow>,
+    included: Vec<SearchRow>,
+    previous_cursor: Option<String>,
+    next_cursor: Option<String>,
+}
+
+impl SearchPage {
+    /// Splits the rows returned by a search into the matches of the page, the
+    /// included entities and the paging cursors.
+    ///
+    /// `has_more` is set if there are more matches in the direction of the search,
+    /// i.e. before the page when searching `backwards`.
+    fn new(rows: Vec<SearchRow>, has_more: bool, backwards: bool, has_cursor: bool) -> Self {
+        let (entities, included): (Vec<_>, Vec<_>) = rows
+            .into_iter()
+            .partition(|e| e.mode.as_deref() == Some("match"));
+
+        let first_cursor = entities.first().and_then(|e| e.cursor.clone());
+        let last_cursor = entities.last().and_then(|e| e.cursor.clone());
+
+        Self {
+            entities,
+            included,
+            previous_cursor: first_cursor.filter(|_| if backwards { has_more } else { has_cursor }),
+            next_cursor: last_cursor.filter(|_| has_more || backwards),
+        }
+    }
+
+    /// Builds the `searchset` Bundle of this page, including its paging links.
+    fn into_bundle(
+        self,
+        base_url: &str,
+        path: &str,
+        query: &str,
+        total: Option<i64>,
+    ) -> Result<Bundle> {
+        let mut bundle = Bundle::new(BundleType::Searchset);
+        bundle.total = total;
+
+        bundle.push_link("self", self_url(base_url, path, query));
+        bundle.push_link("first", page_url(base_url, path, query, None)?);
+        if let Some(cursor) = self.previous_cursor {
+            bundle.push_link(
+                "previous",
+                page_url(base_url, path, query, Some(("_before", &cursor)))?,
+            );
+        }
+        if let Some(cursor) = self.next_cursor {
+            bundle.push_link(
+                "next",
+                page_url(base_url, path, query, Some(("_cursor", &cursor)))?,
+            );
+        }
+
+        let matches = self
+            .entities
+            .into_iter()
+            .map(|e| (e, SearchEntryMode::Match));
+        let included = self
+            .included
+            .into_iter()
+            .map(|e| (e, SearchEntryMode::Include));
+        bundle.entry = matches
+            .chain(included)
+            .filter_map(|(e, mode)| {
+                Some(BundleEntry {
+                    full_url: Some(format!("{base_url}/fhir/{}/{}", e.resource_type?, e.id?)),
+                    resource: Some(e.entity?),
+                    search: Some(BundleEntrySearch { mode }),
+                })
+            })
+            .collect();
+
+        Ok(bundle)
+    }
+}
+
+/// Checks if there is another match after `cursor`, or before it if `backwards`.
+#[allow(clippy::too_many_arguments)]
+async fn search_has_more(
+    db: &PgPool,
+    target: &SearchTarget<'_>,
+    key: &str,
+    op: &str,
+    value: &str,
+    sort: Option<&str>,
+    cursor: &str,
+    backwards: bool,
+) -> Result<bool> {
+    let no_includes: &[String] = &[];
+    let has_more = match target {
+        SearchTarget::Type(resource) => {
+            query!(
+                r#"SELECT EXISTS (SELECT FROM fhir_search($1, $2, $3, $4, $5, $6, $7, $8, $9)) as "has_more!""#,
+                resource,
+                key,
+                op,
+                value,
+                sort,
+                1_i64,
+                cursor,
+                backwards,
+                no_includes,
+            )
+            .fetch_one(db)
+            .await?
+            .has_more
+        }
+        SearchTarget::System(types) => {
+            query!(
+                r#"SELECT EXISTS (SELECT FROM fhir_search_system($1, $2, $3, $4, $5, $6, $7, $8, $9)) as "has_more!""#,
+                types,
+                key,
+                op,
+                value,
+                sort,
+                1_i64,
+                cursor,
+                backwards,
+                no_includes,
+            )
+            .fetch_one(db)
+            .await?
+            .has_more
+        }
+        SearchTarget::Compartment {
+            compartment,
+            id,
+            resource,
+        } => {
+            query!(
+                r#"SELECT EXISTS (SELECT FROM fhir_search($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)) as "has_more!""#,
+                compartment,
+                id,
+                resource,
+                key,
+                op,
+                value,
+                sort,
+                1_i64,
+                cursor,
+                backwards,
+                no_includes,
+            )
+            .fetch_one(db)
+            .await?
+            .has_more
+        }
+    };
+
+    Ok(has_more)
+}
+
+/// Calculates the total number of matches of a search.
+async fn search_total(
+    db: &PgPool,
+    target: &SearchTarget<'_>,
+    key: &str,
+    op: &str,
+    value: &str,
+    estimate: bool,
+) -> Result<Option<i64>> {
+    let total = match target {
+        SearchTarget::Type(resource) => {
+            query!(
+                "SELECT fhir_search_total($1, $2, $3, $4, $5) as total",
+                resource,
+                key,
+                op,
+                value,
+                estimate,
+            )
+            .fetch_one(db)
+            .await
+            .map_err(AppError::from_query)?
+            .total
+        }
+        SearchTarget::System(types) => {
+            query!(
+                "SELECT fhir_search_system_total($1, $2, $3, $4, $5) as total",
+                types,
+                key,
+                op,
+                value,
+                estimate,
+            )
+            .fetch_one(db)
+            .await
+            .map_err(AppError::from_query)?
+            .total
+        }
+        SearchTarget::Compartment {
+            compartment,
+            id,
+            resource,
+        } => {
+            query!(
+                "SELECT fhir_search_total($1, $2, $3, $4, $5, $6, $7) as total",
+                compartment,
+                id,
+                resource,
+                key,
+                op,
+                value,
+                estimate,
+            )
+            .fetch_one(db)
+            .await
+            .map_err(AppError::from_query)?
+            .total
+        }
+    };
+
+    Ok(total)
+}
+
+/// Returns whether the query parameter is an `_include` or `_revinclude` parameter.
+fn is_include_param(key: &str) -> bool {
+    key.starts_with("_include") || key.starts_with("_revinclude")
+}
+
+/// Returns the cursor of the requested page, and whether the page is right
+/// before the cursor, given the `_cursor` and `_before` parameters.
+pub(super) fn paging_cursor(
+    cursor: Option<String>,
+    before: Option<String>,
+) -> Result<(Option<String>, bool)> {
+    match (cursor, before) {
+        (Some(_), Some(_)) => Err(AppError::BadRequest(Some(
+            "`_cursor` and `_before` can not be used together",
+        ))),
+        (None, Some(before)) => Ok((Some(before), true)),
+        (cursor, None) => Ok((cursor, false)),
+    }
+}
+
+/// Builds the URL of the current search.
+pub(super) fn self_url(base_url: &str, path: &str, query: &str) -> String {
+    if query.is_empty() {
+        format!("{base_url}{path}")
+    } else {
+        format!("{base_url}{path}?{query}")
+    }
+}
+
+/// Builds the URL of the current search, but for another page.
+///
+/// All existing paging parameters are removed, and replaced by `paging`.
+pub(super) fn page_url(
+    base_url: &str,
+    path: &str,
+    query: &str,
+    paging: Option<(&str, &str)>,
+) -> Result<String> {
+    let mut query = serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .wrap_err("failed to parse query string")?;
+
+    query.retain(|(key, _)| key != "_cursor" && key != "_before");
+    if let Some((key, value)) = paging {
+        query.push((key.to_string(), value.to_string()));
+    }
+
+    let query = serde_urlencoded::to_string(query).wrap_err("failed to encode query string")?;
+    Ok(self_url(base_url, path, &query))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use uuid::Uuid;
+
+    use super::*;
+
+    const BASE_URL: &str = "http://localhost";
+    const PATH: &str = "/fhir/Patient";
+
+    fn count(query: &str) -> i64 {
+        serde_urlencoded::from_str::<ListQueryParams>(query)
+            .unwrap()
+            .count
+    }
+
+    fn rows(n: u128) -> Vec<SearchRow> {
+        (1..=n)
+            .map(|i| SearchRow {
+                id: Some(Uuid::from_u128(i)),
+                resource_type: Some("Patient".to_string()),
+                mode: Some("match".to_string()),
+                entity: Some(json!({ "resourceType": "Patient" })),
+                cursor: Some(format!("c{i}")),
+            })
+            .collect()
+    }
+
+    fn links(bundle: &Bundle) -> Vec<(&str, &str)> {
+        bundle
+            .link
+            .iter()
+            .map(|link| (link.relation.as_str(), link.url.as_str()))
+            .collect()
+    }
+
+    #[test]
+    fn count_is_clamped() {
+        assert_eq!(count("name=marie"), 20);
+        assert_eq!(count("_count=5&name=marie"), 5);
+        assert_eq!(count("_count=-5&name=marie"), 1);
+        assert_eq!(count("_count=0&name=marie"), 1);
+        assert_eq!(count("_count=9223372036854775807&name=marie"), 100);
+    }
+
+    #[test]
+    fn count_must_be_a_number() {
+        assert!(serde_urlencoded::from_str::<ListQueryParams>("_count=ten").is_err());
+    }
+
+    #[test]
+    fn prefixes() {
+        assert_eq!(split_prefix("ge2020-01-01"), (">=", "2020-01-01"));
+        assert_eq!(split_prefix("ne"), ("!=", ""));
+        assert_eq!(split_prefix("likemar"), ("~", "mar"));
+        assert_eq!(split_prefix("trgmmarie"), ("%", "marie"));
+        assert_eq!(split_prefix("marie"), ("=", "marie"));
+    }
+
+    #[test]
+    fn prefixes_of_short_values() {
+        assert_eq!(split_prefix(""), ("=", ""));
+        assert_eq!(split_prefix("f"), ("=", "f"));
+        assert_eq!(split_prefix("é"), ("=", "é"));
+        assert_eq!(split_prefix("aé"), ("=", "aé"));
+    }
+
+    #[test]
+    fn post_search_params() {
+        let body = vec![("name".to_string(), "marie curie".to_string())];
+        let query = combine_params("_count=5", body).unwrap();
+        assert_eq!(query, "_count=5&name=marie+curie");
+
+        let params = serde_urlencoded::from_str::<ListQueryParams>(&query).unwrap();
+        assert_eq!(params.count, 5);
+        assert_eq!(params.search_params["name"], "marie curie");
+    }
+
+    #[test]
+    fn post_search_links() {
+        // The links of a `POST` search are built from the query string only.
+        let page = SearchPage::new(rows(2), true, false, false);
+        let bundle = page
+            .into_bundle(BASE_URL, "/fhir/Patient/_search", "_count=2", None)
+            .unwrap();
+
+        assert_eq!(
+            links(&bundle),
+            [
+                ("self", "http://localhost/fhir/Patient/_search?_count=2"),
+                ("first", "http://localhost/fhir/Patient/_search?_count=2"),
+                (
+                    "next",
+                    "http://localhost/fhir/Patient/_search?_count=2&_cursor=c2"
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn first_page() {
+        let query = "name=marie&_count=2";
+        let page = SearchPage::new(rows(2), true, false, false);
+        let bundle = page.into_bundle(BASE_URL, PATH, query, None).unwrap();
+
+        assert_eq!(bundle.entry.len(), 2);
+        assert_eq!(
+            links(&bundle),
+            [
+                ("self", "http://localhost/fhir/Patient?name=marie&_count=2"),
+                ("first", "http://localhost/fhir/Patient?name=marie&_count=2"),
+                (
+                    "next",
+                    "http://localhost/fhir/Patient?name=marie&_count=2&_cursor=c2"
+                ),
+            ]
+        );
+
+        let json = serde_json::to_value(&bundle).unwrap();
+        assert_eq!(json["type"], "searchset");
+        assert!(json.get("total").is_none());
+        assert_eq!(
+            json["entry"][0]["fullUrl"],
+            format!("http://localhost/fhir/Patient/{}", Uuid::from_u128(1))
+        );
+        assert_eq!(json["entry"][0]["search"]["mode"], "match");
+    }
+
+    #[test]
+    fn last_page() {
+        let query = "name=marie&_count=2&_cursor=c2";
+        let page = SearchPage::new(rows(1), false, false, true);
+        let bundle = page.into_bundle(BASE_URL, PATH, query, Some(3)).unwrap();
+
+        assert_eq!(
+            links(&bundle),
+            [
+                (
+                    "self",
+                    "http://localhost/fhir/Patient?name=marie&_count=2&_cursor=c2"
+                ),
+                ("first", "http://localhost/fhir/Patient?name=marie&_count=2"),
+                (
+                    "previous",
+                    "http://localhost/fhir/Patient?name=marie&_count=2&_before=c1"
+                ),
+            ]
+        );
+        assert_eq!(serde_json::to_value(&bundle).unwrap()["total"], 3);
+    }
+
+    #[test]
+    fn backwards_page() {
+        // A backwards page links to the previous page if there are matches before it.
+        let mut rows = rows(3);
+        rows.remove(0);
+        let page = SearchPage::new(rows, true, true, true);
+
+        let ids = page.entities.iter().map(|e| e.id).collect::<Vec<_>>();
+        assert_eq!(ids, [Some(Uuid::from_u128(2)), Some(Uuid::from_u128(3))]);
+        assert_eq!(page.previous_cursor.as_deref(), Some("c2"));
+        assert_eq!(page.next_cursor.as_deref(), Some("c3"));
+    }
+
+    #[test]
+    fn included_entities_are_not_counted() {
+        let mut rows = rows(2);
+        rows[1].mode = Some("include".to_string());
+        let page = SearchPage::new(rows, false, false, false);
+
+        assert_eq!(page.entities.len(), 1);
+        assert_eq!(page.included.len(), 1);
+        assert_eq!(page.next_cursor, None);
+
+        let bundle = page.into_bundle(BASE_URL, PATH, "", None).unwrap();
+        let json = serde_json::to_value(&bundle).unwrap();
+        assert_eq!(json["entry"][1]["search"]["mode"], "include");
+    }
+
+    #[test]
+    fn includes_of_the_next_page_are_not_returned() {
+        // The search for the next page only returns a match, its includes are
+        // never part of the rows of this page.
+        let mut rows = rows(3);
+        rows[2].mode = Some("include".to_string());
+        rows[2].cursor = None;
+        let page = SearchPage::new(rows, true, false, false);
+        let bundle = page.into_bundle(BASE_URL, PATH, "_count=2", None).unwrap();
 
-    Ok(Json(
-        entities.into_iter().filter_map(|e| e.entity).collect(),
-    ))
+        assert_eq!(bundle.entry.len(), 3);
+        assert!(
+            links(&bundle)
+                .iter()
+                .any(|(relation, url)| *relation == "next" && url.ends_with("_cursor=c2"))
+        );
+    }
 }
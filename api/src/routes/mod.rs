@@ -5,15 +5,30 @@ use utoipa_scalar::{Scalar, Servable as _};
 use crate::AppState;
 
 mod create;
+mod everything;
 mod get;
 mod history;
 mod list;
+mod terminology;
+mod transaction;
+mod validate;
 
 pub fn build_router() -> Router<AppState> {
     let (router, openapi) = OpenApiRouter::<AppState>::new()
         .routes(routes!(create::fhir_create, list::fhir_list))
+        .routes(routes!(list::fhir_search))
+        .routes(routes!(
+            list::fhir_system_search,
+            transaction::fhir_transaction
+        ))
+        .routes(routes!(list::fhir_compartment_search))
         .routes(routes!(history::fhir_get_history))
         .routes(routes!(get::fhir_get))
+        .routes(routes!(everything::fhir_patient_everything))
+        .routes(routes!(validate::fhir_validate))
+        .routes(routes!(terminology::fhir_code_lookup))
+        .routes(routes!(terminology::fhir_validate_code))
+        .routes(routes!(terminology::fhir_expand))
         .split_for_parts();
 
     router.merge(Scalar::with_url("/docs", openapi))
@@ -0,0 +1,179 @@
+//! The terminology operations `CodeSystem/$lookup`, `ValueSet/$validate-code` and
+//! `ValueSet/$expand`.
+
+use axum::{
+    Json,
+    extract::Query,
+    http::{HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use serde::Deserialize;
+use serde_json::Value;
+use sqlx::query;
+use tracing::instrument;
+use utoipa::IntoParams;
+
+use crate::{
+    AppState,
+    bundle::FHIR_JSON,
+    error::{AppError, Result},
+    version::VersionedState,
+};
+
+/// Query parameters for the `$lookup` operation.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct LookupQueryParams {
+    /// The canonical url of the code system.
+    system: String,
+
+    /// The code to look up.
+    code: String,
+
+    /// The version of the code system, the most recent one is used if omitted.
+    version: Option<String>,
+}
+
+/// Query parameters for the `$validate-code` operation.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ValidateCodeQueryParams {
+    /// The canonical url of the value set, optionally with a version like `<url>|<version>`.
+    url: String,
+
+    /// The canonical url of the code system of the code.
+    system: String,
+
+    /// The code to validate.
+    code: String,
+}
+
+/// Query parameters for the `$expand` operation.
+#[derive(Debug, Deserialize, IntoParams)]
+pub struct ExpandQueryParams {
+    /// The canonical url of the value set, optionally with a version like `<url>|<version>`.
+    url: String,
+
+    /// Only return concepts whose display contains this text, or a similar word.
+    filter: Option<String>,
+
+    /// The number of concepts to skip.
+    #[serde(default)]
+    #[param(minimum = 0, default = 0)]
+    offset: i64,
+
+    /// The maximum number of concepts to return, all concepts are returned if omitted.
+    #[param(minimum = 0)]
+    count: Option<i64>,
+}
+
+/// Look up a code
+///
+/// Returns a `Parameters` resource with the name and version of the code system,
+/// the display and definition of the concept, and its `parent` and `child` concepts.
+#[utoipa::path(
+    get,
+    path = "/fhir/CodeSystem/$lookup",
+    params(LookupQueryParams),
+    responses(
+        (status = 200, description = "Returns the details of the code", content(
+            (Value = "application/fhir+json"),
+        )),
+        (status = 404, description = "The code is not part of a stored code system"),
+    )
+)]
+#[instrument(skip(db))]
+#[axum::debug_handler(state = AppState)]
+pub async fn fhir_code_lookup(
+    VersionedState(AppState { db, .. }): VersionedState,
+    Query(params): Query<LookupQueryParams>,
+) -> Result<Response> {
+    let lookup = query!(
+        "SELECT fhir_code_lookup($1, $2, $3) as parameters",
+        params.system,
+        params.code,
+        params.version
+    )
+    .fetch_one(&db)
+    .await?;
+
+    operation_response(lookup.parameters)
+}
+
+/// Validate a code against a value set
+///
+/// Returns a `Parameters` resource with the `result`, the `display` of the code, and
+/// a `message` if the code is not part of the value set.
+#[utoipa::path(
+    get,
+    path = "/fhir/ValueSet/$validate-code",
+    params(ValidateCodeQueryParams),
+    responses(
+        (status = 200, description = "Returns the validation result", content(
+            (Value = "application/fhir+json"),
+        )),
+        (status = 404, description = "The value set does not exist"),
+    )
+)]
+#[instrument(skip(db))]
+#[axum::debug_handler(state = AppState)]
+pub async fn fhir_validate_code(
+    VersionedState(AppState { db, .. }): VersionedState,
+    Query(params): Query<ValidateCodeQueryParams>,
+) -> Result<Response> {
+    let validated = query!(
+        "SELECT fhir_validate_code($1, $2, $3) as parameters",
+        params.url,
+        params.system,
+        params.code
+    )
+    .fetch_one(&db)
+    .await?;
+
+    operation_response(validated.parameters)
+}
+
+/// Expand a value set
+///
+/// Returns the value set with an `expansion` containing its concepts, computed from
+/// its `compose` rules and the stored code systems. The expansion is paginated using
+/// `offset` and `count`, and contains the `total` number of matching concepts.
+#[utoipa::path(
+    get,
+    path = "/fhir/ValueSet/$expand",
+    params(ExpandQueryParams),
+    responses(
+        (status = 200, description = "Returns the expanded value set", content(
+            (Value = "application/fhir+json"),
+        )),
+        (status = 404, description = "The value set does not exist"),
+    )
+)]
+#[instrument(skip(db))]
+#[axum::debug_handler(state = AppState)]
+pub async fn fhir_expand(
+    VersionedState(AppState { db, .. }): VersionedState,
+    Query(params): Query<ExpandQueryParams>,
+) -> Result<Response> {
+    let expanded = query!(
+        "SELECT fhir_expand($1, $2, $3, $4) as valueset",
+        params.url,
+        params.filter,
+        params.offset,
+        params.count
+    )
+    .fetch_one(&db)
+    .await?;
+
+    operation_response(expanded.valueset)
+}
+
+/// Returns the resource returned by an operation, or `404 Not Found` if the
+/// operation did not return one.
+fn operation_response(resource: Option<Value>) -> Result<Response> {
+    let resource = resource.ok_or(AppError::NotFound)?;
+
+    Ok((
+        [(header::CONTENT_TYPE, HeaderValue::from_static(FHIR_JSON))],
+        Json(resource),
+    )
+        .into_response())
+}
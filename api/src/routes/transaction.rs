@@ -0,0 +1,59 @@
+//! The transaction and batch route.
+
+use axum::{
+    Json,
+    http::{HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use eyre::eyre;
+use serde_json::Value;
+use sqlx::query;
+use tracing::instrument;
+
+use crate::{AppState, bundle::FHIR_JSON, error::Result, version::VersionedState};
+
+/// Process a transaction or batch Bundle
+///
+/// Only entries that create a new entity using `POST` are supported.
+/// References to the `fullUrl` of another entry in a transaction are replaced
+/// by references to the created entity.
+#[utoipa::path(
+    post,
+    path = "/fhir",
+    request_body(description = "A Bundle of type `transaction` or `batch`"),
+    responses(
+        (status = 200, description = "Returns a `transaction-response` or `batch-response` Bundle", content(
+            (Value = "application/fhir+json"),
+        )),
+        (status = 400, description = "The Bundle is invalid, returns an OperationOutcome", content(
+            (Value = "application/fhir+json"),
+        )),
+    )
+)]
+#[instrument(skip(db, body))]
+#[axum::debug_handler(state = AppState)]
+pub async fn fhir_transaction(
+    VersionedState(AppState { db, .. }): VersionedState,
+    Json(body): Json<Value>,
+) -> Result<Response> {
+    let result = query!("SELECT fhir_transaction($1) as bundle", body)
+        .fetch_one(&db)
+        .await?;
+
+    let Some(response) = result.bundle else {
+        return Err(eyre!("`fhir_transaction` did not return a Bundle").into());
+    };
+
+    let status = if response["resourceType"] == "OperationOutcome" {
+        StatusCode::BAD_REQUEST
+    } else {
+        StatusCode::OK
+    };
+
+    Ok((
+        status,
+        [(header::CONTENT_TYPE, HeaderValue::from_static(FHIR_JSON))],
+        Json(response),
+    )
+        .into_response())
+}
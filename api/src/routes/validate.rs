@@ -0,0 +1,291 @@
+//! The `$validate` operation.
+
+use axum::{
+    Json,
+    extract::Path,
+    http::{HeaderValue, header},
+    response::{IntoResponse, Response},
+};
+use eyre::eyre;
+use serde_json::{Value, json};
+use sqlx::query;
+use tracing::instrument;
+use uuid::Uuid;
+
+use crate::{
+    AppState,
+    bundle::FHIR_JSON,
+    error::{AppError, Result},
+    version::VersionedState,
+};
+
+/// The kind of change the resource is validated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ValidationMode {
+    Create,
+    Update,
+    Delete,
+}
+
+impl ValidationMode {
+    const fn as_str(self) -> &'static str {
+        match self {
+            ValidationMode::Create => "create",
+            ValidationMode::Update => "update",
+            ValidationMode::Delete => "delete",
+        }
+    }
+}
+
+/// The arguments of the `$validate` operation.
+#[derive(Debug)]
+struct ValidateArgs {
+    resource: Option<Value>,
+    mode: ValidationMode,
+    profile: Option<String>,
+}
+
+impl ValidateArgs {
+    /// Takes the arguments from a `Parameters` resource, or uses the body as the
+    /// resource to validate.
+    fn from_body(body: Value) -> Result<Self> {
+        if body["resourceType"] != "Parameters" {
+            return Ok(Self {
+                resource: Some(body),
+                mode: ValidationMode::Create,
+                profile: None,
+            });
+        }
+
+        let mut args = Self {
+            resource: None,
+            mode: ValidationMode::Create,
+            profile: None,
+        };
+
+        let parameters = body["parameter"].as_array().cloned().unwrap_or_default();
+        for mut parameter in parameters {
+            match parameter["name"].as_str() {
+                Some("resource") => args.resource = Some(parameter["resource"].take()),
+                Some("mode") => {
+                    args.mode = match parameter["valueCode"].as_str() {
+                        Some("create") => ValidationMode::Create,
+                        Some("update") => ValidationMode::Update,
+                        Some("delete") => ValidationMode::Delete,
+                        _ => {
+                            return Err(AppError::BadRequest(Some(
+                                "`mode` must be one of `create`, `update` or `delete`",
+                            )));
+                        }
+                    };
+                }
+                Some("profile") => {
+                    args.profile = parameter["valueUri"]
+                        .as_str()
+                        .or(parameter["valueCanonical"].as_str())
+                        .map(ToString::to_string);
+                }
+                _ => {}
+            }
+        }
+
+        Ok(args)
+    }
+}
+
+/// Builds an `OperationOutcome` with a single error.
+fn error_outcome(code: &str, location: &str, diagnostics: &str) -> Value {
+    json!({
+        "resourceType": "OperationOutcome",
+        "issue": [{
+            "severity": "error",
+            "code": code,
+            "diagnostics": diagnostics,
+            "location": [location],
+        }],
+    })
+}
+
+/// Takes the id of the resource, which is required to validate an update or a deletion.
+///
+/// Returns an `OperationOutcome` with the error if the id is missing or invalid.
+fn resource_id(resource: &Value, mode: ValidationMode) -> std::result::Result<Uuid, Value> {
+    let Some(id) = resource["id"].as_str() else {
+        return Err(error_outcome(
+            "required",
+            "/id",
+            &format!(
+                "the resource must have an `id` for mode `{}`",
+                mode.as_str()
+            ),
+        ));
+    };
+
+    id.parse()
+        .map_err(|_| error_outcome("value", "/id", "the `id` of the resource must be a UUID"))
+}
+
+/// Validate a FHIR resource without storing it
+///
+/// Accepts either the resource itself, or a `Parameters` resource with the `resource`,
+/// an optional `mode` (`create`, `update` or `delete`) and an optional `profile`.
+/// Returns an `OperationOutcome` with one issue per validation error.
+/// Updates require the `id` of the resource. Deletions only require the `id`,
+/// and are valid if the resource exists.
+#[utoipa::path(
+    post,
+    path = "/fhir/{resource}/$validate",
+    request_body(description = "The FHIR resource, or a Parameters resource"),
+    params(
+        ("resource", description = "The FHIR resource type to validate"),
+    ),
+    responses(
+        (status = 200, description = "Returns the validation result as an OperationOutcome", content(
+            (Value = "application/fhir+json"),
+        )),
+        (status = 400, description = "The parameters are invalid"),
+    )
+)]
+#[instrument(skip(db, body))]
+#[axum::debug_handler(state = AppState)]
+pub async fn fhir_validate(
+    VersionedState(AppState { db, .. }): VersionedState,
+    Path(resource): Path<String>,
+    Json(body): Json<Value>,
+) -> Result<Response> {
+    let args = ValidateArgs::from_body(body)?;
+    let Some(data) = args.resource else {
+        return Err(AppError::BadRequest(Some(
+            "the `resource` parameter is missing",
+        )));
+    };
+
+    let id = match args.mode {
+        ValidationMode::Create => None,
+        ValidationMode::Update | ValidationMode::Delete => match resource_id(&data, args.mode) {
+            Ok(id) => Some(id),
+            Err(outcome) => return Ok(outcome_response(outcome)),
+        },
+    };
+
+    let outcome = match (args.mode, id) {
+        (ValidationMode::Delete, Some(id)) => {
+            let found = query!(
+                r#"SELECT EXISTS (SELECT 1 FROM "fhir"."entity" WHERE "id" = $1 AND "resource_type" = $2) as "exists!""#,
+                id,
+                resource
+            )
+            .fetch_one(&db)
+            .await?;
+
+            if found.exists {
+                json!({
+                    "resourceType": "OperationOutcome",
+                    "issue": [{
+                        "severity": "information",
+                        "code": "informational",
+                        "diagnostics": "the resource can be deleted",
+                    }],
+                })
+            } else {
+                error_outcome(
+                    "not-found",
+                    "/id",
+                    &format!("{resource}/{id} does not exist"),
+                )
+            }
+        }
+        _ => {
+            let validated = query!(
+                "SELECT fhir_validate($1, $2, $3) as outcome",
+                resource,
+                data,
+                args.profile
+            )
+            .fetch_one(&db)
+            .await?;
+
+            validated
+                .outcome
+                .ok_or_else(|| eyre!("`fhir_validate` did not return an OperationOutcome"))?
+        }
+    };
+
+    Ok(outcome_response(outcome))
+}
+
+/// Returns the `OperationOutcome` as a FHIR JSON response.
+fn outcome_response(outcome: Value) -> Response {
+    (
+        [(header::CONTENT_TYPE, HeaderValue::from_static(FHIR_JSON))],
+        Json(outcome),
+    )
+        .into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parameters(mode: &str, resource: Value) -> Value {
+        json!({
+            "resourceType": "Parameters",
+            "parameter": [
+                { "name": "resource", "resource": resource },
+                { "name": "mode", "valueCode": mode },
+            ],
+        })
+    }
+
+    #[test]
+    fn resource_body() {
+        let args = ValidateArgs::from_body(json!({ "resourceType": "Patient" })).unwrap();
+        assert_eq!(args.mode, ValidationMode::Create);
+        assert_eq!(args.resource.unwrap()["resourceType"], "Patient");
+    }
+
+    #[test]
+    fn parameters_body() {
+        let patient = json!({ "resourceType": "Patient" });
+        for (mode, expected) in [
+            ("create", ValidationMode::Create),
+            ("update", ValidationMode::Update),
+            ("delete", ValidationMode::Delete),
+        ] {
+            let args = ValidateArgs::from_body(parameters(mode, patient.clone())).unwrap();
+            assert_eq!(args.mode, expected);
+            assert_eq!(args.resource, Some(patient.clone()));
+        }
+
+        assert!(ValidateArgs::from_body(parameters("patch", patient)).is_err());
+    }
+
+    #[test]
+    fn update_requires_id() {
+        let outcome = resource_id(
+            &json!({ "resourceType": "Patient" }),
+            ValidationMode::Update,
+        )
+        .unwrap_err();
+        assert_eq!(outcome["issue"][0]["severity"], "error");
+        assert_eq!(outcome["issue"][0]["code"], "required");
+        assert_eq!(
+            outcome["issue"][0]["diagnostics"],
+            "the resource must have an `id` for mode `update`"
+        );
+    }
+
+    #[test]
+    fn id_must_be_uuid() {
+        let outcome = resource_id(
+            &json!({ "resourceType": "Patient", "id": "example" }),
+            ValidationMode::Delete,
+        )
+        .unwrap_err();
+        assert_eq!(outcome["issue"][0]["code"], "value");
+
+        let id = Uuid::from_u128(42);
+        let resource = json!({ "resourceType": "Patient", "id": id.to_string() });
+        assert_eq!(resource_id(&resource, ValidationMode::Delete).unwrap(), id);
+    }
+}
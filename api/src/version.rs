@@ -0,0 +1,145 @@
+//! Negotiation of the FHIR version, using the
+//! [`fhirVersion`](<https://hl7.org/fhir/http.html#version-parameter>) parameter of
+//! the FHIR media type.
+//!
+//! The default version is selected by the `fhir.version` setting of the database.
+//! Requests can ask for any other version that is bundled in the extension, in which
+//! case they are handled by connections that set `fhir.version` accordingly.
+
+use std::convert::Infallible;
+
+use axum::{
+    extract::{FromRequestParts, Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, header, request::Parts},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use sqlx::PgPool;
+
+use crate::{AppState, bundle::FHIR_JSON, error::AppError};
+
+/// A FHIR version whose definitions are bundled in the extension.
+#[derive(Debug, Clone)]
+pub struct BundledVersion {
+    /// The name that selects the version in `fhir.version`, like `R4B`.
+    pub name: String,
+
+    /// The full version number, like `4.3.0`.
+    pub number: String,
+
+    /// The database connection pool, whose connections use this version.
+    pub db: PgPool,
+}
+
+/// The version that was negotiated for a request, if it asked for one.
+#[derive(Debug, Clone)]
+struct NegotiatedVersion(BundledVersion);
+
+/// The application state, using the database connections of the FHIR version that
+/// was negotiated for the request.
+pub struct VersionedState(pub AppState);
+
+impl FromRequestParts<AppState> for VersionedState {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Infallible> {
+        let mut state = state.clone();
+        if let Some(NegotiatedVersion(version)) = parts.extensions.get() {
+            state.db = version.db.clone();
+        }
+
+        Ok(Self(state))
+    }
+}
+
+/// Selects the FHIR version of the request, rejects requests for versions that are
+/// not bundled, and adds the selected version to all FHIR responses.
+pub async fn negotiate_fhir_version(
+    State(AppState {
+        fhir_version,
+        fhir_versions,
+        ..
+    }): State<AppState>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let find = |requested: &String| {
+        fhir_versions
+            .iter()
+            .find(|version| matches(&version.number, requested))
+    };
+
+    let headers = request.headers();
+    let content_type = requested_versions(headers, header::CONTENT_TYPE);
+    let mut selected = None;
+    if !content_type.is_empty() {
+        let Some(version) = content_type.iter().find_map(find) else {
+            return AppError::UnsupportedMediaType.into_response();
+        };
+        selected = Some(version);
+    }
+
+    // The response must use the same version as the request body.
+    let accept = requested_versions(headers, header::ACCEPT);
+    if !accept.is_empty() {
+        let acceptable = accept
+            .iter()
+            .filter_map(find)
+            .find(|version| selected.is_none_or(|selected| selected.number == version.number));
+        let Some(version) = acceptable else {
+            return AppError::NotAcceptable.into_response();
+        };
+        selected = Some(version);
+    }
+
+    let number = selected.map_or(&*fhir_version, |version| &version.number);
+    let media_type = format!(
+        "{FHIR_JSON}; fhirVersion={}",
+        &number[..number.len().min(3)]
+    );
+    if let Some(version) = selected {
+        request
+            .extensions_mut()
+            .insert(NegotiatedVersion(version.clone()));
+    }
+
+    let mut response = next.run(request).await;
+
+    let is_fhir = response
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(FHIR_JSON));
+    if is_fhir && let Ok(value) = HeaderValue::from_str(&media_type) {
+        response.headers_mut().insert(header::CONTENT_TYPE, value);
+    }
+
+    response
+}
+
+/// Returns the `fhirVersion` parameters of the FHIR media types in the given header.
+fn requested_versions(headers: &HeaderMap, name: HeaderName) -> Vec<String> {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter(|media_type| media_type.trim().starts_with(FHIR_JSON))
+        .flat_map(|media_type| media_type.split(';').skip(1))
+        .filter_map(|param| {
+            let (key, value) = param.split_once('=')?;
+            key.trim()
+                .eq_ignore_ascii_case("fhirVersion")
+                .then(|| value.trim().trim_matches('"').to_string())
+        })
+        .collect()
+}
+
+/// Checks if the requested version, like `4.0` or `4.0.1`, matches the full version
+/// number of the server.
+fn matches(version: &str, requested: &str) -> bool {
+    version == requested
+        || version
+            .strip_prefix(requested)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
@@ -0,0 +1,34 @@
+//! Checks that the definitions of the FHIR versions selected by the `r4` and `r5`
+//! features were downloaded, see `scripts/fetch-definitions.sh`.
+
+use std::{env, path::Path};
+
+const DEFINITIONS: &[&str] = &[
+    "fhir.schema.json",
+    "summary.json",
+    "invariants.json",
+    "compartments.json",
+];
+
+fn main() {
+    for version in ["R4", "R5"] {
+        if env::var_os(format!("CARGO_FEATURE_{version}")).is_none() {
+            continue;
+        }
+
+        let dir = Path::new("assets").join(version.to_lowercase());
+        println!("cargo:rerun-if-changed={}", dir.display());
+
+        for file in DEFINITIONS {
+            assert!(
+                dir.join(file).exists(),
+                "the '{}' feature requires the FHIR {version} definitions in db/{}, \
+                 download them with `db/scripts/fetch-definitions.sh {version}`",
+                version.to_lowercase(),
+                dir.display(),
+            );
+        }
+    }
+
+    println!("cargo:rerun-if-changed=build.rs");
+}
@@ -0,0 +1,33 @@
+#!/usr/bin/env bash
+# Downloads the JSON schema and the definitions of a FHIR version from the
+# specification, and generates the files in `db/assets/<version>` that are bundled
+# into the extension.
+#
+# usage: db/scripts/fetch-definitions.sh R4|R4B|R5
+set -euo pipefail
+
+version="${1:?usage: $0 R4|R4B|R5}"
+case "$version" in
+    R4 | R4B | R5) ;;
+    *)
+        echo "unknown FHIR version '$version', expected R4, R4B or R5" >&2
+        exit 1
+        ;;
+esac
+
+root="$(cd "$(dirname "$0")/.." && pwd)"
+assets="$root/assets/$(echo "$version" | tr '[:upper:]' '[:lower:]')"
+tmp="$(mktemp -d)"
+trap 'rm -rf "$tmp"' EXIT
+
+for archive in fhir.schema.json definitions.json; do
+    curl --fail --location --silent --show-error \
+        --output "$tmp/$archive.zip" "https://hl7.org/fhir/$version/$archive.zip"
+    unzip -q -o "$tmp/$archive.zip" -d "$tmp/$archive"
+done
+
+mkdir -p "$assets"
+cp "$tmp/fhir.schema.json/fhir.schema.json" "$assets/fhir.schema.json"
+python3 "$root/scripts/generate-definitions.py" "$tmp/definitions.json" "$assets"
+
+echo "generated the FHIR $version definitions in $assets"
@@ -0,0 +1,153 @@
+#!/usr/bin/env python3
+"""Generates the definitions that are bundled into the extension, from the
+`definitions.json` of a FHIR version.
+
+usage: generate-definitions.py <definitions dir> <output dir>
+
+The definitions directory contains the Bundles of the specification, like
+`profiles-resources.json`. The following files are written to the output directory:
+
+- `summary.json`: the top-level elements of every resource type that are marked
+  with `isSummary` in its `StructureDefinition`. The elements of `Resource` are
+  only listed once.
+- `invariants.json`: the constraints of every resource type, with the FHIRPath
+  `context` that selects the constrained elements, starting at the resource.
+  The constraints of `Element` and `Extension` apply to all of their descendants.
+- `compartments.json`: the search parameters that link resource types to the
+  `Patient` compartment, taken from its `CompartmentDefinition`.
+"""
+
+import json
+import sys
+from pathlib import Path
+
+COMPARTMENTS = ["Patient"]
+
+# Data types whose constraints are checked on every resource, with the FHIRPath
+# context that selects the elements of that type.
+TYPE_CONTEXTS = {
+    "Element": "descendants()",
+    "Extension": "extension | modifierExtension | descendants().extension"
+    " | descendants().modifierExtension",
+}
+
+
+def resources(definitions: Path):
+    """Yields all resources of the Bundles in the definitions directory."""
+    for path in sorted(definitions.glob("*.json")):
+        bundle = json.loads(path.read_text(encoding="utf-8"))
+        if bundle.get("resourceType") != "Bundle":
+            continue
+
+        for entry in bundle.get("entry", []):
+            if "resource" in entry:
+                yield entry["resource"]
+
+
+def summary(definitions: list) -> dict:
+    """The top-level summary elements of each resource type."""
+    result = {}
+    for definition in definitions:
+        if definition.get("resourceType") != "StructureDefinition":
+            continue
+        if definition.get("kind") != "resource" or definition.get("derivation") == "constraint":
+            continue
+
+        elements = []
+        for element in definition.get("snapshot", {}).get("element", []):
+            path = element["path"].split(".")
+            if len(path) == 2 and element.get("isSummary"):
+                elements.append(path[1])
+
+        result[definition["type"]] = elements
+
+    base = set(result.get("Resource", []))
+    return {
+        name: elements if name == "Resource" else [e for e in elements if e not in base]
+        for name, elements in result.items()
+    }
+
+
+def invariants(definitions: list) -> dict:
+    """The constraints defined by each resource type, and by `TYPE_CONTEXTS`.
+
+    Constraints that are inherited from a base definition, like `ele-1` on every
+    element, are only listed for the definition that introduces them.
+    """
+    result = {}
+    for definition in definitions:
+        if definition.get("resourceType") != "StructureDefinition":
+            continue
+        if definition.get("derivation") == "constraint":
+            continue
+
+        name = definition["type"]
+        if definition.get("kind") != "resource" and name not in TYPE_CONTEXTS:
+            continue
+
+        found = []
+        for element in definition.get("snapshot", {}).get("element", []):
+            path = element["path"].split(".")
+            if name in TYPE_CONTEXTS:
+                if len(path) != 1:
+                    continue
+                context = TYPE_CONTEXTS[name]
+            else:
+                context = ".".join(part.removesuffix("[x]") for part in path[1:]) or "$this"
+
+            for constraint in element.get("constraint", []):
+                if constraint.get("source", definition["url"]) != definition["url"]:
+                    continue
+                if "expression" not in constraint:
+                    continue
+
+                found.append({
+                    "key": constraint["key"],
+                    "severity": constraint["severity"],
+                    "human": constraint["human"],
+                    "context": context,
+                    "expression": constraint["expression"],
+                })
+
+        if found:
+            result[name] = found
+
+    return result
+
+
+def compartments(definitions: list) -> dict:
+    """The reference parameters of each resource type, per compartment."""
+    result = {}
+    for definition in definitions:
+        if definition.get("resourceType") != "CompartmentDefinition":
+            continue
+        if definition.get("code") not in COMPARTMENTS:
+            continue
+
+        result[definition["code"]] = {
+            resource["code"]: resource["param"]
+            for resource in definition.get("resource", [])
+            if resource.get("param")
+        }
+
+    return result
+
+
+def write(path: Path, value):
+    path.write_text(json.dumps(value, indent=2, sort_keys=True) + "\n", encoding="utf-8")
+
+
+def main():
+    if len(sys.argv) != 3:
+        sys.exit(__doc__)
+
+    definitions = list(resources(Path(sys.argv[1])))
+    output = Path(sys.argv[2])
+
+    write(output / "summary.json", summary(definitions))
+    write(output / "invariants.json", invariants(definitions))
+    write(output / "compartments.json", compartments(definitions))
+
+
+if __name__ == "__main__":
+    main()
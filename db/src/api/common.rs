@@ -1,8 +1,11 @@
-use fastrace::trace;
+use fastrace::{prelude::*, trace};
 use pgrx::{prelude::*, JsonB};
 use serde_json::Value;
 
-use crate::fhir;
+use crate::{
+    fhir::{self, version::FhirVersion},
+    spi,
+};
 
 /// Checks if the `data` matches the given JSON schema.
 #[pg_extern]
@@ -26,6 +29,26 @@ pub fn fhir_is_valid(entity: &str, mut data: JsonB) -> bool {
     fhir::is_valid(&data.0)
 }
 
+/// Returns the full number of the FHIR version selected by `fhir.version`, like `4.3.0`.
+#[pg_extern]
+#[trace]
+pub fn fhir_version() -> &'static str {
+    FhirVersion::current().number()
+}
+
+/// Returns the FHIR versions whose definitions are part of this build, with the name
+/// that selects them in `fhir.version` and their full number.
+#[pg_extern]
+#[trace]
+pub fn fhir_bundled_versions(
+) -> TableIterator<'static, (name!(name, String), name!(number, &'static str))> {
+    TableIterator::new(
+        FhirVersion::bundled()
+            .into_iter()
+            .map(|version| (version.to_string(), version.number())),
+    )
+}
+
 /// Generates a new UUID v7.
 ///
 /// These ids are used for all FHIR resources as their identifier.
@@ -34,3 +57,53 @@ pub fn fhir_is_valid(entity: &str, mut data: JsonB) -> bool {
 pub fn fhir_generate_id() -> pgrx::Uuid {
     pgrx::Uuid::from_bytes(uuid::Uuid::now_v7().into_bytes())
 }
+
+/// Looks up the canonical entity of the given type by its canonical url, which may
+/// contain a version like `<url>|<version>`.
+///
+/// If there are multiple entities with the same url, the most recent one is returned.
+pub fn find_canonical(resource_type: &str, canonical: &str) -> spi::Result<Option<Value>> {
+    Ok(find_canonical_entity(resource_type, canonical)?.map(|(_, data)| data))
+}
+
+/// Like [`find_canonical`], but also returns the id of the entity.
+pub fn find_canonical_entity(
+    resource_type: &str,
+    canonical: &str,
+) -> spi::Result<Option<(pgrx::Uuid, Value)>> {
+    let (url, version) = match canonical.split_once('|') {
+        Some((url, version)) => (url, Some(version)),
+        None => (canonical, None),
+    };
+
+    let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+    Spi::connect(|client| {
+        let Some(row) = client
+            .select(
+                r#"
+                SELECT "entity"."id", "entity"."data"
+                FROM "fhir"."entity" "entity"
+                JOIN "fhir"."entity_index_uri" "uri" ON "uri"."entity_id" = "entity"."id"
+                WHERE
+                    "uri"."entity" = $1
+                    AND "uri"."key" = 'url'
+                    AND "uri"."value" = $2
+                    AND ($3::text IS NULL OR "entity"."data" ->> 'version' = $3)
+                ORDER BY "entity"."last_updated" DESC
+                "#,
+                Some(1),
+                &[resource_type.into(), url.into(), version.into()],
+            )?
+            .next()
+        else {
+            return Ok(None);
+        };
+
+        let (Some(id), Some(data)) = (row["id"].value()?, row["data"].value::<JsonB>()?) else {
+            return Ok(None);
+        };
+
+        Ok(Some((id, data.0)))
+    })
+}
@@ -0,0 +1,172 @@
+//! The [Patient `$everything`](<https://hl7.org/fhir/operation-patient-everything.html>) operation.
+
+use fastrace::prelude::*;
+use pgrx::{datum::DatumWithOid, prelude::*, Uuid};
+
+use crate::{
+    api::search::{unindexed_param, Cursor, SearchError, SortSql},
+    fhir,
+};
+
+/// Resource types that are returned, if they are referenced by an entity in the compartment.
+const REFERENCED_TYPES: &[&str] = &["Practitioner", "Organization", "Medication"];
+
+/// Returns the patient, all entities in its compartment, and the practitioners,
+/// organizations and medications that are referenced by them.
+///
+/// Only the compartment members whose linking parameters are all indexed are
+/// returned, see `fhir_compartment_unindexed_types` for the missing ones. Referenced
+/// entities are found using the reference index, and are returned with `mode` set
+/// to `include`, if they are not part of the compartment.
+///
+/// The entities are ordered by their id. At most `count` entities are returned,
+/// and every returned row contains a `cursor`, which can be passed to the next call
+/// to continue after that row. If `backwards` is set, the entities right before
+/// the cursor are returned instead, still in ascending order.
+///
+/// `since` only returns entities that were changed at or after that time, and
+/// `types` only returns entities of the given resource types.
+#[allow(clippy::type_complexity)]
+#[pg_extern]
+#[trace]
+pub fn fhir_patient_everything(
+    patient_id: Uuid,
+    since: default!(Option<TimestampWithTimeZone>, "NULL"),
+    types: default!(Option<Vec<String>>, "NULL"),
+    count: default!(Option<i64>, "NULL"),
+    cursor: default!(Option<&str>, "NULL"),
+    backwards: default!(bool, "false"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(idx, i64),
+            name!(id, Uuid),
+            name!(cursor, Option<String>),
+            name!(resource_type, String),
+            name!(mode, String),
+        ),
+    >,
+    SearchError,
+> {
+    let (member_types, member_keys): (Vec<_>, Vec<_>) = fhir::compartment_types("Patient")
+        .into_iter()
+        .filter(|(entity, params)| unindexed_param(entity, params).is_none())
+        .flat_map(|(entity, params)| params.into_iter().map(move |param| (entity.clone(), param)))
+        .unzip();
+    let referenced_types = REFERENCED_TYPES
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    let mut args: Vec<DatumWithOid<'_>> = vec![
+        patient_id.into(),
+        member_types.into(),
+        member_keys.into(),
+        referenced_types.into(),
+    ];
+
+    let mut conditions = Vec::new();
+    if let Some(since) = since {
+        args.push(since.into());
+        conditions.push(format!(r#""entity"."last_updated" >= ${}"#, args.len()));
+    }
+    if let Some(types) = types {
+        args.push(types.into());
+        conditions.push(format!(
+            r#""entity"."resource_type" = ANY(${})"#,
+            args.len()
+        ));
+    }
+    if let Some(cursor) = cursor {
+        let cursor = Cursor::decode(cursor, &[])?;
+        let sort = SortSql::new(&[], backwards, false, &mut args)?;
+        conditions.push(cursor.after_condition(&sort, &mut args));
+    }
+    let conditions = if conditions.is_empty() {
+        "true".to_string()
+    } else {
+        conditions.join(" AND ")
+    };
+
+    let limit = match count {
+        Some(count) => {
+            args.push(count.into());
+            format!("LIMIT ${}", args.len())
+        }
+        None => String::new(),
+    };
+
+    let sql = format!(
+        r#"
+            WITH "members" AS (
+                SELECT "id" AS "entity_id"
+                FROM "fhir"."entity"
+                WHERE "id" = $1 AND "resource_type" = 'Patient'
+                UNION
+                SELECT "reference"."entity_id"
+                FROM "fhir"."entity_index_reference" "reference"
+                JOIN unnest($2::text[], $3::text[]) AS "param" ("entity", "key")
+                    ON "param"."entity" = "reference"."entity" AND "param"."key" = "reference"."key"
+                WHERE "reference"."target_type" = 'Patient' AND "reference"."target_id" = $1
+            ), "referenced" AS (
+                SELECT DISTINCT "reference"."target_id" AS "entity_id"
+                FROM "fhir"."entity_index_reference" "reference"
+                WHERE
+                    "reference"."entity_id" IN (SELECT "entity_id" FROM "members")
+                    AND "reference"."target_type" = ANY($4)
+            ), "matches" AS (
+                SELECT "entity_id", 'match' AS "mode" FROM "members"
+                UNION ALL
+                SELECT "entity_id", 'include'
+                FROM "referenced"
+                WHERE
+                    "entity_id" IS NOT NULL
+                    AND "entity_id" NOT IN (SELECT "entity_id" FROM "members")
+            )
+            SELECT "matches"."entity_id", "entity"."resource_type", "matches"."mode"
+            FROM "matches"
+            JOIN "fhir"."entity" "entity" ON "entity"."id" = "matches"."entity_id"
+            WHERE {conditions}
+            ORDER BY "matches"."entity_id" {order}
+            {limit}
+        "#,
+        order = if backwards { "DESC" } else { "ASC" },
+    );
+
+    let mut rows = {
+        let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+        Spi::connect(|conn| {
+            conn.select(&sql, None, &args)?
+                .map(|row| {
+                    let (Some(id), Some(resource_type), Some(mode)) = (
+                        row.get::<Uuid>(1)?,
+                        row.get::<String>(2)?,
+                        row.get::<String>(3)?,
+                    ) else {
+                        return Ok(None);
+                    };
+
+                    Ok(Some((id, resource_type, mode)))
+                })
+                .filter_map(Result::transpose)
+                .collect::<pgrx::spi::Result<Vec<_>>>()
+        })?
+    };
+    if backwards {
+        rows.reverse();
+    }
+
+    Ok(TableIterator::new(rows.into_iter().enumerate().map(
+        |(idx, (id, resource_type, mode))| {
+            (
+                i64::try_from(idx).expect("usize to i64 conversion failed"),
+                id,
+                Some(Cursor::new(Vec::new(), id).encode()),
+                resource_type,
+                mode,
+            )
+        },
+    )))
+}
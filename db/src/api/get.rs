@@ -1,12 +1,28 @@
+use std::str::FromStr as _;
+
 use fastrace::{prelude::*, trace};
 use pgrx::{prelude::*, JsonB, Uuid};
 use serde_json::Value;
 
+use crate::api::projection::{self, Summary};
+
 /// Gets a FHIR resource for a certain id.
+///
+/// The returned resource can be reduced using the `_summary` modes `true`, `text`,
+/// `data` and `false`, or a comma separated list of `elements`.
 #[pg_extern]
 #[trace]
-pub fn fhir_get(entity: String, id: Uuid) -> Option<JsonB> {
-    let mut data = {
+pub fn fhir_get(
+    entity: String,
+    id: Uuid,
+    summary: default!(Option<&str>, "NULL"),
+    elements: default!(Option<&str>, "NULL"),
+) -> Option<JsonB> {
+    let summary = summary
+        .map_or(Ok(Summary::False), Summary::from_str)
+        .unwrap_or_else(|error| error.report());
+
+    let data = {
         let _guard = LocalSpan::enter_with_local_parent("spi");
 
         Spi::connect(|client| {
@@ -18,13 +34,17 @@ pub fn fhir_get(entity: String, id: Uuid) -> Option<JsonB> {
             .expect("Failed to get entity")
             .next()?["data"]
             .value::<JsonB>().expect("data of fhir entity must be JsonB")
-        })?
+        })
     };
 
+    let mut data = data?;
+
     let obj = data.0.as_object_mut().expect("Entity must be an object");
 
     obj.insert("id".to_string(), Value::String(id.to_string()));
-    obj.insert("resourceType".to_string(), Value::String(entity));
+    obj.insert("resourceType".to_string(), Value::String(entity.clone()));
+
+    projection::project(&entity, obj, summary, elements).unwrap_or_else(|error| error.report());
 
     Some(data)
 }
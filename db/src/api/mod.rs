@@ -1,5 +1,10 @@
 pub mod common;
+pub mod everything;
 pub mod get;
 pub mod history;
+pub mod projection;
 pub mod put;
 pub mod search;
+pub mod terminology;
+pub mod transaction;
+pub mod validate;
@@ -0,0 +1,150 @@
+//! Projection of FHIR entities using the `_summary` and `_elements` parameters.
+
+use std::str::FromStr;
+
+use pgrx::{ereport, PgSqlErrorCode};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use crate::fhir;
+
+/// The code system of the `SUBSETTED` tag.
+const SUBSETTED_SYSTEM: &str = "http://terminology.hl7.org/CodeSystem/v3-ObservationValue";
+
+/// Errors that can occurr while projecting an entity.
+#[derive(Debug, Error)]
+pub enum ProjectionError {
+    /// The provided `_summary` value is not supported.
+    #[error("unknown summary mode: '{0}'")]
+    UnknownSummary(String),
+
+    /// The summary elements of the resource type are not part of the bundled definitions.
+    #[error("the summary elements of resource type '{0}' are not known")]
+    UnknownSummaryElements(String),
+}
+
+impl ProjectionError {
+    /// Raises the error as `invalid_parameter_value`, so that clients can tell it
+    /// apart from internal errors.
+    pub fn report(self) -> ! {
+        ereport!(
+            ERROR,
+            PgSqlErrorCode::ERRCODE_INVALID_PARAMETER_VALUE,
+            self.to_string()
+        );
+    }
+}
+
+/// The [`_summary`](<https://hl7.org/fhir/search.html#_summary>) modes.
+///
+/// `count` is not included, because it only applies to searches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Summary {
+    /// Only return the elements that are marked as summary.
+    True,
+    /// Only return the narrative and mandatory elements.
+    Text,
+    /// Return all elements except the narrative.
+    Data,
+    /// Return all elements.
+    False,
+}
+
+impl FromStr for Summary {
+    type Err = ProjectionError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "true" => Ok(Summary::True),
+            "text" => Ok(Summary::Text),
+            "data" => Ok(Summary::Data),
+            "false" => Ok(Summary::False),
+            _ => Err(ProjectionError::UnknownSummary(s.to_string())),
+        }
+    }
+}
+
+/// Removes all top-level elements from `data` that are not requested.
+///
+/// `elements` is a comma separated list of element names, and takes precedence
+/// over `summary`. The `id`, `meta` and mandatory elements are always kept.
+/// If any element was removed, the `SUBSETTED` tag is added to the entity.
+///
+/// Fails for `_summary=true`, if the summary elements of the resource type are not known.
+pub fn project(
+    resource_type: &str,
+    data: &mut Map<String, Value>,
+    summary: Summary,
+    elements: Option<&str>,
+) -> Result<(), ProjectionError> {
+    let mut keep = match (elements, summary) {
+        (Some(elements), _) => elements
+            .split(',')
+            .map(str::trim)
+            .map(String::from)
+            .chain(["id".to_string(), "meta".to_string()])
+            .collect(),
+        (None, Summary::False) => return Ok(()),
+        (None, Summary::Data) => {
+            if data.remove("text").is_some() {
+                mark_subsetted(data);
+            }
+            return Ok(());
+        }
+        (None, Summary::Text) => vec!["id".to_string(), "meta".to_string(), "text".to_string()],
+        (None, Summary::True) => fhir::summary_elements(resource_type)
+            .ok_or_else(|| ProjectionError::UnknownSummaryElements(resource_type.to_string()))?,
+    };
+
+    keep.push("resourceType".to_string());
+    keep.extend(fhir::required_elements(resource_type));
+
+    let len = data.len();
+    data.retain(|key, _| keep.iter().any(|element| matches_element(key, element)));
+
+    if data.len() != len {
+        mark_subsetted(data);
+    }
+
+    Ok(())
+}
+
+/// Returns whether the JSON property `key` belongs to `element`.
+///
+/// This includes the `_` prefixed properties of primitive values, and the
+/// typed properties of choice elements, like `deceasedBoolean` for `deceased[x]`.
+fn matches_element(key: &str, element: &str) -> bool {
+    let key = key.strip_prefix('_').unwrap_or(key);
+
+    match element.strip_suffix("[x]") {
+        Some(element) => key
+            .strip_prefix(element)
+            .is_some_and(|ty| ty.starts_with(|c: char| c.is_ascii_uppercase())),
+        None => key == element,
+    }
+}
+
+/// Adds the `SUBSETTED` tag to the `meta` of the entity, unless it is already tagged.
+fn mark_subsetted(data: &mut Map<String, Value>) {
+    let meta = data.entry("meta").or_insert_with(|| json!({}));
+
+    let Some(meta) = meta.as_object_mut() else {
+        return;
+    };
+
+    let tags = meta.entry("tag").or_insert_with(|| json!([]));
+    let Some(tags) = tags.as_array_mut() else {
+        return;
+    };
+
+    let tagged = tags
+        .iter()
+        .any(|tag| tag["system"] == SUBSETTED_SYSTEM && tag["code"] == "SUBSETTED");
+    if !tagged {
+        tags.push(json!({
+            "system": SUBSETTED_SYSTEM,
+            "code": "SUBSETTED",
+            "display": "Resource encoded in summary mode",
+        }));
+    }
+}
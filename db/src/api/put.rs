@@ -2,36 +2,82 @@ use fastrace::trace;
 use pgrx::{prelude::*, JsonB, Uuid};
 use serde_json::Value;
 
-use crate::{api::common::fhir_generate_id, index::collect_index_values_for, spi};
+use crate::{
+    api::{
+        common::fhir_generate_id,
+        validate::{
+            first_error, operation_outcome, record_issues, validate_write, WriteValidation,
+        },
+    },
+    index::collect_index_values_for,
+    spi, terminology,
+};
 
 /// Inserts a new FHIR resource into the database.
 ///
 /// This function will only insert a new entity, and will not update existing entities.
+/// The entity must match the FHIR schema and the base invariants, and if
+/// `fhir.validate_profiles` is enabled, the profiles in its `meta.profile`.
+///
+/// Invalid entities are only rejected in the `strict` validation mode, with a
+/// `check_violation` error whose detail is an `OperationOutcome` of all errors.
+/// In `warn` mode, they are stored and their errors are recorded in `fhir.validation_issue`.
 #[pg_extern]
 #[trace]
 pub fn fhir_put(mut entity: JsonB) -> Uuid {
-    let entity_obj = entity.0.as_object_mut().expect("Entity must be an object");
-
-    let Some(Value::String(resource_type)) = entity_obj.remove("resourceType") else {
+    let Some(Value::String(resource_type)) = entity.0.get("resourceType").cloned() else {
         panic!("the given entity does not have a 'resourceType'");
     };
 
-    entity_obj.remove("id");
+    let issues = match validate_write(&entity.0).expect("Failed to validate entity") {
+        WriteValidation::Accepted(issues) => issues,
+        WriteValidation::Rejected(errors) => {
+            ereport!(
+                ERROR,
+                PgSqlErrorCode::ERRCODE_CHECK_VIOLATION,
+                format!(
+                    "the given entity is invalid: {}",
+                    first_error(&errors).unwrap_or_default()
+                ),
+                operation_outcome(&errors).to_string(),
+            );
+        }
+    };
 
-    let indexable_values = collect_index_values_for(&resource_type, &entity.0);
+    let entity_obj = entity.0.as_object_mut().expect("Entity must be an object");
+    entity_obj.remove("resourceType");
+    entity_obj.remove("id");
 
     let id = fhir_generate_id();
+    insert_entity(id, &resource_type, entity).expect("Failed to insert entity");
+    record_issues(id, &resource_type, &issues).expect("Failed to record validation issues");
+
+    id
+}
+
+/// Inserts the entity with the given id, and its index values.
+///
+/// `entity` must neither contain the `resourceType` nor the `id`. The concepts of a
+/// `CodeSystem` are inserted into the `concept` table.
+#[trace]
+pub fn insert_entity(id: Uuid, resource_type: &str, entity: JsonB) -> spi::Result<()> {
+    let indexable_values = collect_index_values_for(resource_type, &entity.0);
+    let concepts = (resource_type == "CodeSystem")
+        .then(|| terminology::collect_concepts(&entity.0))
+        .flatten();
+
     spi::run_with_args(
         r#"
         INSERT INTO "fhir"."entity" ("id", "resource_type", "data") VALUES ($1, $2, $3);
         "#,
         &[id.into(), resource_type.into(), entity.into()],
-    )
-    .expect("Failed to insert entity");
+    )?;
 
-    indexable_values
-        .insert(id)
-        .expect("Failed to insert index values");
+    indexable_values.insert(id)?;
 
-    id
+    if let Some(concepts) = concepts {
+        concepts.insert(id)?;
+    }
+
+    Ok(())
 }
@@ -0,0 +1,162 @@
+//! Parsing of chained (`subject:Patient.name`) and reverse chained
+//! (`_has:Observation:subject:code`) search keys.
+
+use pgrx::datum::DatumWithOid;
+
+use crate::{
+    api::search::SearchError,
+    gucs,
+    index::{self, IndexedKeyType},
+};
+
+/// A single reference that is followed by a chained search key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Link<'a> {
+    /// Follows the reference `key` of the current entity to entities of type `target`.
+    Forward { key: &'a str, target: &'a str },
+
+    /// Follows the reference `key` of `source` entities back to the current entity.
+    Reverse { source: &'a str, key: &'a str },
+}
+
+/// A search key, split into the references that must be followed and the
+/// key that is compared with the search value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Chain<'a> {
+    links: Vec<(&'a str, Link<'a>)>,
+
+    /// The entity the last key belongs to.
+    pub entity: &'a str,
+
+    /// The key that is compared with the search value.
+    pub key: &'a str,
+}
+
+impl<'a> Chain<'a> {
+    /// Parses a search key of `entity`.
+    ///
+    /// Chained keys must name the type of the referenced entity, e.g. `subject:Patient.name`,
+    /// and can be combined with `_has` keys, e.g. `_has:Observation:subject:code`.
+    /// The number of references is limited by the `fhir.search_max_chain_depth` setting.
+    pub fn parse(entity: &'a str, key: &'a str) -> Result<Self, SearchError> {
+        let invalid = || SearchError::UnknownSearchKey(key.to_string());
+
+        let mut links = Vec::new();
+        let mut entity = entity;
+        let mut rest = key;
+
+        loop {
+            if let Some(has) = rest.strip_prefix("_has:") {
+                let mut parts = has.splitn(3, ':');
+                let (Some(source), Some(reference), Some(next)) =
+                    (parts.next(), parts.next(), parts.next())
+                else {
+                    return Err(invalid());
+                };
+
+                if !is_reference(source, reference) {
+                    return Err(invalid());
+                }
+
+                links.push((
+                    entity,
+                    Link::Reverse {
+                        source,
+                        key: reference,
+                    },
+                ));
+                entity = source;
+                rest = next;
+            } else if let Some((reference, next)) = rest.split_once('.') {
+                let (reference, target) = reference.split_once(':').ok_or_else(invalid)?;
+                if !is_reference(entity, reference) {
+                    return Err(invalid());
+                }
+
+                links.push((
+                    entity,
+                    Link::Forward {
+                        key: reference,
+                        target,
+                    },
+                ));
+                entity = target;
+                rest = next;
+            } else {
+                break;
+            }
+        }
+
+        let max_depth = usize::try_from(gucs::SEARCH_MAX_CHAIN_DEPTH.get()).unwrap_or_default();
+        if links.len() > max_depth {
+            return Err(SearchError::ChainTooDeep(max_depth));
+        }
+
+        Ok(Self {
+            links,
+            entity,
+            key: rest,
+        })
+    }
+
+    /// Whether the key follows any references.
+    pub fn is_chained(&self) -> bool {
+        !self.links.is_empty()
+    }
+
+    /// Wraps `sql`, which selects the ids of the matching entities of the last key,
+    /// into queries that select the ids of the entities of the first key.
+    pub fn wrap_sql(&self, mut sql: String, args: &mut Vec<DatumWithOid<'a>>) -> String {
+        for &(entity, link) in self.links.iter().rev() {
+            sql = match link {
+                Link::Forward { key, target } => {
+                    args.push(entity.into());
+                    args.push(key.into());
+                    args.push(target.into());
+                    let arg = args.len();
+
+                    format!(
+                        r#"
+                    SELECT "entity_id"
+                    FROM "fhir"."entity_index_reference"
+                    WHERE
+                        "entity" = ${entity}
+                        AND "key" = ${key}
+                        AND "target_type" = ${target}
+                        AND "target_id" IN ({sql})"#,
+                        entity = arg - 2,
+                        key = arg - 1,
+                        target = arg,
+                    )
+                }
+                Link::Reverse { source, key } => {
+                    args.push(source.into());
+                    args.push(key.into());
+                    args.push(entity.into());
+                    let arg = args.len();
+
+                    format!(
+                        r#"
+                    SELECT "target_id" AS "entity_id"
+                    FROM "fhir"."entity_index_reference"
+                    WHERE
+                        "entity" = ${source}
+                        AND "key" = ${key}
+                        AND "target_type" = ${target}
+                        AND "entity_id" IN ({sql})"#,
+                        source = arg - 2,
+                        key = arg - 1,
+                        target = arg,
+                    )
+                }
+            };
+        }
+
+        sql
+    }
+}
+
+/// Returns whether `key` is a reference search key of `entity`.
+fn is_reference(entity: &str, key: &str) -> bool {
+    index::find_search_index_for_key(entity, key) == Some(IndexedKeyType::Reference)
+}
@@ -0,0 +1,264 @@
+//! Searches in a compartment, like all observations of a single patient.
+//!
+//! See [Compartments](<https://hl7.org/fhir/compartmentdefinition.html>).
+
+use fastrace::prelude::*;
+use pgrx::{datum::DatumWithOid, prelude::*, Uuid};
+
+use crate::{
+    api::search::{
+        fhir_search, fhir_search_total, SearchEntity, SearchError, SearchOptions, SearchValue,
+    },
+    fhir,
+    index::{self, IndexedKeyType},
+};
+
+/// A compartment, identified by the entity it belongs to, like `Patient/<id>`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Compartment<'a> {
+    /// The resource type of the compartment entity, like `Patient`.
+    pub code: &'a str,
+
+    /// The id of the compartment entity.
+    pub id: Uuid,
+}
+
+impl<'a> Compartment<'a> {
+    /// Wraps `sql`, which selects the ids of matching `entity` entities, so only the
+    /// entities in this compartment are selected.
+    ///
+    /// Entities are part of the compartment, if one of the reference parameters of the
+    /// compartment definition points to the compartment entity. The compartment entity
+    /// itself is also part of its compartment. Fails if one of the parameters is not
+    /// indexed as a reference, instead of silently missing entities.
+    pub fn wrap_sql(
+        &self,
+        entity: &'a str,
+        sql: String,
+        args: &mut Vec<DatumWithOid<'a>>,
+    ) -> Result<String, SearchError> {
+        let params = fhir::compartment_params(self.code, entity).ok_or_else(|| {
+            SearchError::NotInCompartment(entity.to_string(), self.code.to_string())
+        })?;
+        if let Some(param) = unindexed_param(entity, &params) {
+            return Err(SearchError::CompartmentNotIndexed(
+                entity.to_string(),
+                self.code.to_string(),
+                param.clone(),
+            ));
+        }
+
+        args.push(entity.into());
+        args.push(params.into());
+        args.push(self.code.into());
+        args.push(self.id.into());
+        let arg = args.len();
+
+        let itself = if entity == self.code {
+            format!(r#" OR "entity_id" = ${arg}"#)
+        } else {
+            String::new()
+        };
+
+        Ok(format!(
+            r#"
+                    SELECT "entity_id"
+                    FROM ({sql}) "compartment_matches"
+                    WHERE
+                        "entity_id" IN (
+                            SELECT "entity_id"
+                            FROM "fhir"."entity_index_reference"
+                            WHERE
+                                "entity" = ${entity}
+                                AND "key" = ANY(${params})
+                                AND "target_type" = ${code}
+                                AND "target_id" = ${arg}
+                        ){itself}"#,
+            entity = arg - 3,
+            params = arg - 2,
+            code = arg - 1,
+        ))
+    }
+}
+
+/// Returns the first of the `params` that link `entity` to a compartment, which
+/// is not indexed as a reference.
+pub fn unindexed_param<'p>(entity: &str, params: &'p [String]) -> Option<&'p String> {
+    params.iter().find(|param| {
+        index::find_search_index_for_key(entity, param) != Some(IndexedKeyType::Reference)
+    })
+}
+
+/// Returns the resource types of a compartment, that can't be searched in the
+/// compartment, because not all parameters linking them to it are indexed.
+#[pg_extern]
+#[trace]
+pub fn fhir_compartment_unindexed_types(compartment: &str) -> Vec<String> {
+    let mut types = fhir::compartment_types(compartment)
+        .into_iter()
+        .filter(|(entity, params)| unindexed_param(entity, params).is_some())
+        .map(|(entity, _)| entity)
+        .collect::<Vec<_>>();
+    types.sort();
+
+    types
+}
+
+/// [`fhir_search`] in a compartment with string as search value.
+///
+/// Only the `entity` entities that are part of the compartment of the
+/// `compartment` entity with the id `compartment_id` are returned.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+#[pg_extern(name = "fhir_search")]
+#[trace]
+pub fn fhir_search_compartment_text(
+    compartment: &str,
+    compartment_id: Uuid,
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: String,
+    sort: default!(Option<&str>, "NULL"),
+    count: default!(Option<i64>, "NULL"),
+    cursor: default!(Option<&str>, "NULL"),
+    backwards: default!(bool, "false"),
+    includes: default!(Option<Vec<String>>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(idx, i64),
+            name!(id, Uuid),
+            name!(cursor, Option<String>),
+            name!(resource_type, String),
+            name!(mode, String),
+        ),
+    >,
+    SearchError,
+> {
+    let options = SearchOptions {
+        sort,
+        count,
+        cursor,
+        backwards,
+        includes: includes.as_deref().unwrap_or_default(),
+        compartment: Some(Compartment {
+            code: compartment,
+            id: compartment_id,
+        }),
+    };
+
+    fhir_search(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Text(value),
+        &options,
+    )
+}
+
+/// [`fhir_search`] in a compartment with date as search value.
+///
+/// See [`fhir_search_compartment_text`].
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+#[pg_extern(name = "fhir_search")]
+#[trace]
+pub fn fhir_search_compartment_date(
+    compartment: &str,
+    compartment_id: Uuid,
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: Date,
+    sort: default!(Option<&str>, "NULL"),
+    count: default!(Option<i64>, "NULL"),
+    cursor: default!(Option<&str>, "NULL"),
+    backwards: default!(bool, "false"),
+    includes: default!(Option<Vec<String>>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(idx, i64),
+            name!(id, Uuid),
+            name!(cursor, Option<String>),
+            name!(resource_type, String),
+            name!(mode, String),
+        ),
+    >,
+    SearchError,
+> {
+    let options = SearchOptions {
+        sort,
+        count,
+        cursor,
+        backwards,
+        includes: includes.as_deref().unwrap_or_default(),
+        compartment: Some(Compartment {
+            code: compartment,
+            id: compartment_id,
+        }),
+    };
+
+    fhir_search(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Date(value),
+        &options,
+    )
+}
+
+/// [`fhir_search_total`] in a compartment with string as search value.
+#[pg_extern(name = "fhir_search_total")]
+#[trace]
+pub fn fhir_search_total_compartment_text(
+    compartment: &str,
+    compartment_id: Uuid,
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: String,
+    estimate: default!(bool, "false"),
+) -> Result<i64, SearchError> {
+    let compartment = Compartment {
+        code: compartment,
+        id: compartment_id,
+    };
+
+    fhir_search_total(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Text(value),
+        Some(&compartment),
+        estimate,
+    )
+}
+
+/// [`fhir_search_total`] in a compartment with date as search value.
+#[pg_extern(name = "fhir_search_total")]
+#[trace]
+pub fn fhir_search_total_compartment_date(
+    compartment: &str,
+    compartment_id: Uuid,
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: Date,
+    estimate: default!(bool, "false"),
+) -> Result<i64, SearchError> {
+    let compartment = Compartment {
+        code: compartment,
+        id: compartment_id,
+    };
+
+    fhir_search_total(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Date(value),
+        Some(&compartment),
+        estimate,
+    )
+}
@@ -0,0 +1,52 @@
+//! Parsing of composite search values, like `http://loinc.org|8480-6$gt140`.
+
+use std::str::FromStr as _;
+
+use crate::api::search::{SearchError, SearchOperator};
+
+/// The value of a composite search parameter with a token and a quantity component.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenQuantity {
+    pub token: String,
+    pub operator: SearchOperator,
+    pub quantity: f64,
+}
+
+impl TokenQuantity {
+    /// Parses a composite value, whose components are separated by `$`.
+    ///
+    /// The token is either `code` or `system|code`. The quantity can be prefixed by
+    /// a comparison, like `gt` or `le`, and must be equal otherwise. Units are not
+    /// supported.
+    pub fn parse(value: &str) -> Result<Self, SearchError> {
+        let (token, quantity) = value
+            .split_once('$')
+            .filter(|(token, _)| !token.is_empty())
+            .ok_or(SearchError::InvalidValueType)?;
+
+        let (prefix, quantity) = quantity.split_at(
+            quantity
+                .find(|c: char| !c.is_ascii_alphabetic())
+                .unwrap_or(0),
+        );
+        let operator = match prefix {
+            "" => SearchOperator::Eq,
+            prefix => match SearchOperator::from_str(prefix)? {
+                SearchOperator::Like | SearchOperator::Trgm => {
+                    return Err(SearchError::UnknownOperator(prefix.to_string()));
+                }
+                operator => operator,
+            },
+        };
+
+        let quantity = quantity
+            .parse::<f64>()
+            .map_err(|_| SearchError::InvalidValueType)?;
+
+        Ok(Self {
+            token: token.to_string(),
+            operator,
+            quantity,
+        })
+    }
+}
@@ -0,0 +1,131 @@
+//! Continuation tokens for keyset pagination of search results.
+//!
+//! Instead of skipping a number of rows with `OFFSET`, the next page of a search
+//! starts right after the last entity of the previous page. The cursor stores the
+//! sort values and the id of that entity, so Postgres can seek to that position.
+
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use pgrx::{datum::DatumWithOid, Uuid};
+use serde::{Deserialize, Serialize};
+
+use crate::api::search::{
+    sort::{SortKey, SortSql},
+    SearchError,
+};
+
+/// Position of an entity inside a sorted search result.
+///
+/// The cursor is handed out as an opaque, url-safe string.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Cursor {
+    /// The values of all sort keys, in the same order as the `_sort` parameter.
+    sort_values: Vec<Option<String>>,
+
+    /// The id of the entity, which is always the last sort key.
+    id: String,
+}
+
+impl Cursor {
+    pub fn new(sort_values: Vec<Option<String>>, id: Uuid) -> Self {
+        Self {
+            sort_values,
+            id: id.to_string(),
+        }
+    }
+
+    /// Encodes the cursor to its opaque string representation.
+    pub fn encode(&self) -> String {
+        let json = serde_json::to_vec(self).expect("cursor must be serializable");
+        URL_SAFE_NO_PAD.encode(json)
+    }
+
+    /// Decodes a cursor that was created by [`Cursor::encode`].
+    ///
+    /// `sort_keys` are the keys of the current search, which must match the
+    /// keys that were used when the cursor was created.
+    pub fn decode(cursor: &str, sort_keys: &[SortKey<'_>]) -> Result<Self, SearchError> {
+        let cursor = URL_SAFE_NO_PAD
+            .decode(cursor)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<Self>(&json).ok())
+            .ok_or(SearchError::InvalidCursor)?;
+
+        if cursor.sort_values.len() != sort_keys.len() {
+            return Err(SearchError::InvalidCursor);
+        }
+
+        Ok(cursor)
+    }
+
+    /// Builds the SQL condition that only matches entities after this cursor,
+    /// in the order of `sort`.
+    ///
+    /// Consecutive keys that are sorted in the same direction are compared as a
+    /// single row, like `("entity"."last_updated", "entity"."id") > ($5, $6)`, so
+    /// Postgres can seek to the cursor in the index of the first value.
+    /// The values of the cursor are appended to `args`.
+    pub fn after_condition(&self, sort: &SortSql, args: &mut Vec<DatumWithOid<'_>>) -> String {
+        // The compared values with their sort direction, from the first to the last key.
+        let mut values = Vec::new();
+        for (column, value) in sort.sort_columns.iter().zip(&self.sort_values) {
+            // Missing values are always sorted last, which is the order of
+            // `IS NULL` for ascending keys, and `IS NOT NULL` for descending keys.
+            // If the cursor is missing the value, the following keys only have to be
+            // compared for entities that are also missing it.
+            if column.nullable {
+                let (flag, flag_value) = if column.key_descending {
+                    ("IS NOT NULL", value.is_some())
+                } else {
+                    ("IS NULL", value.is_none())
+                };
+                values.push((
+                    format!("({} {flag})", column.value),
+                    flag_value.to_string(),
+                    column.descending,
+                ));
+            }
+
+            if let Some(value) = value {
+                args.push(value.clone().into());
+                values.push((
+                    column.value.clone(),
+                    format!("${}::{}", args.len(), column.sql_type),
+                    column.descending,
+                ));
+            }
+        }
+
+        args.push(self.id.clone().into());
+        values.push((
+            r#""entity"."id""#.to_string(),
+            format!("${}::uuid", args.len()),
+            sort.id_descending,
+        ));
+
+        // Built from the last run of values to the first one, because every run
+        // only has to be checked when all previous runs are equal.
+        let mut runs = values.chunk_by(|(_, _, a), (_, _, b)| a == b).rev();
+        let row = |run: &[(String, String, bool)]| {
+            let (columns, values): (Vec<_>, Vec<_>) = run
+                .iter()
+                .map(|(column, value, _)| (column.as_str(), value.as_str()))
+                .unzip();
+            (columns.join(", "), values.join(", "))
+        };
+        let op = |run: &[(String, String, bool)]| if run[0].2 { "<" } else { ">" };
+
+        let last = runs.next().expect("the id is always compared");
+        let (columns, values) = row(last);
+        let mut condition = format!("({columns}) {} ({values})", op(last));
+
+        for run in runs {
+            let (columns, values) = row(run);
+            condition = format!(
+                "(({columns}) {op} ({values}) OR (({columns}) = ({values}) AND {condition}))",
+                op = op(run),
+            );
+        }
+
+        condition
+    }
+}
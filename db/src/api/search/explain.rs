@@ -0,0 +1,151 @@
+//! Diagnostics for the queries that are generated by [`fhir_search`](super::fhir_search).
+
+use std::collections::BTreeSet;
+
+use fastrace::prelude::*;
+use pgrx::{prelude::*, JsonB};
+use serde_json::{json, Value};
+
+use crate::api::search::{SearchEntity, SearchError, SearchOptions, SearchQuery, SearchValue};
+
+/// [`fhir_search_explain`] overload with string as search value.
+#[allow(clippy::too_many_arguments)]
+#[pg_extern(name = "fhir_search_explain")]
+#[trace]
+pub fn fhir_search_explain_text(
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: String,
+    sort: default!(Option<&str>, "NULL"),
+    count: default!(Option<i64>, "NULL"),
+    cursor: default!(Option<&str>, "NULL"),
+    backwards: default!(bool, "false"),
+    includes: default!(Option<Vec<String>>, "NULL"),
+    analyze: default!(bool, "false"),
+) -> Result<JsonB, SearchError> {
+    let options = SearchOptions {
+        sort,
+        count,
+        cursor,
+        backwards,
+        includes: includes.as_deref().unwrap_or_default(),
+        compartment: None,
+    };
+
+    fhir_search_explain(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Text(value),
+        &options,
+        analyze,
+    )
+}
+
+/// [`fhir_search_explain`] overload with date as search value.
+#[allow(clippy::too_many_arguments)]
+#[pg_extern(name = "fhir_search_explain")]
+#[trace]
+pub fn fhir_search_explain_date(
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: Date,
+    sort: default!(Option<&str>, "NULL"),
+    count: default!(Option<i64>, "NULL"),
+    cursor: default!(Option<&str>, "NULL"),
+    backwards: default!(bool, "false"),
+    includes: default!(Option<Vec<String>>, "NULL"),
+    analyze: default!(bool, "false"),
+) -> Result<JsonB, SearchError> {
+    let options = SearchOptions {
+        sort,
+        count,
+        cursor,
+        backwards,
+        includes: includes.as_deref().unwrap_or_default(),
+        compartment: None,
+    };
+
+    fhir_search_explain(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Date(value),
+        &options,
+        analyze,
+    )
+}
+
+/// Explains the query that [`fhir_search`](super::fhir_search) generates for a search.
+///
+/// Returns a JSON object with the generated `sql`, the `relations` and `indexes`
+/// that are used by the Postgres query plan, and the `plan` itself, as returned
+/// by `EXPLAIN (FORMAT JSON)`. If `analyze` is set, the query is executed, so
+/// the plan contains the actual row counts and timings.
+#[trace]
+pub fn fhir_search_explain<'a>(
+    entity: SearchEntity<'a>,
+    key: &'a str,
+    op: &str,
+    value: SearchValue,
+    options: &SearchOptions<'a>,
+    analyze: bool,
+) -> Result<JsonB, SearchError> {
+    let SearchQuery { sql, args, .. } = SearchQuery::new(entity, key, op, value, options)?;
+
+    let plan = {
+        let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+        Spi::get_one_with_args::<pgrx::Json>(
+            &format!("EXPLAIN (FORMAT JSON, VERBOSE, ANALYZE {analyze}) {sql}"),
+            &args,
+        )?
+        .map(|plan| plan.0)
+        .unwrap_or_default()
+    };
+
+    let mut relations = BTreeSet::new();
+    let mut indexes = BTreeSet::new();
+    collect_plan_relations(&plan, &mut relations, &mut indexes);
+
+    Ok(JsonB(json!({
+        "sql": sql,
+        "relations": relations,
+        "indexes": indexes,
+        "plan": plan,
+    })))
+}
+
+/// Collects the names of all relations and indexes that are scanned by the plan.
+fn collect_plan_relations(
+    plan: &Value,
+    relations: &mut BTreeSet<String>,
+    indexes: &mut BTreeSet<String>,
+) {
+    match plan {
+        Value::Object(node) => {
+            if let Some(Value::String(relation)) = node.get("Relation Name") {
+                match node.get("Schema") {
+                    Some(Value::String(schema)) => relations.insert(format!("{schema}.{relation}")),
+                    _ => relations.insert(relation.clone()),
+                };
+            }
+
+            if let Some(Value::String(index)) = node.get("Index Name") {
+                indexes.insert(index.clone());
+            }
+
+            for value in node.values() {
+                collect_plan_relations(value, relations, indexes);
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                collect_plan_relations(value, relations, indexes);
+            }
+        }
+        _ => {}
+    }
+}
@@ -0,0 +1,162 @@
+//! Resolving of the `_include` and `_revinclude` search parameters.
+//!
+//! Included entities are resolved using the `entity_index_reference` table,
+//! in the same query that searches for the matching entities.
+
+use pgrx::datum::DatumWithOid;
+
+use crate::{
+    api::search::SearchError,
+    index::{self, IndexedKeyType},
+};
+
+/// The maximum number of times `:iterate` includes are applied to included entities.
+const MAX_ITERATE_DEPTH: usize = 4;
+
+/// A single `_include` or `_revinclude` parameter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Include<'a> {
+    /// The resource type of the entities that contain the reference.
+    source: &'a str,
+
+    /// The search parameter of the reference.
+    key: &'a str,
+
+    /// Only include references to this resource type.
+    target: Option<&'a str>,
+
+    /// Include the entities that reference the matches, instead of the referenced entities.
+    reverse: bool,
+
+    /// Also apply this include to included entities.
+    iterate: bool,
+}
+
+impl<'a> Include<'a> {
+    /// Parses a single include parameter, including its name.
+    ///
+    /// Examples: `_include=Observation:subject`, `_include=Observation:subject:Patient`
+    /// or `_revinclude:iterate=Provenance:target`.
+    pub fn parse(param: &'a str) -> Result<Self, SearchError> {
+        let invalid = || SearchError::InvalidInclude(param.to_string());
+
+        let (name, value) = param.split_once('=').ok_or_else(invalid)?;
+        let (reverse, iterate) = match name {
+            "_include" => (false, false),
+            "_include:iterate" => (false, true),
+            "_revinclude" => (true, false),
+            "_revinclude:iterate" => (true, true),
+            _ => return Err(invalid()),
+        };
+
+        let mut parts = value.split(':');
+        let (Some(source), Some(key), target, None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(invalid());
+        };
+
+        if index::find_search_index_for_key(source, key) != Some(IndexedKeyType::Reference) {
+            return Err(SearchError::UnknownSearchKey(format!("{source}:{key}")));
+        }
+
+        Ok(Self {
+            source,
+            key,
+            target,
+            reverse,
+            iterate,
+        })
+    }
+
+    /// Builds the query that selects the ids of all entities included by this parameter.
+    ///
+    /// `from` is the relation that contains the `entity_id`s to resolve the include for.
+    fn sql(&self, from: &str, args: &mut Vec<DatumWithOid<'a>>) -> String {
+        args.push(self.source.into());
+        let source = args.len();
+        args.push(self.key.into());
+        let key = args.len();
+
+        let target = match self.target {
+            Some(target) => {
+                args.push(target.into());
+                format!(r#" AND "reference"."target_type" = ${}"#, args.len())
+            }
+            None => String::new(),
+        };
+
+        if self.reverse {
+            format!(
+                r#"
+                SELECT "reference"."entity_id"
+                FROM "fhir"."entity_index_reference" "reference"
+                WHERE
+                    "reference"."target_id" = {from}."entity_id"
+                    AND "reference"."entity" = ${source}
+                    AND "reference"."key" = ${key}{target}"#
+            )
+        } else {
+            format!(
+                r#"
+                SELECT "reference"."target_id" AS "entity_id"
+                FROM "fhir"."entity_index_reference" "reference"
+                WHERE
+                    "reference"."entity_id" = {from}."entity_id"
+                    AND "reference"."entity" = ${source}
+                    AND "reference"."key" = ${key}
+                    AND "reference"."target_id" IS NOT NULL{target}"#
+            )
+        }
+    }
+}
+
+/// Builds the recursive query that selects the ids of all included entities.
+///
+/// The matching entities must be available as the `"page"` relation.
+/// Returns [`None`] if there are no includes.
+pub fn included_sql<'a>(
+    includes: &[Include<'a>],
+    args: &mut Vec<DatumWithOid<'a>>,
+) -> Option<String> {
+    if includes.is_empty() {
+        return None;
+    }
+
+    let direct = includes
+        .iter()
+        .map(|include| include.sql(r#""page""#, args))
+        .collect::<Vec<_>>()
+        .join("\nUNION");
+
+    let iterate = includes
+        .iter()
+        .filter(|include| include.iterate)
+        .map(|include| include.sql(r#""included""#, args))
+        .collect::<Vec<_>>();
+
+    // The recursive part must only reference `included` once, so all iterated
+    // includes are combined inside a single lateral join.
+    let recursive = if iterate.is_empty() {
+        String::new()
+    } else {
+        format!(
+            r#"
+            UNION
+            SELECT "next"."entity_id", "included"."depth" + 1
+            FROM "included"
+            CROSS JOIN LATERAL ({iterate}) "next"
+            WHERE "included"."depth" < {MAX_ITERATE_DEPTH}"#,
+            iterate = iterate.join("\nUNION"),
+        )
+    };
+
+    Some(format!(
+        r#"
+        "included" ("entity_id", "depth") AS (
+            SELECT "direct"."entity_id", 1
+            FROM "page"
+            CROSS JOIN LATERAL ({direct}) "direct"{recursive}
+        )"#
+    ))
+}
@@ -0,0 +1,719 @@
+use std::str::FromStr;
+
+use fastrace::prelude::*;
+use pgrx::{datum::DatumWithOid, prelude::*, Uuid};
+use thiserror::Error;
+
+use crate::index::{self, IndexedKeyType};
+
+mod chain;
+mod compartment;
+mod composite;
+mod cursor;
+mod explain;
+mod include;
+mod sort;
+mod system;
+
+pub use chain::Chain;
+pub use compartment::{unindexed_param, Compartment};
+use composite::TokenQuantity;
+pub use cursor::Cursor;
+pub use include::Include;
+pub use sort::{SortKey, SortSql};
+
+/// Errors that can occurr in the [`fhir_search`] function.
+#[derive(Debug, Error)]
+pub enum SearchError {
+    /// The provided operator string is not a valid search operator.
+    #[error("unknown search operator: '{0}'")]
+    UnknownOperator(String),
+
+    /// The provided search key is not indexed.
+    #[error("unknown search key: '{0}'")]
+    UnknownSearchKey(String),
+
+    /// The provided modifier, like `:below`, is not supported by the search key.
+    #[error("unknown search modifier: '{0}'")]
+    UnknownModifier(String),
+
+    /// The searched resource type is not part of the compartment.
+    #[error("resource type '{0}' is not part of the '{1}' compartment")]
+    NotInCompartment(String, String),
+
+    /// A search parameter that links the searched resource type to the compartment
+    /// is not indexed, so the entities in the compartment can't be found.
+    #[error("resource type '{0}' can't be searched in the '{1}' compartment, because its '{2}' parameter is not indexed")]
+    CompartmentNotIndexed(String, String, String),
+
+    /// The provided `_sort` key is not indexed.
+    #[error("unknown sort key: '{0}'")]
+    UnknownSortKey(String),
+
+    /// The chained search key follows more references than allowed.
+    #[error("search keys can follow at most {0} references")]
+    ChainTooDeep(usize),
+
+    /// The provided cursor is malformed, or was created for a different `_sort`.
+    #[error("invalid search cursor")]
+    InvalidCursor,
+
+    /// The provided `_include` or `_revinclude` parameter is malformed.
+    #[error("invalid include parameter: '{0}'")]
+    InvalidInclude(String),
+
+    /// The search value has wrong type.
+    #[error("the search value is not valid for this search key")]
+    InvalidValueType,
+
+    /// The search value can't be parsed as the type of the search key.
+    #[error("invalid search value: '{0}'")]
+    InvalidValue(String),
+
+    #[error("{0}")]
+    Spi(
+        #[source]
+        #[from]
+        pgrx::spi::Error,
+    ),
+}
+
+/// Search operators for filtering FHIR entities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchOperator {
+    Eq,
+    Ne,
+    Lt,
+    Lte,
+    Gt,
+    Gte,
+    Like,
+    Trgm,
+}
+
+impl SearchOperator {
+    /// Converts the search operator to its corresponding Postgres operator string.
+    pub fn to_postgres_operator(self) -> &'static str {
+        match self {
+            SearchOperator::Eq => "=",
+            SearchOperator::Ne => "!=",
+            SearchOperator::Lt => "<",
+            SearchOperator::Lte => "<=",
+            SearchOperator::Gt => ">",
+            SearchOperator::Gte => ">=",
+            SearchOperator::Like => "ilike",
+            SearchOperator::Trgm => "%",
+        }
+    }
+}
+
+impl FromStr for SearchOperator {
+    type Err = SearchError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "eq" | "=" => Ok(SearchOperator::Eq),
+            "ne" | "!=" | "<>" => Ok(SearchOperator::Ne),
+            "lt" | "<" => Ok(SearchOperator::Lt),
+            "le" | "lte" | "<=" => Ok(SearchOperator::Lte),
+            "gt" | ">" => Ok(SearchOperator::Gt),
+            "ge" | "gte" | ">=" => Ok(SearchOperator::Gte),
+            "like" | "~" => Ok(SearchOperator::Like),
+            "%" => Ok(SearchOperator::Trgm),
+            _ => Err(SearchError::UnknownOperator(s.to_string())),
+        }
+    }
+}
+
+/// The types that can be passed as the search value
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SearchValue {
+    Text(String),
+    Date(Date),
+}
+
+/// The resource types that are searched.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SearchEntity<'a> {
+    /// Search the entities of a single resource type.
+    Type(&'a str),
+
+    /// Search the entities of the given resource types, or of all resource types if empty.
+    ///
+    /// Only the common search parameters are supported, see [`SYSTEM_ENTITY`].
+    System(&'a [String]),
+}
+
+/// The resource type that is used to look up the search keys of system searches.
+///
+/// No resource type has this name, so only the common search parameters are found.
+const SYSTEM_ENTITY: &str = "Resource";
+
+impl<'a> SearchEntity<'a> {
+    /// The resource type that is used to look up search keys.
+    fn name(self) -> &'a str {
+        match self {
+            SearchEntity::Type(entity) => entity,
+            SearchEntity::System(_) => SYSTEM_ENTITY,
+        }
+    }
+}
+
+/// The query that selects the ids of all entities matching a search parameter.
+///
+/// The query returns a single `entity_id` column, which contains an entity once for
+/// every matching value, and uses the first arguments of `args`. More arguments can be appended by the caller.
+struct MatchQuery<'a> {
+    sql: String,
+    args: Vec<DatumWithOid<'a>>,
+
+    /// Whether this is a full text search for the searched entity itself,
+    /// so the matches can be ranked using the search value in `$3`.
+    full_text: bool,
+}
+
+impl<'a> MatchQuery<'a> {
+    fn new(
+        entity: SearchEntity<'a>,
+        key: &'a str,
+        op: &str,
+        value: SearchValue,
+        compartment: Option<&Compartment<'a>>,
+    ) -> Result<Self, SearchError> {
+        let operator = SearchOperator::from_str(op)?;
+        let psql_op = operator.to_postgres_operator();
+
+        let chain = Chain::parse(entity.name(), key)?;
+        let (search_key, modifier) = match chain.key.split_once(':') {
+            Some((search_key, modifier)) => (search_key, Some(modifier)),
+            None => (chain.key, None),
+        };
+        let index_type = index::find_search_index_for_key(chain.entity, search_key)
+            .ok_or_else(|| SearchError::UnknownSearchKey(key.to_string()))?;
+
+        // Composite values contain their own operator for the quantity,
+        // which is compared using the additional argument `$4`.
+        let mut quantity = None;
+
+        // TODO: throw error on invalid operators for data type
+        let value: DatumWithOid<'_> = match (index_type, value) {
+            (IndexedKeyType::Composite, SearchValue::Text(v)) => {
+                let TokenQuantity {
+                    token,
+                    operator,
+                    quantity: value,
+                } = TokenQuantity::parse(&v)?;
+                quantity = Some((operator, value));
+                token.into()
+            }
+            (
+                IndexedKeyType::Text
+                | IndexedKeyType::Reference
+                | IndexedKeyType::Uri
+                | IndexedKeyType::Id
+                | IndexedKeyType::LastUpdated
+                | IndexedKeyType::FullText,
+                SearchValue::Text(v),
+            ) => v.into(),
+            (IndexedKeyType::Date | IndexedKeyType::LastUpdated, SearchValue::Date(v)) => v.into(),
+            (IndexedKeyType::Date, SearchValue::Text(v)) => Date::from_str(&v)
+                .map_err(|_| SearchError::InvalidValue(v))?
+                .into(),
+            _ => return Err(SearchError::InvalidValueType),
+        };
+
+        // Full text searches are parsed as a web search query, e.g. `"heart attack" -family`,
+        // using the `default_text_search_config` of the session.
+        // URIs are matched hierarchically by path segments, so `:below` of
+        // `http://hl7.org/fhir` matches `http://hl7.org/fhir/ValueSet/x`, and `:above`
+        // matches the other way around.
+        let condition = match (index_type, operator, modifier) {
+            (IndexedKeyType::Uri, SearchOperator::Eq, Some("below")) => {
+                r#"("value" = $3 OR starts_with("value", rtrim($3, '/') || '/'))"#.to_string()
+            }
+            (IndexedKeyType::Uri, SearchOperator::Eq, Some("above")) => {
+                r#"("value" = $3 OR starts_with($3, rtrim("value", '/') || '/'))"#.to_string()
+            }
+            (_, _, Some(modifier)) => {
+                return Err(SearchError::UnknownModifier(modifier.to_string()));
+            }
+            (IndexedKeyType::FullText, SearchOperator::Eq, None) => {
+                r#""value" @@ websearch_to_tsquery($3)"#.to_string()
+            }
+            (IndexedKeyType::Composite, SearchOperator::Eq, None) => {
+                let (operator, _) = quantity.ok_or(SearchError::InvalidValueType)?;
+                format!(
+                    r#""token" = $3 and "value" {} $4::numeric"#,
+                    operator.to_postgres_operator()
+                )
+            }
+            (
+                IndexedKeyType::FullText | IndexedKeyType::Composite | IndexedKeyType::Reference,
+                _,
+                None,
+            ) if operator != SearchOperator::Eq => {
+                return Err(SearchError::UnknownOperator(op.to_string()))
+            }
+            (_, _, None) => format!(r#""value" {psql_op} $3::{}"#, index_type.sql_type()),
+        };
+
+        let (entity_condition, entity_arg): (_, DatumWithOid<'_>) = match entity {
+            SearchEntity::Type(_) => (r#""entity" = $1"#, chain.entity.into()),
+            SearchEntity::System(_) if chain.is_chained() => {
+                return Err(SearchError::UnknownSearchKey(key.to_string()));
+            }
+            SearchEntity::System(types) if types.is_empty() => ("true", types.to_vec().into()),
+            SearchEntity::System(types) => (r#""entity" = ANY($1)"#, types.to_vec().into()),
+        };
+
+        let sql = format!(
+            r#"
+                    SELECT
+                        "entity_id"
+                    FROM
+                        {relation}
+                    WHERE
+                        {entity_condition}
+                        and "key" = $2
+                        and {condition}
+            "#,
+            relation = index_type.relation(),
+        );
+
+        let full_text = index_type == IndexedKeyType::FullText && !chain.is_chained();
+        let mut args = vec![entity_arg, search_key.into(), value];
+        if let Some((_, value)) = quantity {
+            args.push(value.into());
+        }
+        let mut sql = chain.wrap_sql(sql, &mut args);
+        if let Some(compartment) = compartment {
+            sql = compartment.wrap_sql(entity.name(), sql, &mut args)?;
+        }
+
+        Ok(Self {
+            sql,
+            args,
+            full_text,
+        })
+    }
+}
+
+/// Options that control the order and pagination of search results.
+#[derive(Debug, Clone, Default)]
+pub struct SearchOptions<'a> {
+    /// Comma separated list of keys to sort by, see [`SortKey::parse_list`].
+    pub sort: Option<&'a str>,
+
+    /// The maximum number of entities to return.
+    pub count: Option<i64>,
+
+    /// Continue the search at this cursor.
+    pub cursor: Option<&'a str>,
+
+    /// Return the entities before `cursor`, instead of after.
+    pub backwards: bool,
+
+    /// `_include` and `_revinclude` parameters, see [`Include::parse`].
+    pub includes: &'a [String],
+
+    /// Only return entities in this compartment, see [`Compartment::wrap_sql`].
+    pub compartment: Option<Compartment<'a>>,
+}
+
+/// The generated query of a search, see [`fhir_search`].
+///
+/// The query returns the `entity_id`, `resource_type` and `mode` columns,
+/// followed by one column with the text value of every sort key.
+struct SearchQuery<'a> {
+    sql: String,
+    args: Vec<DatumWithOid<'a>>,
+    sort_keys: Vec<SortKey<'a>>,
+}
+
+impl<'a> SearchQuery<'a> {
+    fn new(
+        entity: SearchEntity<'a>,
+        key: &'a str,
+        op: &str,
+        value: SearchValue,
+        options: &SearchOptions<'a>,
+    ) -> Result<Self, SearchError> {
+        let SearchOptions {
+            sort,
+            count,
+            cursor,
+            backwards,
+            includes,
+            compartment,
+        } = *options;
+
+        let MatchQuery {
+            sql: match_sql,
+            mut args,
+            full_text,
+        } = MatchQuery::new(entity, key, op, value, compartment.as_ref())?;
+
+        let sort_keys = sort
+            .map(|sort| SortKey::parse_list(entity.name(), sort))
+            .transpose()?
+            .unwrap_or_default();
+
+        let cursor = cursor
+            .map(|cursor| Cursor::decode(cursor, &sort_keys))
+            .transpose()?;
+
+        let includes = includes
+            .iter()
+            .map(|include| Include::parse(include))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let sort = SortSql::new(&sort_keys, backwards, full_text, &mut args)?;
+
+        let after_cursor = cursor.as_ref().map_or_else(
+            || "true".to_string(),
+            |cursor| cursor.after_condition(&sort, &mut args),
+        );
+
+        let limit = match count {
+            Some(count) => {
+                args.push(count.into());
+                format!("LIMIT ${}", args.len())
+            }
+            None => String::new(),
+        };
+
+        let SortSql {
+            columns: sort_columns,
+            joins: sort_joins,
+            order_by,
+            result_order_by,
+            page_columns: page_sort_columns,
+            text_columns: text_sort_columns,
+            null_columns: include_sort_columns,
+            ..
+        } = sort;
+
+        let (included_cte, included_select) = match include::included_sql(&includes, &mut args) {
+            Some(sql) => (
+                format!(",{sql}"),
+                format!(
+                    r#"
+                    UNION ALL
+                    SELECT
+                        "entity"."id", "entity"."resource_type", 'include'{include_sort_columns}
+                    FROM "fhir"."entity" "entity"
+                    WHERE
+                        "entity"."id" IN (SELECT "entity_id" FROM "included")
+                        AND "entity"."id" NOT IN (SELECT "entity_id" FROM "page")"#
+                ),
+            ),
+            None => (String::new(), String::new()),
+        };
+
+        // The page is read from the entity table in the sort order, and stops after
+        // `count` entities, so only the entities of the page have to be sorted again.
+        // `match` is sorted before `include`.
+        let sql = format!(
+            r#"
+                WITH RECURSIVE "page" AS (
+                    SELECT
+                        "entity"."id" AS "entity_id", "entity"."resource_type"{sort_columns}
+                    FROM "fhir"."entity" "entity"{sort_joins}
+                    WHERE
+                        "entity"."id" IN ({match_sql})
+                        AND {after_cursor}
+                    ORDER BY
+                        {order_by}
+                    {limit}
+                ){included_cte}
+                SELECT
+                    "entity_id", "resource_type", "mode"{text_sort_columns}
+                FROM (
+                    SELECT
+                        "page"."entity_id", "page"."resource_type", 'match' AS "mode"{page_sort_columns}
+                    FROM "page"{included_select}
+                ) "results"
+                ORDER BY "mode" DESC, {result_order_by}
+            "#,
+        );
+
+        Ok(Self {
+            sql,
+            args,
+            sort_keys,
+        })
+    }
+}
+
+/// [`fhir_search`] overload with string as search value.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+#[pg_extern(name = "fhir_search")]
+#[trace]
+pub fn fhir_search_text(
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: String,
+    sort: default!(Option<&str>, "NULL"),
+    count: default!(Option<i64>, "NULL"),
+    cursor: default!(Option<&str>, "NULL"),
+    backwards: default!(bool, "false"),
+    includes: default!(Option<Vec<String>>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(idx, i64),
+            name!(id, Uuid),
+            name!(cursor, Option<String>),
+            name!(resource_type, String),
+            name!(mode, String),
+        ),
+    >,
+    SearchError,
+> {
+    let options = SearchOptions {
+        sort,
+        count,
+        cursor,
+        backwards,
+        includes: includes.as_deref().unwrap_or_default(),
+        compartment: None,
+    };
+
+    fhir_search(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Text(value),
+        &options,
+    )
+}
+
+/// [`fhir_search`] overload with date as search value.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+#[pg_extern(name = "fhir_search")]
+#[trace]
+pub fn fhir_search_date(
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: Date,
+    sort: default!(Option<&str>, "NULL"),
+    count: default!(Option<i64>, "NULL"),
+    cursor: default!(Option<&str>, "NULL"),
+    backwards: default!(bool, "false"),
+    includes: default!(Option<Vec<String>>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(idx, i64),
+            name!(id, Uuid),
+            name!(cursor, Option<String>),
+            name!(resource_type, String),
+            name!(mode, String),
+        ),
+    >,
+    SearchError,
+> {
+    let options = SearchOptions {
+        sort,
+        count,
+        cursor,
+        backwards,
+        includes: includes.as_deref().unwrap_or_default(),
+        compartment: None,
+    };
+
+    fhir_search(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Date(value),
+        &options,
+    )
+}
+
+/// Searches for FHIR entities based on indexed search parameters.
+///
+/// This function performs searches against the FHIR entity index tables
+/// to efficiently find entities that match the specified search criteria.
+/// The `key` can also be a chained or `_has` key, see [`Chain::parse`].
+///
+/// The results are ordered by the keys of [`SearchOptions::sort`],
+/// using the same index tables that are used for filtering.
+/// Entities without a value for a sort key are always placed last, and ties are
+/// broken by the entity id, so the order is stable across calls.
+///
+/// At most [`SearchOptions::count`] entities are returned. Every returned row contains
+/// a `cursor`, which can be passed to the next call to continue the search after that row.
+/// If [`SearchOptions::backwards`] is set, the entities right before the cursor are
+/// returned instead, which is used to get the previous page.
+///
+/// Entities referenced by [`SearchOptions::includes`] are returned after the matches,
+/// with `mode` set to `include` instead of `match`. They do not count towards
+/// [`SearchOptions::count`] and have no cursor.
+#[allow(clippy::type_complexity)]
+#[trace]
+pub fn fhir_search<'a>(
+    entity: SearchEntity<'a>,
+    key: &'a str,
+    op: &str,
+    value: SearchValue,
+    options: &SearchOptions<'a>,
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(idx, i64),
+            name!(id, Uuid),
+            name!(cursor, Option<String>),
+            name!(resource_type, String),
+            name!(mode, String),
+        ),
+    >,
+    SearchError,
+> {
+    let SearchQuery {
+        sql,
+        args,
+        sort_keys,
+    } = SearchQuery::new(entity, key, op, value, options)?;
+
+    let rows = {
+        let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+        Spi::connect(|conn| {
+            conn.select(&sql, None, &args)?
+                .map(|row| {
+                    let (Some(id), Some(resource_type), Some(mode)) = (
+                        row.get::<Uuid>(1)?,
+                        row.get::<String>(2)?,
+                        row.get::<String>(3)?,
+                    ) else {
+                        return Ok(None);
+                    };
+
+                    let sort_values = (0..sort_keys.len())
+                        .map(|i| row.get::<String>(i + 4))
+                        .collect::<pgrx::spi::Result<Vec<_>>>()?;
+
+                    Ok(Some((id, sort_values, resource_type, mode)))
+                })
+                .filter_map(Result::transpose)
+                .collect::<pgrx::spi::Result<Vec<_>>>()
+        })?
+    };
+
+    let (mut matches, included): (Vec<_>, Vec<_>) = rows
+        .into_iter()
+        .partition(|(_, _, _, mode)| mode == "match");
+
+    if options.backwards {
+        matches.reverse();
+    }
+
+    let rows = matches
+        .into_iter()
+        .map(|(id, sort_values, resource_type, mode)| {
+            let cursor = Cursor::new(sort_values, id).encode();
+            (id, Some(cursor), resource_type, mode)
+        })
+        .chain(
+            included
+                .into_iter()
+                .map(|(id, _, resource_type, mode)| (id, None, resource_type, mode)),
+        );
+
+    Ok(TableIterator::new(rows.enumerate().map(
+        |(idx, (id, cursor, resource_type, mode))| {
+            (
+                i64::try_from(idx).expect("usize to i64 conversion failed"),
+                id,
+                cursor,
+                resource_type,
+                mode,
+            )
+        },
+    )))
+}
+
+/// [`fhir_search_total`] overload with string as search value.
+#[pg_extern(name = "fhir_search_total")]
+#[trace]
+pub fn fhir_search_total_text(
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: String,
+    estimate: default!(bool, "false"),
+) -> Result<i64, SearchError> {
+    fhir_search_total(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Text(value),
+        None,
+        estimate,
+    )
+}
+
+/// [`fhir_search_total`] overload with date as search value.
+#[pg_extern(name = "fhir_search_total")]
+#[trace]
+pub fn fhir_search_total_date(
+    entity: &str,
+    key: &str,
+    op: &str,
+    value: Date,
+    estimate: default!(bool, "false"),
+) -> Result<i64, SearchError> {
+    fhir_search_total(
+        SearchEntity::Type(entity),
+        key,
+        op,
+        SearchValue::Date(value),
+        None,
+        estimate,
+    )
+}
+
+/// Counts the number of entities that match a search.
+///
+/// If `estimate` is set, the entities are not counted, but the row estimate of the
+/// Postgres query planner is returned instead. This is much cheaper for large
+/// results, but is only as accurate as the table statistics.
+#[trace]
+pub fn fhir_search_total<'a>(
+    entity: SearchEntity<'a>,
+    key: &'a str,
+    op: &str,
+    value: SearchValue,
+    compartment: Option<&Compartment<'a>>,
+    estimate: bool,
+) -> Result<i64, SearchError> {
+    let MatchQuery { sql, args, .. } = MatchQuery::new(entity, key, op, value, compartment)?;
+    let sql = format!(r#"SELECT DISTINCT "entity_id" FROM ({sql}) "matches""#);
+
+    let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+    if !estimate {
+        let total = Spi::get_one_with_args::<i64>(
+            &format!(r#"SELECT count(*) FROM ({sql}) "matches""#),
+            &args,
+        )?;
+
+        return Ok(total.unwrap_or_default());
+    }
+
+    let plan =
+        Spi::get_one_with_args::<pgrx::Json>(&format!("EXPLAIN (FORMAT JSON) {sql}"), &args)?;
+
+    #[allow(clippy::cast_possible_truncation)]
+    let rows = plan
+        .and_then(|plan| plan.0[0]["Plan"]["Plan Rows"].as_f64())
+        .map_or(0, |rows| rows.round() as i64);
+
+    Ok(rows)
+}
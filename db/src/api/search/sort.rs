@@ -0,0 +1,215 @@
+//! Parsing of the `_sort` search parameter.
+
+use std::fmt::Write as _;
+
+use pgrx::datum::DatumWithOid;
+
+use crate::{
+    api::search::SearchError,
+    index::{self, IndexedKeyType},
+};
+
+/// A single key of the `_sort` search parameter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SortKey<'a> {
+    pub key: &'a str,
+    pub index_type: IndexedKeyType,
+    pub descending: bool,
+}
+
+impl<'a> SortKey<'a> {
+    /// Parses a comma separated list of sort keys, like `family,-birth_date`.
+    ///
+    /// Keys prefixed with `-` are sorted in descending order.
+    pub fn parse_list(entity: &str, sort: &'a str) -> Result<Vec<Self>, SearchError> {
+        sort.split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                let (key, descending) = match key.strip_prefix('-') {
+                    Some(key) => (key, true),
+                    None => (key, false),
+                };
+
+                let index_type = index::find_search_index_for_key(entity, key)
+                    .ok_or_else(|| SearchError::UnknownSortKey(key.to_string()))?;
+
+                Ok(Self {
+                    key,
+                    index_type,
+                    descending,
+                })
+            })
+            .collect()
+    }
+}
+
+/// A sort value of the page query, see [`SortSql`].
+pub struct SortColumn {
+    /// The SQL expression of the value.
+    pub value: String,
+
+    /// The Postgres type of the value, see [`IndexedKeyType::sql_type`].
+    pub sql_type: &'static str,
+
+    /// Whether entities can be missing the value.
+    pub nullable: bool,
+
+    /// Whether the key is sorted in descending order, as given in `_sort`.
+    pub key_descending: bool,
+
+    /// Whether the value is sorted in descending order, after applying `backwards`.
+    pub descending: bool,
+}
+
+/// The SQL fragments that sort the entities of a search by the `_sort` keys.
+///
+/// The sorted relation is `"fhir"."entity" "entity"`. Values of the entity table
+/// are used directly, so the page can be read in order from their indexes.
+/// Keys with multiple values are joined as `"sort_{n}"."value"`.
+///
+/// The sort values are selected as `"sort_value_{n}"`, and ties are broken by the
+/// entity id.
+pub struct SortSql {
+    /// The sort values, selected from the entity.
+    pub columns: String,
+
+    /// The lateral joins that look up the values of keys with multiple values.
+    pub joins: String,
+
+    /// The `ORDER BY` expressions of the page, including the entity id.
+    pub order_by: String,
+
+    /// The `ORDER BY` expressions of the selected `"sort_value_{n}"` and `"entity_id"` columns.
+    pub result_order_by: String,
+
+    /// The selected sort values.
+    pub page_columns: String,
+
+    /// The selected sort values as text.
+    pub text_columns: String,
+
+    /// A typed `NULL` for every sort value.
+    pub null_columns: String,
+
+    /// The sort values, in the order of the `_sort` keys.
+    pub sort_columns: Vec<SortColumn>,
+
+    /// Whether the entity id is sorted in descending order.
+    ///
+    /// The id follows the direction of the last key, so a cursor of keys that are
+    /// all sorted in the same direction is a single row comparison.
+    pub id_descending: bool,
+}
+
+impl SortSql {
+    /// Builds the fragments for `sort_keys`, and appends their arguments to `args`.
+    ///
+    /// `full_text` must be set if `$3` is a full text search for the searched entity,
+    /// because full text keys can only be sorted by the rank of that search.
+    pub fn new<'a>(
+        sort_keys: &[SortKey<'a>],
+        backwards: bool,
+        full_text: bool,
+        args: &mut Vec<DatumWithOid<'a>>,
+    ) -> Result<Self, SearchError> {
+        let mut columns = String::new();
+        let mut joins = String::new();
+        let mut order_by = String::new();
+        let mut result_order_by = String::new();
+        let mut page_columns = String::new();
+        let mut text_columns = String::new();
+        let mut null_columns = String::new();
+        let mut sort_columns = Vec::with_capacity(sort_keys.len());
+
+        // When searching backwards, the whole order is reversed, and the
+        // results are reversed again after fetching them.
+        let nulls = if backwards {
+            "NULLS FIRST"
+        } else {
+            "NULLS LAST"
+        };
+        let direction = |descending| if descending { "DESC" } else { "ASC" };
+
+        for (i, sort_key) in sort_keys.iter().enumerate() {
+            // Single valued keys are stored in the `entity` table.
+            // Full text keys are sorted by their rank for the searched text.
+            // For keys with multiple values, the smallest value is used for ascending
+            // order and the largest one for descending order.
+            let value = match (sort_key.index_type, sort_key.key) {
+                (IndexedKeyType::FullText, "_text") if full_text => {
+                    r#"ts_rank("entity"."narrative", websearch_to_tsquery($3))"#.to_string()
+                }
+                (IndexedKeyType::FullText, _) if full_text => {
+                    r#"ts_rank("entity"."content", websearch_to_tsquery($3))"#.to_string()
+                }
+                (IndexedKeyType::FullText, _) => {
+                    return Err(SearchError::UnknownSortKey(sort_key.key.to_string()));
+                }
+                (IndexedKeyType::Id, _) => r#""entity"."id""#.to_string(),
+                (IndexedKeyType::LastUpdated, _) => r#""entity"."last_updated""#.to_string(),
+                (index_type, _) => {
+                    args.push(sort_key.key.into());
+                    write!(
+                        joins,
+                        r#"
+                    LEFT JOIN LATERAL (
+                        SELECT {aggregate}("value") AS "value"
+                        FROM {relation}
+                        WHERE "entity_id" = "entity"."id" AND "key" = ${arg}
+                    ) "sort_{i}" ON true"#,
+                        aggregate = if sort_key.descending { "max" } else { "min" },
+                        relation = index_type.relation(),
+                        arg = args.len(),
+                    )
+                    .expect("writing to a string can not fail");
+
+                    format!(r#""sort_{i}"."value""#)
+                }
+            };
+
+            let descending = sort_key.descending != backwards;
+            let direction = direction(descending);
+            let sql_type = sort_key.index_type.sql_type();
+
+            write!(columns, r#", {value} AS "sort_value_{i}""#)
+                .expect("writing to a string can not fail");
+            write!(order_by, "{value} {direction} {nulls}, ")
+                .expect("writing to a string can not fail");
+            write!(result_order_by, r#""sort_value_{i}" {direction} {nulls}, "#)
+                .expect("writing to a string can not fail");
+            write!(page_columns, r#", "sort_value_{i}""#)
+                .expect("writing to a string can not fail");
+            write!(text_columns, r#", "sort_value_{i}"::text"#)
+                .expect("writing to a string can not fail");
+            write!(null_columns, ", NULL::{sql_type}").expect("writing to a string can not fail");
+
+            sort_columns.push(SortColumn {
+                value,
+                sql_type,
+                nullable: !sort_key.index_type.is_single_valued(),
+                key_descending: sort_key.descending,
+                descending,
+            });
+        }
+
+        let id_descending = sort_keys.last().is_some_and(|key| key.descending) != backwards;
+        let id_direction = direction(id_descending);
+        write!(order_by, r#""entity"."id" {id_direction}"#)
+            .expect("writing to a string can not fail");
+        write!(result_order_by, r#""entity_id" {id_direction}"#)
+            .expect("writing to a string can not fail");
+
+        Ok(Self {
+            columns,
+            joins,
+            order_by,
+            result_order_by,
+            page_columns,
+            text_columns,
+            null_columns,
+            sort_columns,
+            id_descending,
+        })
+    }
+}
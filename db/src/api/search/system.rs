@@ -0,0 +1,144 @@
+//! Searches across resource types, using [`SearchEntity::System`].
+
+use fastrace::prelude::*;
+use pgrx::{prelude::*, Uuid};
+
+use crate::api::search::{
+    fhir_search, fhir_search_total, SearchEntity, SearchError, SearchOptions, SearchValue,
+};
+
+/// [`fhir_search`] across resource types with string as search value.
+///
+/// `types` restricts the search to the given resource types, if it is `NULL` or empty
+/// all resource types are searched. Only the common search parameters, like `_id`
+/// or `_lastUpdated`, can be used as `key`.
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+#[pg_extern(name = "fhir_search_system")]
+#[trace]
+pub fn fhir_search_system_text(
+    types: Option<Vec<String>>,
+    key: &str,
+    op: &str,
+    value: String,
+    sort: default!(Option<&str>, "NULL"),
+    count: default!(Option<i64>, "NULL"),
+    cursor: default!(Option<&str>, "NULL"),
+    backwards: default!(bool, "false"),
+    includes: default!(Option<Vec<String>>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(idx, i64),
+            name!(id, Uuid),
+            name!(cursor, Option<String>),
+            name!(resource_type, String),
+            name!(mode, String),
+        ),
+    >,
+    SearchError,
+> {
+    let options = SearchOptions {
+        sort,
+        count,
+        cursor,
+        backwards,
+        includes: includes.as_deref().unwrap_or_default(),
+        compartment: None,
+    };
+
+    fhir_search(
+        SearchEntity::System(types.as_deref().unwrap_or_default()),
+        key,
+        op,
+        SearchValue::Text(value),
+        &options,
+    )
+}
+
+/// [`fhir_search`] across resource types with date as search value.
+///
+/// See [`fhir_search_system_text`].
+#[allow(clippy::too_many_arguments, clippy::type_complexity)]
+#[pg_extern(name = "fhir_search_system")]
+#[trace]
+pub fn fhir_search_system_date(
+    types: Option<Vec<String>>,
+    key: &str,
+    op: &str,
+    value: Date,
+    sort: default!(Option<&str>, "NULL"),
+    count: default!(Option<i64>, "NULL"),
+    cursor: default!(Option<&str>, "NULL"),
+    backwards: default!(bool, "false"),
+    includes: default!(Option<Vec<String>>, "NULL"),
+) -> Result<
+    TableIterator<
+        'static,
+        (
+            name!(idx, i64),
+            name!(id, Uuid),
+            name!(cursor, Option<String>),
+            name!(resource_type, String),
+            name!(mode, String),
+        ),
+    >,
+    SearchError,
+> {
+    let options = SearchOptions {
+        sort,
+        count,
+        cursor,
+        backwards,
+        includes: includes.as_deref().unwrap_or_default(),
+        compartment: None,
+    };
+
+    fhir_search(
+        SearchEntity::System(types.as_deref().unwrap_or_default()),
+        key,
+        op,
+        SearchValue::Date(value),
+        &options,
+    )
+}
+
+/// [`fhir_search_total`] across resource types with string as search value.
+#[pg_extern(name = "fhir_search_system_total")]
+#[trace]
+pub fn fhir_search_system_total_text(
+    types: Option<Vec<String>>,
+    key: &str,
+    op: &str,
+    value: String,
+    estimate: default!(bool, "false"),
+) -> Result<i64, SearchError> {
+    fhir_search_total(
+        SearchEntity::System(types.as_deref().unwrap_or_default()),
+        key,
+        op,
+        SearchValue::Text(value),
+        None,
+        estimate,
+    )
+}
+
+/// [`fhir_search_total`] across resource types with date as search value.
+#[pg_extern(name = "fhir_search_system_total")]
+#[trace]
+pub fn fhir_search_system_total_date(
+    types: Option<Vec<String>>,
+    key: &str,
+    op: &str,
+    value: Date,
+    estimate: default!(bool, "false"),
+) -> Result<i64, SearchError> {
+    fhir_search_total(
+        SearchEntity::System(types.as_deref().unwrap_or_default()),
+        key,
+        op,
+        SearchValue::Date(value),
+        None,
+        estimate,
+    )
+}
@@ -0,0 +1,185 @@
+//! The terminology operations [`$lookup`](<https://hl7.org/fhir/codesystem-operation-lookup.html>),
+//! [`$validate-code`](<https://hl7.org/fhir/valueset-operation-validate-code.html>)
+//! and [`$expand`](<https://hl7.org/fhir/valueset-operation-expand.html>).
+
+use fastrace::trace;
+use pgrx::{prelude::*, JsonB};
+use serde_json::json;
+
+use crate::{
+    api::common::{find_canonical, find_canonical_entity},
+    terminology::{
+        self, expansion,
+        valueset::{self, Membership},
+    },
+};
+
+/// Looks up a code of a stored `CodeSystem`.
+///
+/// Returns the name and version of the code system, the display and definition of
+/// the concept, and the codes of its `parent` and `child` concepts as properties.
+/// Returns `NULL` if the code is unknown.
+#[pg_extern]
+#[trace]
+pub fn fhir_code_lookup(
+    system: &str,
+    code: &str,
+    version: default!(Option<&str>, "NULL"),
+) -> Option<JsonB> {
+    let concept =
+        terminology::find_concept(system, version, code).expect("Failed to look up the code")?;
+    let children =
+        terminology::child_codes(system, version, code).expect("Failed to look up the code");
+    let code_system = find_canonical(
+        "CodeSystem",
+        &version.map_or_else(
+            || system.to_string(),
+            |version| format!("{system}|{version}"),
+        ),
+    )
+    .expect("Failed to look up the code system")
+    .unwrap_or_default();
+
+    let mut parameters = vec![json!({
+        "name": "name",
+        "valueString": code_system["name"].as_str().unwrap_or(system),
+    })];
+    if let Some(version) = code_system["version"].as_str() {
+        parameters.push(json!({ "name": "version", "valueString": version }));
+    }
+    if let Some(display) = concept.display {
+        parameters.push(json!({ "name": "display", "valueString": display }));
+    }
+    if let Some(definition) = concept.definition {
+        parameters.push(json!({ "name": "definition", "valueString": definition }));
+    }
+
+    let relatives = concept
+        .parent
+        .into_iter()
+        .map(|parent| ("parent", parent))
+        .chain(children.into_iter().map(|child| ("child", child)));
+    for (relation, code) in relatives {
+        parameters.push(json!({
+            "name": "property",
+            "part": [
+                { "name": "code", "valueCode": relation },
+                { "name": "value", "valueCode": code },
+            ],
+        }));
+    }
+
+    Some(JsonB(json!({
+        "resourceType": "Parameters",
+        "parameter": parameters,
+    })))
+}
+
+/// Checks if a code is part of the `ValueSet` with the given canonical url.
+///
+/// Returns the `result`, the `display` of the code and a `message` if the code is
+/// not valid. Returns `NULL` if the value set is unknown.
+#[pg_extern]
+#[trace]
+pub fn fhir_validate_code(valueset_url: &str, system: &str, code: &str) -> Option<JsonB> {
+    let valueset =
+        find_canonical("ValueSet", valueset_url).expect("Failed to look up the value set")?;
+
+    let membership =
+        valueset::contains(&valueset, system, code).expect("Failed to validate the code");
+
+    let (result, display, message) = match membership {
+        Membership::Member(display) => {
+            let concept =
+                terminology::find_concept(system, None, code).expect("Failed to look up the code");
+            (true, concept.and_then(|c| c.display).or(display), None)
+        }
+        Membership::NotMember => (
+            false,
+            None,
+            Some(format!(
+                "the code '{system}|{code}' is not part of the value set '{valueset_url}'"
+            )),
+        ),
+        Membership::Unknown(message) => (false, None, Some(message)),
+    };
+
+    let mut parameters = vec![json!({ "name": "result", "valueBoolean": result })];
+    if let Some(message) = message {
+        parameters.push(json!({ "name": "message", "valueString": message }));
+    }
+    if let Some(display) = display {
+        parameters.push(json!({ "name": "display", "valueString": display }));
+    }
+
+    Some(JsonB(json!({
+        "resourceType": "Parameters",
+        "parameter": parameters,
+    })))
+}
+
+/// Expands the `ValueSet` with the given canonical url.
+///
+/// Returns the value set with an `expansion`, containing the page of concepts given by
+/// `offset` and `count`, and the `total` number of concepts. `filter` only returns
+/// concepts whose display matches the text. Returns `NULL` if the value set is
+/// unknown.
+///
+/// Expansions are cached until a `CodeSystem` or `ValueSet` changes.
+#[pg_extern]
+#[trace]
+pub fn fhir_expand(
+    valueset_url: &str,
+    filter: default!(Option<&str>, "NULL"),
+    offset: default!(i64, "0"),
+    count: default!(Option<i64>, "NULL"),
+) -> Option<JsonB> {
+    let (id, mut valueset) = find_canonical_entity("ValueSet", valueset_url)
+        .expect("Failed to look up the value set")?;
+
+    if let Err(message) =
+        expansion::ensure_cached(id, &valueset).expect("Failed to expand the value set")
+    {
+        panic!("cannot expand value set '{valueset_url}': {message}");
+    }
+
+    let filter = filter.filter(|filter| !filter.is_empty());
+    let offset = offset.max(0);
+    let page = expansion::page(id, filter, offset, count.map(|count| count.max(0)))
+        .expect("Failed to read the expansion");
+
+    let mut parameters = vec![json!({ "name": "offset", "valueInteger": offset })];
+    if let Some(filter) = filter {
+        parameters.push(json!({ "name": "filter", "valueString": filter }));
+    }
+    if let Some(count) = count {
+        parameters.push(json!({ "name": "count", "valueInteger": count }));
+    }
+
+    let contains = page
+        .concepts
+        .into_iter()
+        .map(|concept| {
+            let mut entry = json!({ "system": concept.system, "code": concept.code });
+            if let Some(version) = concept.version {
+                entry["version"] = json!(version);
+            }
+            if let Some(display) = concept.display {
+                entry["display"] = json!(display);
+            }
+            entry
+        })
+        .collect::<Vec<_>>();
+
+    valueset["resourceType"] = json!("ValueSet");
+    valueset["id"] = json!(id.to_string());
+    valueset["expansion"] = json!({
+        "timestamp": page.timestamp,
+        "total": page.total,
+        "offset": offset,
+        "parameter": parameters,
+        "contains": contains,
+    });
+
+    Some(JsonB(valueset))
+}
@@ -0,0 +1,318 @@
+//! Processing of [transaction and batch](<https://hl7.org/fhir/http.html#transaction>) Bundles.
+
+use std::collections::HashMap;
+
+use fastrace::trace;
+use pgrx::{prelude::*, JsonB, Uuid};
+use serde_json::{json, Map, Value};
+use thiserror::Error;
+
+use crate::{
+    api::{
+        common::fhir_generate_id,
+        put::insert_entity,
+        validate::{first_error, record_issues, validate_write, WriteValidation},
+    },
+    fhir::ValidationIssue,
+    spi,
+};
+
+/// Errors that can occurr while processing a Bundle.
+///
+/// Invalid Bundles and entries are reported using an `OperationOutcome` instead.
+#[derive(Debug, Error)]
+pub enum TransactionError {
+    #[error("{0}")]
+    Spi(
+        #[source]
+        #[from]
+        pgrx::spi::Error,
+    ),
+}
+
+/// How the entries of a Bundle are processed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BundleType {
+    /// All entries succeed or fail together.
+    Transaction,
+
+    /// Every entry succeeds or fails on its own.
+    Batch,
+}
+
+/// Why an entry of the Bundle failed.
+#[derive(Debug)]
+enum EntryError {
+    /// The entry is invalid.
+    Invalid(String),
+
+    /// The request method of the entry is not supported.
+    NotSupported(String),
+
+    /// Processing the entry raised an error.
+    Failed(String),
+}
+
+impl EntryError {
+    fn diagnostics(&self) -> &str {
+        match self {
+            EntryError::Invalid(diagnostics)
+            | EntryError::NotSupported(diagnostics)
+            | EntryError::Failed(diagnostics) => diagnostics,
+        }
+    }
+
+    /// The `code` of the `OperationOutcome` issue.
+    fn code(&self) -> &'static str {
+        match self {
+            EntryError::Invalid(_) => "invalid",
+            EntryError::NotSupported(_) => "not-supported",
+            EntryError::Failed(_) => "exception",
+        }
+    }
+
+    /// The `response` of the entry in a `batch-response`.
+    fn response(&self) -> Value {
+        let status = match self {
+            EntryError::Invalid(_) | EntryError::NotSupported(_) => "400 Bad Request",
+            EntryError::Failed(_) => "500 Internal Server Error",
+        };
+
+        json!({
+            "status": status,
+            "outcome": error_outcome(self.code(), self.diagnostics()),
+        })
+    }
+}
+
+/// An entry of the Bundle that creates a new entity.
+struct CreateEntry {
+    id: Uuid,
+    resource_type: String,
+    data: Map<String, Value>,
+
+    /// The errors of an entry that is accepted in `warn` mode.
+    issues: Vec<ValidationIssue>,
+}
+
+/// Processes a `transaction` or `batch` Bundle.
+///
+/// Only entries with the `POST` method, which create a new entity, are supported.
+/// `PUT`, `DELETE` and `GET` entries are rejected as `not-supported`. Every created
+/// entity gets a new id, and references to the `fullUrl` of an entry, like
+/// `urn:uuid:<uuid>`, are replaced by references to that id.
+///
+/// Returns a `transaction-response` or `batch-response` Bundle, with the status of
+/// every entry. If a transaction contains an invalid entry, nothing is stored and
+/// an `OperationOutcome` is returned instead. Every entry of a batch is processed
+/// in its own subtransaction, so a failing entry is only reported in its response
+/// entry, and doesn't affect the other entries.
+#[pg_extern]
+#[trace]
+pub fn fhir_transaction(bundle: JsonB) -> Result<JsonB, TransactionError> {
+    if bundle.0.get("resourceType").and_then(Value::as_str) != Some("Bundle") {
+        return Ok(JsonB(error_outcome(
+            "invalid",
+            "the resource is not a Bundle",
+        )));
+    }
+
+    let bundle_type = match bundle.0.get("type").and_then(Value::as_str) {
+        Some("transaction") => BundleType::Transaction,
+        Some("batch") => BundleType::Batch,
+        _ => {
+            return Ok(JsonB(error_outcome(
+                "invalid",
+                "the Bundle type must be 'transaction' or 'batch'",
+            )));
+        }
+    };
+
+    let entries = bundle
+        .0
+        .get("entry")
+        .and_then(Value::as_array)
+        .cloned()
+        .unwrap_or_default();
+
+    let mut references = HashMap::new();
+    let mut parsed = Vec::with_capacity(entries.len());
+    for (i, entry) in entries.iter().enumerate() {
+        let entry = match bundle_type {
+            BundleType::Transaction => parse_entry(entry)?,
+            BundleType::Batch => spi::subtransaction(|| parse_entry(entry))
+                .unwrap_or_else(|error| Err(EntryError::Failed(error))),
+        }
+        .map_err(|error| (i, error));
+
+        if let Ok((entry, Some(full_url))) = &entry {
+            references.insert(
+                full_url.clone(),
+                format!("{}/{}", entry.resource_type, entry.id),
+            );
+        }
+
+        parsed.push(entry.map(|(entry, _)| entry));
+    }
+    let entries = parsed;
+
+    if bundle_type == BundleType::Transaction {
+        if let Some(Err((i, error))) = entries.iter().find(|e| e.is_err()) {
+            return Ok(JsonB(error_outcome(
+                error.code(),
+                &format!("entry {i}: {}", error.diagnostics()),
+            )));
+        }
+    }
+
+    let mut response_entries = Vec::with_capacity(entries.len());
+    for entry in entries {
+        let response = match entry {
+            Ok(mut entry) => {
+                // References between entries are only resolved for transactions,
+                // because the entries of a batch must not depend on each other.
+                if bundle_type == BundleType::Transaction {
+                    for value in entry.data.values_mut() {
+                        resolve_references(value, &references);
+                    }
+                }
+
+                let location = format!("{}/{}", entry.resource_type, entry.id);
+                let created = match bundle_type {
+                    BundleType::Transaction => Ok(store_entry(entry)?),
+                    BundleType::Batch => spi::subtransaction(|| store_entry(entry)),
+                };
+
+                match created {
+                    Ok(()) => json!({
+                        "status": "201 Created",
+                        "location": location,
+                    }),
+                    Err(error) => EntryError::Failed(error).response(),
+                }
+            }
+            Err((_, error)) => error.response(),
+        };
+
+        response_entries.push(json!({ "response": response }));
+    }
+
+    let response_type = match bundle_type {
+        BundleType::Transaction => "transaction-response",
+        BundleType::Batch => "batch-response",
+    };
+
+    Ok(JsonB(json!({
+        "resourceType": "Bundle",
+        "type": response_type,
+        "entry": response_entries,
+    })))
+}
+
+/// Stores the entity of an entry, together with its validation issues.
+fn store_entry(entry: CreateEntry) -> spi::Result<()> {
+    insert_entity(
+        entry.id,
+        &entry.resource_type,
+        JsonB(Value::Object(entry.data)),
+    )?;
+    record_issues(entry.id, &entry.resource_type, &entry.issues)
+}
+
+/// Parses and validates a single entry, and assigns the id of the new entity.
+///
+/// Returns the entry together with its `fullUrl`, or the reason why it is invalid.
+fn parse_entry(entry: &Value) -> spi::Result<Result<(CreateEntry, Option<String>), EntryError>> {
+    let Some(method) = entry.pointer("/request/method").and_then(Value::as_str) else {
+        return Ok(Err(EntryError::Invalid(
+            "the entry has no request method".to_string(),
+        )));
+    };
+    if method != "POST" {
+        return Ok(Err(EntryError::NotSupported(format!(
+            "the request method '{method}' is not supported"
+        ))));
+    }
+    if entry.pointer("/request/ifNoneExist").is_some() {
+        return Ok(Err(EntryError::NotSupported(
+            "conditional creates using 'ifNoneExist' are not supported".to_string(),
+        )));
+    }
+
+    let Some(resource) = entry
+        .get("resource")
+        .filter(|resource| resource.is_object())
+    else {
+        return Ok(Err(EntryError::Invalid(
+            "the entry has no resource".to_string(),
+        )));
+    };
+    let issues = match validate_write(resource)? {
+        WriteValidation::Accepted(issues) => issues,
+        WriteValidation::Rejected(errors) => {
+            return Ok(Err(EntryError::Invalid(format!(
+                "the resource is invalid: {}",
+                first_error(&errors).unwrap_or_default()
+            ))));
+        }
+    };
+
+    let mut data = resource.as_object().cloned().unwrap_or_default();
+    let Some(Value::String(resource_type)) = data.remove("resourceType") else {
+        return Ok(Err(EntryError::Invalid(
+            "the resource does not have a 'resourceType'".to_string(),
+        )));
+    };
+    data.remove("id");
+
+    let full_url = entry
+        .get("fullUrl")
+        .and_then(Value::as_str)
+        .map(ToString::to_string);
+
+    Ok(Ok((
+        CreateEntry {
+            id: fhir_generate_id(),
+            resource_type,
+            data,
+            issues,
+        },
+        full_url,
+    )))
+}
+
+/// Replaces all references to the `fullUrl` of an entry with a reference to its entity.
+fn resolve_references(value: &mut Value, references: &HashMap<String, String>) {
+    match value {
+        Value::Object(obj) => {
+            for (key, value) in obj.iter_mut() {
+                match value {
+                    Value::String(reference) if key == "reference" => {
+                        if let Some(resolved) = references.get(reference.as_str()) {
+                            reference.clone_from(resolved);
+                        }
+                    }
+                    value => resolve_references(value, references),
+                }
+            }
+        }
+        Value::Array(values) => {
+            for value in values {
+                resolve_references(value, references);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Builds an `OperationOutcome` with a single error issue.
+fn error_outcome(code: &str, diagnostics: &str) -> Value {
+    json!({
+        "resourceType": "OperationOutcome",
+        "issue": [{
+            "severity": "error",
+            "code": code,
+            "diagnostics": diagnostics,
+        }],
+    })
+}
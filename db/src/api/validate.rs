@@ -0,0 +1,218 @@
+//! Validation of resources, reported as an [`OperationOutcome`](<https://hl7.org/fhir/operationoutcome.html>).
+
+use fastrace::trace;
+use pgrx::{prelude::*, JsonB, Uuid};
+use serde_json::{json, Value};
+
+use crate::{
+    api::common::find_canonical,
+    fhir::{self, profile, IssueSeverity, ValidationIssue},
+    gucs::{self, ValidationMode},
+    spi,
+};
+
+/// The result of validating a resource before it is stored.
+pub enum WriteValidation {
+    /// The resource can be stored.
+    ///
+    /// In `warn` mode, contains the errors that must be recorded with [`record_issues`].
+    Accepted(Vec<ValidationIssue>),
+
+    /// The resource must not be stored, because of the given errors.
+    Rejected(Vec<ValidationIssue>),
+}
+
+/// Validates `data` as a resource of the given type.
+///
+/// Besides the FHIR schema, `data` is validated against the given `profile`, and
+/// against the profiles in its `meta.profile` if `fhir.validate_profiles` is enabled.
+///
+/// Returns an `OperationOutcome` with one issue per violation, which contains the
+/// JSON pointer to the invalid element as its `location`. If `data` is valid and
+/// there are no warnings, a single `information` issue is returned.
+#[pg_extern]
+#[trace]
+pub fn fhir_validate(
+    resource_type: &str,
+    mut data: JsonB,
+    profile: default!(Option<&str>, "NULL"),
+) -> Result<JsonB, pgrx::spi::Error> {
+    let Some(data_obj) = data.0.as_object_mut() else {
+        return Ok(JsonB(operation_outcome(&[ValidationIssue::error(
+            "structure",
+            "",
+            "the resource is not an object",
+        )])));
+    };
+
+    match data_obj.get("resourceType").and_then(Value::as_str) {
+        Some(ty) if ty == resource_type => {}
+        Some(ty) => {
+            return Ok(JsonB(operation_outcome(&[ValidationIssue::error(
+                "structure",
+                "/resourceType",
+                format!("expected resource type '{resource_type}', got '{ty}'"),
+            )])));
+        }
+        None => {
+            data_obj.insert(
+                "resourceType".to_string(),
+                Value::String(resource_type.to_string()),
+            );
+        }
+    }
+
+    let mut issues = fhir::validate(&data.0);
+    issues.extend(profile_issues(&data.0, profile)?);
+
+    Ok(JsonB(operation_outcome(&issues)))
+}
+
+/// Returns the validation mode of the given resource type, which is either `strict`,
+/// `warn` or `off`.
+///
+/// See `fhir.validation_mode` and `fhir.validation_mode_overrides`.
+#[pg_extern]
+#[trace]
+pub fn fhir_validation_mode(resource_type: &str) -> &'static str {
+    gucs::validation_mode(resource_type).as_str()
+}
+
+/// Validates a resource before it is stored, according to the validation mode of
+/// its type.
+///
+/// The resource is validated against the FHIR schema, the base invariants and its
+/// profiles. In `strict` mode, any error rejects the resource. In `warn` mode,
+/// the resource is always accepted together with all of its errors.
+#[trace]
+pub fn validate_write(data: &Value) -> spi::Result<WriteValidation> {
+    let resource_type = data
+        .get("resourceType")
+        .and_then(Value::as_str)
+        .unwrap_or_default();
+
+    let mode = gucs::validation_mode(resource_type);
+    if mode == ValidationMode::Off {
+        return Ok(WriteValidation::Accepted(Vec::new()));
+    }
+
+    let mut issues = fhir::validate(data);
+    issues.extend(profile_issues(data, None)?);
+    issues.retain(|issue| issue.severity == IssueSeverity::Error);
+
+    Ok(match mode {
+        ValidationMode::Strict if issues.is_empty() => WriteValidation::Accepted(Vec::new()),
+        ValidationMode::Strict => WriteValidation::Rejected(issues),
+        _ => WriteValidation::Accepted(issues),
+    })
+}
+
+/// Stores the errors of an entity that was accepted in `warn` mode in the
+/// `fhir.validation_issue` table, and reports them as a warning.
+#[trace]
+pub fn record_issues(id: Uuid, resource_type: &str, issues: &[ValidationIssue]) -> spi::Result<()> {
+    if issues.is_empty() {
+        return Ok(());
+    }
+
+    warning!(
+        "stored {resource_type}/{id} with {} validation errors, see fhir.validation_issue",
+        issues.len()
+    );
+
+    for issue in issues {
+        spi::run_with_args(
+            r#"
+            INSERT INTO "fhir"."validation_issue" ("entity_id", "entity", "code", "location", "message")
+            VALUES ($1, $2, $3, $4, $5);
+            "#,
+            &[
+                id.into(),
+                resource_type.into(),
+                issue.code.into(),
+                issue.location.as_str().into(),
+                issue.message.as_str().into(),
+            ],
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Validates `data` against the given `profile`, and against the profiles in its
+/// `meta.profile` if `fhir.validate_profiles` is enabled.
+///
+/// Profiles are looked up by their canonical url in the stored `StructureDefinition`s.
+/// Unknown profiles are reported as a warning.
+#[trace]
+pub fn profile_issues(data: &Value, profile: Option<&str>) -> spi::Result<Vec<ValidationIssue>> {
+    let mut profiles = profile.into_iter().collect::<Vec<_>>();
+    if gucs::VALIDATE_PROFILES.get() {
+        profiles.extend(
+            data["meta"]["profile"]
+                .as_array()
+                .into_iter()
+                .flatten()
+                .filter_map(Value::as_str),
+        );
+    }
+
+    let mut issues = Vec::new();
+    for url in profiles {
+        match find_canonical("StructureDefinition", url)? {
+            Some(definition) => issues.extend(profile::validate(&definition, data)),
+            None => issues.push(ValidationIssue::warning(
+                "not-found",
+                "/meta/profile",
+                format!("the profile '{url}' is unknown"),
+            )),
+        }
+    }
+
+    Ok(issues)
+}
+
+/// Returns the first error of `issues`, prefixed with its location when it has one.
+pub fn first_error(issues: &[ValidationIssue]) -> Option<String> {
+    issues
+        .iter()
+        .find(|issue| issue.severity == IssueSeverity::Error)
+        .map(|issue| {
+            if issue.location.is_empty() {
+                issue.message.clone()
+            } else {
+                format!("{}: {}", issue.location, issue.message)
+            }
+        })
+}
+
+/// Builds an `OperationOutcome` from the given validation issues.
+pub fn operation_outcome(issues: &[ValidationIssue]) -> Value {
+    if issues.is_empty() {
+        return json!({
+            "resourceType": "OperationOutcome",
+            "issue": [{
+                "severity": "information",
+                "code": "informational",
+                "diagnostics": "the resource is valid",
+            }],
+        });
+    }
+
+    let issues = issues
+        .iter()
+        .map(|issue| {
+            json!({
+                "severity": issue.severity.as_str(),
+                "code": issue.code,
+                "diagnostics": issue.message,
+                "location": [issue.location],
+            })
+        })
+        .collect::<Vec<_>>();
+
+    json!({
+        "resourceType": "OperationOutcome",
+        "issue": issues,
+    })
+}
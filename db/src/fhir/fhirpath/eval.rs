@@ -0,0 +1,938 @@
+//! Evaluation of `FHIRPath` expressions on JSON resources.
+
+use std::borrow::Cow;
+
+use serde_json::{Number, Value};
+
+use crate::fhir::fhirpath::{BinaryOp, Expr, FhirPathError, TypeOp};
+
+/// A single item of a `FHIRPath` collection.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Node<'a> {
+    pub value: Cow<'a, Value>,
+
+    /// The JSON pointer of the value in the resource, if it is part of the resource.
+    pub location: Option<String>,
+
+    /// The type of a choice element, like `Quantity` for `valueQuantity`.
+    type_name: Option<String>,
+}
+
+impl<'a> Node<'a> {
+    /// An element of the resource at the given location.
+    pub fn element(value: &'a Value, location: impl Into<String>) -> Self {
+        Self {
+            value: Cow::Borrowed(value),
+            location: Some(location.into()),
+            type_name: None,
+        }
+    }
+
+    /// A value that was computed during evaluation.
+    fn computed(value: Value) -> Self {
+        Self {
+            value: Cow::Owned(value),
+            location: None,
+            type_name: None,
+        }
+    }
+
+    /// Returns the child elements with the given name.
+    ///
+    /// If there is no such element, choice elements like `valueQuantity` are returned
+    /// for `value`. Computed values do not have children.
+    fn children_named(&self, name: &str, out: &mut Vec<Node<'a>>) {
+        let Cow::Borrowed(Value::Object(obj)) = self.value else {
+            return;
+        };
+
+        let location = self.location.as_deref().unwrap_or_default();
+        match obj.get_key_value(name) {
+            Some((key, value)) => push_values(value, &format!("{location}/{key}"), None, out),
+            None => {
+                for (key, value) in obj {
+                    let Some(type_name) = key
+                        .strip_prefix(name)
+                        .filter(|ty| ty.starts_with(char::is_uppercase))
+                    else {
+                        continue;
+                    };
+
+                    push_values(value, &format!("{location}/{key}"), Some(type_name), out);
+                }
+            }
+        }
+    }
+
+    /// Returns all child elements.
+    ///
+    /// `resourceType` and the `_` prefixed extensions of primitive elements are skipped.
+    fn children(&self, out: &mut Vec<Node<'a>>) {
+        let Cow::Borrowed(Value::Object(obj)) = self.value else {
+            return;
+        };
+
+        let location = self.location.as_deref().unwrap_or_default();
+        for (key, value) in obj {
+            if key == "resourceType" || key.starts_with('_') {
+                continue;
+            }
+
+            push_values(value, &format!("{location}/{key}"), None, out);
+        }
+    }
+}
+
+/// Pushes the items of `value` if it is an array, or `value` itself.
+fn push_values<'a>(
+    value: &'a Value,
+    location: &str,
+    type_name: Option<&str>,
+    out: &mut Vec<Node<'a>>,
+) {
+    let node = |value, location| Node {
+        value: Cow::Borrowed(value),
+        location: Some(location),
+        type_name: type_name.map(ToString::to_string),
+    };
+
+    match value {
+        Value::Array(values) => out.extend(
+            values
+                .iter()
+                .enumerate()
+                .map(|(i, value)| node(value, format!("{location}/{i}"))),
+        ),
+        value => out.push(node(value, location.to_string())),
+    }
+}
+
+type Collection<'a> = Vec<Node<'a>>;
+
+type Result<T> = std::result::Result<T, FhirPathError>;
+
+/// Evaluates the expression on `context`, which is part of the `resource`.
+///
+/// `%resource` and `%rootResource` refer to the `resource`, and `%context` to the `context`.
+pub fn evaluate<'a>(expr: &Expr, context: Node<'a>, resource: &'a Value) -> Result<Collection<'a>> {
+    let evaluator = Evaluator {
+        resource,
+        context: context.clone(),
+    };
+
+    evaluator.eval(expr, &[context])
+}
+
+/// Evaluates the expression on `context` to a single boolean.
+///
+/// Returns [`None`] if the result is empty.
+pub fn evaluate_boolean<'a>(
+    expr: &Expr,
+    context: Node<'a>,
+    resource: &'a Value,
+) -> Result<Option<bool>> {
+    to_boolean(&evaluate(expr, context, resource)?)
+}
+
+struct Evaluator<'a> {
+    resource: &'a Value,
+    context: Node<'a>,
+}
+
+impl<'a> Evaluator<'a> {
+    fn eval(&self, expr: &Expr, input: &[Node<'a>]) -> Result<Collection<'a>> {
+        match expr {
+            Expr::Empty => Ok(Vec::new()),
+            Expr::Literal(value) => Ok(vec![Node::computed(value.clone())]),
+            Expr::This => Ok(input.to_vec()),
+            Expr::Variable(name) => match name.as_str() {
+                "resource" | "rootResource" => Ok(vec![Node::element(self.resource, "")]),
+                "context" => Ok(vec![self.context.clone()]),
+                "ucum" => Ok(vec![Node::computed(Value::String(
+                    "http://unitsofmeasure.org".to_string(),
+                ))]),
+                "sct" => Ok(vec![Node::computed(Value::String(
+                    "http://snomed.info/sct".to_string(),
+                ))]),
+                "loinc" => Ok(vec![Node::computed(Value::String(
+                    "http://loinc.org".to_string(),
+                ))]),
+                name => Err(FhirPathError::Unsupported(format!("%{name}"))),
+            },
+            Expr::Member(base, name) => {
+                let base = if let Some(base) = base {
+                    self.eval(base, input)?
+                } else {
+                    // A type name at the start of a path, like `Patient.name`, only
+                    // filters the input.
+                    if name.starts_with(char::is_uppercase)
+                        && !input.is_empty()
+                        && input.iter().all(|node| {
+                            node.value.get("resourceType") == Some(&Value::from(name.as_str()))
+                        })
+                    {
+                        return Ok(input.to_vec());
+                    }
+
+                    input.to_vec()
+                };
+
+                let mut out = Vec::new();
+                for node in &base {
+                    node.children_named(name, &mut out);
+                }
+                Ok(out)
+            }
+            Expr::Function(base, name, args) => {
+                let base = match base {
+                    Some(base) => self.eval(base, input)?,
+                    None => input.to_vec(),
+                };
+
+                self.function(name, base, args, input)
+            }
+            Expr::Index(base, index) => {
+                let base = self.eval(base, input)?;
+                let index = self.eval(index, input)?;
+                let Some(index) = single(&index)?.and_then(|index| index.value.as_u64()) else {
+                    return Ok(Vec::new());
+                };
+
+                Ok(usize::try_from(index)
+                    .ok()
+                    .and_then(|index| base.into_iter().nth(index))
+                    .into_iter()
+                    .collect())
+            }
+            Expr::Negate(expr) => {
+                let values = self.eval(expr, input)?;
+                let Some(node) = single(&values)? else {
+                    return Ok(Vec::new());
+                };
+
+                arithmetic(BinaryOp::Sub, &Value::from(0), &node.value)
+                    .map(|value| vec![Node::computed(value)])
+            }
+            Expr::Binary(op, lhs, rhs) => self.binary(*op, lhs, rhs, input),
+            Expr::Type(op, expr, type_name) => {
+                let values = self.eval(expr, input)?;
+                type_op(*op, values, type_name)
+            }
+        }
+    }
+
+    fn binary(
+        &self,
+        op: BinaryOp,
+        lhs: &Expr,
+        rhs: &Expr,
+        input: &[Node<'a>],
+    ) -> Result<Collection<'a>> {
+        let lhs = self.eval(lhs, input)?;
+        let rhs = self.eval(rhs, input)?;
+
+        match op {
+            BinaryOp::And | BinaryOp::Or | BinaryOp::Xor | BinaryOp::Implies => {
+                Ok(boolean(logical(op, to_boolean(&lhs)?, to_boolean(&rhs)?)))
+            }
+            BinaryOp::Eq
+            | BinaryOp::Ne
+            | BinaryOp::Equivalent
+            | BinaryOp::NotEquivalent
+            | BinaryOp::Lt
+            | BinaryOp::Gt
+            | BinaryOp::Le
+            | BinaryOp::Ge
+            | BinaryOp::In
+            | BinaryOp::Contains => Ok(boolean(compare(op, &lhs, &rhs)?)),
+            BinaryOp::Union => Ok(union(lhs, rhs)),
+            BinaryOp::Concat => {
+                let text = |values: &[Node<'_>]| -> Result<String> {
+                    Ok(single(values)?
+                        .and_then(|node| node.value.as_str().map(ToString::to_string))
+                        .unwrap_or_default())
+                };
+
+                Ok(vec![Node::computed(Value::String(
+                    text(&lhs)? + &text(&rhs)?,
+                ))])
+            }
+            BinaryOp::Add
+            | BinaryOp::Sub
+            | BinaryOp::Mul
+            | BinaryOp::Div
+            | BinaryOp::IntDiv
+            | BinaryOp::Mod => {
+                let (Some(lhs), Some(rhs)) = (single(&lhs)?, single(&rhs)?) else {
+                    return Ok(Vec::new());
+                };
+
+                if let (BinaryOp::Add, Value::String(lhs), Value::String(rhs)) =
+                    (op, &*lhs.value, &*rhs.value)
+                {
+                    return Ok(vec![Node::computed(Value::String(format!("{lhs}{rhs}")))]);
+                }
+
+                arithmetic(op, &lhs.value, &rhs.value).map(|value| {
+                    if value.is_null() {
+                        Vec::new()
+                    } else {
+                        vec![Node::computed(value)]
+                    }
+                })
+            }
+        }
+    }
+
+    /// Calls the function `name` on the `base` collection.
+    ///
+    /// `input` is the focus of the whole invocation, which is used for the arguments.
+    fn function(
+        &self,
+        name: &str,
+        base: Collection<'a>,
+        args: &[Expr],
+        input: &[Node<'a>],
+    ) -> Result<Collection<'a>> {
+        let single_bool = |value: bool| Ok(boolean(Some(value)));
+
+        match name {
+            "empty" => single_bool(base.is_empty()),
+            "exists" if args.is_empty() => single_bool(!base.is_empty()),
+            "exists" => single_bool(!self.filter(base, &args[0])?.is_empty()),
+            "not" => Ok(boolean(to_boolean(&base)?.map(|value| !value))),
+            "count" => Ok(vec![Node::computed(Value::from(base.len()))]),
+            "hasValue" => single_bool(
+                base.len() == 1
+                    && !matches!(
+                        *base[0].value,
+                        Value::Object(_) | Value::Array(_) | Value::Null
+                    ),
+            ),
+            "children" => {
+                let mut out = Vec::new();
+                for node in &base {
+                    node.children(&mut out);
+                }
+                Ok(out)
+            }
+            "descendants" => {
+                let mut out = Vec::new();
+                let mut queue = base;
+                while !queue.is_empty() {
+                    let mut children = Vec::new();
+                    for node in &queue {
+                        node.children(&mut children);
+                    }
+                    out.extend(children.iter().cloned());
+                    queue = children;
+                }
+                Ok(out)
+            }
+            "where" => self.filter(base, args.first().ok_or_else(|| missing(name))?),
+            "select" => {
+                let expr = args.first().ok_or_else(|| missing(name))?;
+                let mut out = Vec::new();
+                for node in base {
+                    out.extend(self.eval(expr, &[node])?);
+                }
+                Ok(out)
+            }
+            "all" => {
+                let expr = args.first().ok_or_else(|| missing(name))?;
+                for node in base {
+                    if to_boolean(&self.eval(expr, &[node])?)? != Some(true) {
+                        return single_bool(false);
+                    }
+                }
+                single_bool(true)
+            }
+            "allTrue" => single_bool(base.iter().all(|node| *node.value == Value::Bool(true))),
+            "anyTrue" => single_bool(base.iter().any(|node| *node.value == Value::Bool(true))),
+            "allFalse" => single_bool(base.iter().all(|node| *node.value == Value::Bool(false))),
+            "anyFalse" => single_bool(base.iter().any(|node| *node.value == Value::Bool(false))),
+            "first" => Ok(base.into_iter().take(1).collect()),
+            "last" => Ok(base.into_iter().last().into_iter().collect()),
+            "tail" => Ok(base.into_iter().skip(1).collect()),
+            "single" => Ok(single(&base)?.cloned().into_iter().collect()),
+            "iif" => {
+                let criterion = self.eval(args.first().ok_or_else(|| missing(name))?, &base)?;
+                if to_boolean(&criterion)? == Some(true) {
+                    self.arg(name, args, 1, input)
+                } else if args.len() > 2 {
+                    self.arg(name, args, 2, input)
+                } else {
+                    Ok(Vec::new())
+                }
+            }
+            "trace" => Ok(base),
+            "extension" => {
+                let url = string_value(&self.arg(name, args, 0, input)?)?;
+                let mut extensions = Vec::new();
+                for node in &base {
+                    node.children_named("extension", &mut extensions);
+                }
+                Ok(extensions
+                    .into_iter()
+                    .filter(|node| node.value.get("url").and_then(Value::as_str) == url.as_deref())
+                    .collect())
+            }
+            "ofType" | "is" | "as" => {
+                let Some(Expr::Member(_, type_name)) = args.first() else {
+                    return Err(missing(name));
+                };
+
+                type_function(name, base, type_name)
+            }
+            "distinct" | "isDistinct" | "union" | "combine" | "intersect" | "exclude" => {
+                self.set_function(name, base, args, input)
+            }
+            "startsWith" | "endsWith" | "contains" | "length" | "upper" | "lower" | "substring"
+            | "toString" => self.string_function(name, &base, args, input),
+            name => Err(FhirPathError::Unsupported(format!("the function '{name}'"))),
+        }
+    }
+
+    /// Calls one of the functions that combine collections.
+    fn set_function(
+        &self,
+        name: &str,
+        base: Collection<'a>,
+        args: &[Expr],
+        input: &[Node<'a>],
+    ) -> Result<Collection<'a>> {
+        match name {
+            "distinct" => Ok(union(base, Vec::new())),
+            "isDistinct" => {
+                let count = base.len();
+                Ok(boolean(Some(union(base, Vec::new()).len() == count)))
+            }
+            "union" => Ok(union(base, self.arg(name, args, 0, input)?)),
+            "combine" => {
+                let mut base = base;
+                base.extend(self.arg(name, args, 0, input)?);
+                Ok(base)
+            }
+            _ => {
+                let other = self.arg(name, args, 0, input)?;
+                let keep = name == "intersect";
+                let base = base
+                    .into_iter()
+                    .filter(|node| {
+                        other.iter().any(|o| values_equal(&node.value, &o.value)) == keep
+                    })
+                    .collect();
+
+                Ok(if keep { union(base, Vec::new()) } else { base })
+            }
+        }
+    }
+
+    /// Calls one of the functions that operate on a single string.
+    fn string_function(
+        &self,
+        name: &str,
+        base: &[Node<'a>],
+        args: &[Expr],
+        input: &[Node<'a>],
+    ) -> Result<Collection<'a>> {
+        if name == "toString" {
+            return Ok(single(base)?
+                .map(|node| {
+                    Node::computed(Value::String(match &*node.value {
+                        Value::String(text) => text.clone(),
+                        value => value.to_string(),
+                    }))
+                })
+                .into_iter()
+                .collect());
+        }
+
+        let Some(text) = string_value(base)? else {
+            return Ok(Vec::new());
+        };
+        let index_arg = |i: usize| -> Result<Option<usize>> {
+            Ok(single(&self.arg(name, args, i, input)?)?
+                .and_then(|node| node.value.as_u64())
+                .and_then(|value| usize::try_from(value).ok()))
+        };
+
+        let value = match name {
+            "startsWith" | "endsWith" | "contains" => {
+                let Some(arg) = string_value(&self.arg(name, args, 0, input)?)? else {
+                    return Ok(Vec::new());
+                };
+
+                Value::Bool(match name {
+                    "startsWith" => text.starts_with(&arg),
+                    "endsWith" => text.ends_with(&arg),
+                    _ => text.contains(&arg),
+                })
+            }
+            "length" => Value::from(text.chars().count()),
+            "upper" => Value::String(text.to_uppercase()),
+            "lower" => Value::String(text.to_lowercase()),
+            _ => {
+                let start = index_arg(0)?.unwrap_or_default();
+                let length = if args.len() > 1 { index_arg(1)? } else { None };
+
+                let chars = text.chars().skip(start);
+                Value::String(match length {
+                    Some(length) => chars.take(length).collect(),
+                    None => chars.collect(),
+                })
+            }
+        };
+
+        Ok(vec![Node::computed(value)])
+    }
+
+    /// Evaluates the `i`th argument of the function `name`.
+    fn arg(
+        &self,
+        name: &str,
+        args: &[Expr],
+        i: usize,
+        input: &[Node<'a>],
+    ) -> Result<Collection<'a>> {
+        let expr = args.get(i).ok_or_else(|| missing(name))?;
+        self.eval(expr, input)
+    }
+
+    /// Returns the nodes for which `criteria` evaluates to `true`.
+    fn filter(&self, base: Collection<'a>, criteria: &Expr) -> Result<Collection<'a>> {
+        let mut out = Vec::new();
+        for node in base {
+            if to_boolean(&self.eval(criteria, std::slice::from_ref(&node))?)? == Some(true) {
+                out.push(node);
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Wraps an optional boolean into a collection.
+fn boolean<'a>(value: Option<bool>) -> Collection<'a> {
+    value
+        .map(|value| Node::computed(Value::Bool(value)))
+        .into_iter()
+        .collect()
+}
+
+/// Applies a boolean operator, using three-valued logic for empty operands.
+fn logical(op: BinaryOp, lhs: Option<bool>, rhs: Option<bool>) -> Option<bool> {
+    match op {
+        BinaryOp::And => match (lhs, rhs) {
+            (Some(false), _) | (_, Some(false)) => Some(false),
+            (Some(true), Some(true)) => Some(true),
+            _ => None,
+        },
+        BinaryOp::Or => match (lhs, rhs) {
+            (Some(true), _) | (_, Some(true)) => Some(true),
+            (Some(false), Some(false)) => Some(false),
+            _ => None,
+        },
+        BinaryOp::Xor => lhs.zip(rhs).map(|(lhs, rhs)| lhs != rhs),
+        _ => match (lhs, rhs) {
+            (Some(false), _) | (_, Some(true)) => Some(true),
+            (Some(true), Some(false)) => Some(false),
+            _ => None,
+        },
+    }
+}
+
+/// Applies an equality, comparison or membership operator.
+fn compare(op: BinaryOp, lhs: &[Node<'_>], rhs: &[Node<'_>]) -> Result<Option<bool>> {
+    match op {
+        BinaryOp::Eq | BinaryOp::Ne => {
+            if lhs.is_empty() || rhs.is_empty() {
+                return Ok(None);
+            }
+
+            let equal = lhs.len() == rhs.len()
+                && lhs
+                    .iter()
+                    .zip(rhs)
+                    .all(|(lhs, rhs)| values_equal(&lhs.value, &rhs.value));
+            Ok(Some(equal == (op == BinaryOp::Eq)))
+        }
+        BinaryOp::Equivalent | BinaryOp::NotEquivalent => {
+            let equivalent = lhs.len() == rhs.len()
+                && lhs.iter().all(|lhs| {
+                    rhs.iter()
+                        .any(|rhs| values_equivalent(&lhs.value, &rhs.value))
+                });
+            Ok(Some(equivalent == (op == BinaryOp::Equivalent)))
+        }
+        BinaryOp::In | BinaryOp::Contains => {
+            let (item, collection) = if op == BinaryOp::In {
+                (lhs, rhs)
+            } else {
+                (rhs, lhs)
+            };
+
+            Ok(single(item)?.map(|item| {
+                collection
+                    .iter()
+                    .any(|node| values_equal(&node.value, &item.value))
+            }))
+        }
+        _ => {
+            let (Some(lhs), Some(rhs)) = (single(lhs)?, single(rhs)?) else {
+                return Ok(None);
+            };
+
+            let ordering = match (&*lhs.value, &*rhs.value) {
+                (Value::Number(lhs), Value::Number(rhs)) => as_f64(lhs).partial_cmp(&as_f64(rhs)),
+                (Value::String(lhs), Value::String(rhs)) => Some(lhs.cmp(rhs)),
+                _ => None,
+            };
+            let Some(ordering) = ordering else {
+                return Err(FhirPathError::Evaluation(
+                    "the values can not be compared".to_string(),
+                ));
+            };
+
+            Ok(Some(match op {
+                BinaryOp::Lt => ordering.is_lt(),
+                BinaryOp::Gt => ordering.is_gt(),
+                BinaryOp::Le => ordering.is_le(),
+                _ => ordering.is_ge(),
+            }))
+        }
+    }
+}
+
+fn missing(function: &str) -> FhirPathError {
+    FhirPathError::Evaluation(format!("missing argument for '{function}'"))
+}
+
+/// Returns the single item of the collection, or [`None`] if it is empty.
+fn single<'n, 'a>(values: &'n [Node<'a>]) -> Result<Option<&'n Node<'a>>> {
+    match values {
+        [] => Ok(None),
+        [node] => Ok(Some(node)),
+        _ => Err(FhirPathError::Evaluation(
+            "expected a single value, but got a collection".to_string(),
+        )),
+    }
+}
+
+/// Returns the single string of the collection.
+fn string_value(values: &[Node<'_>]) -> Result<Option<String>> {
+    Ok(single(values)?.and_then(|node| node.value.as_str().map(ToString::to_string)))
+}
+
+/// Converts a collection to a boolean, where a single non-boolean value is `true`.
+fn to_boolean(values: &[Node<'_>]) -> Result<Option<bool>> {
+    Ok(single(values)?.map(|node| node.value.as_bool().unwrap_or(true)))
+}
+
+/// Merges both collections, removing duplicates.
+///
+/// Elements of the resource are only duplicates if they are at the same location.
+fn union<'a>(lhs: Collection<'a>, rhs: Collection<'a>) -> Collection<'a> {
+    let mut out: Collection<'a> = Vec::new();
+    for node in lhs.into_iter().chain(rhs) {
+        let duplicate = out
+            .iter()
+            .any(|other| match (&node.location, &other.location) {
+                (Some(location), Some(other)) => location == other,
+                _ => values_equal(&node.value, &other.value),
+            });
+        if !duplicate {
+            out.push(node);
+        }
+    }
+
+    out
+}
+
+fn as_f64(number: &Number) -> f64 {
+    number.as_f64().unwrap_or(f64::NAN)
+}
+
+fn values_equal(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (Value::Number(lhs), Value::Number(rhs)) => as_f64(lhs).total_cmp(&as_f64(rhs)).is_eq(),
+        (Value::Array(lhs), Value::Array(rhs)) => {
+            lhs.len() == rhs.len() && lhs.iter().zip(rhs).all(|(lhs, rhs)| values_equal(lhs, rhs))
+        }
+        (Value::Object(lhs), Value::Object(rhs)) => {
+            lhs.len() == rhs.len()
+                && lhs
+                    .iter()
+                    .all(|(key, lhs)| rhs.get(key).is_some_and(|rhs| values_equal(lhs, rhs)))
+        }
+        (lhs, rhs) => lhs == rhs,
+    }
+}
+
+/// Equality, which ignores the case and surrounding whitespace of strings.
+fn values_equivalent(lhs: &Value, rhs: &Value) -> bool {
+    match (lhs, rhs) {
+        (Value::String(lhs), Value::String(rhs)) => lhs.trim().eq_ignore_ascii_case(rhs.trim()),
+        (lhs, rhs) => values_equal(lhs, rhs),
+    }
+}
+
+/// Applies an arithmetic operator, returning `null` for a division by zero.
+fn arithmetic(op: BinaryOp, lhs: &Value, rhs: &Value) -> Result<Value> {
+    let (Value::Number(lhs), Value::Number(rhs)) = (lhs, rhs) else {
+        return Err(FhirPathError::Evaluation(
+            "arithmetic is only supported on numbers".to_string(),
+        ));
+    };
+
+    if let (Some(lhs), Some(rhs)) = (lhs.as_i64(), rhs.as_i64()) {
+        let result = match op {
+            BinaryOp::Add => lhs.checked_add(rhs),
+            BinaryOp::Sub => lhs.checked_sub(rhs),
+            BinaryOp::Mul => lhs.checked_mul(rhs),
+            BinaryOp::IntDiv => lhs.checked_div(rhs),
+            BinaryOp::Mod => lhs.checked_rem(rhs),
+            _ => None,
+        };
+        if let Some(result) = result {
+            return Ok(Value::from(result));
+        }
+        if matches!(op, BinaryOp::IntDiv | BinaryOp::Mod) {
+            return Ok(Value::Null);
+        }
+    }
+
+    let (lhs, rhs) = (as_f64(lhs), as_f64(rhs));
+    let result = match op {
+        BinaryOp::Add => lhs + rhs,
+        BinaryOp::Sub => lhs - rhs,
+        BinaryOp::Mul => lhs * rhs,
+        BinaryOp::Div => lhs / rhs,
+        BinaryOp::IntDiv => (lhs / rhs).trunc(),
+        _ => lhs % rhs,
+    };
+
+    Ok(Number::from_f64(result).map_or(Value::Null, Value::Number))
+}
+
+/// Calls `ofType`, `is` or `as` with the given type.
+fn type_function<'a>(name: &str, base: Collection<'a>, type_name: &str) -> Result<Collection<'a>> {
+    match name {
+        "ofType" => {
+            let mut out = Vec::new();
+            for node in base {
+                out.extend(type_op(TypeOp::As, vec![node], type_name)?);
+            }
+            Ok(out)
+        }
+        "is" => type_op(TypeOp::Is, base, type_name),
+        _ => type_op(TypeOp::As, base, type_name),
+    }
+}
+
+/// Applies `is` or `as` to the collection.
+fn type_op<'a>(op: TypeOp, values: Collection<'a>, type_name: &str) -> Result<Collection<'a>> {
+    let Some(node) = single(&values)? else {
+        return Ok(Vec::new());
+    };
+
+    let matches = is_type(node, type_name)?;
+    Ok(match op {
+        TypeOp::Is => vec![Node::computed(Value::Bool(matches))],
+        TypeOp::As if matches => values,
+        TypeOp::As => Vec::new(),
+    })
+}
+
+/// Checks if the node has the given type.
+///
+/// The type is only known for resources, choice elements and primitive values.
+fn is_type(node: &Node<'_>, type_name: &str) -> Result<bool> {
+    if let Some(own_type) = &node.type_name {
+        return Ok(own_type.eq_ignore_ascii_case(type_name));
+    }
+
+    match &*node.value {
+        Value::Bool(_) => Ok(type_name.eq_ignore_ascii_case("boolean")),
+        Value::Number(_) => Ok(matches!(
+            type_name,
+            "decimal"
+                | "integer"
+                | "positiveInt"
+                | "unsignedInt"
+                | "integer64"
+                | "Decimal"
+                | "Integer"
+        )),
+        Value::String(_) => Ok(matches!(
+            type_name,
+            "string"
+                | "code"
+                | "id"
+                | "uri"
+                | "url"
+                | "canonical"
+                | "oid"
+                | "uuid"
+                | "markdown"
+                | "base64Binary"
+                | "date"
+                | "dateTime"
+                | "instant"
+                | "time"
+                | "xhtml"
+                | "String"
+        )),
+        Value::Object(obj) => match obj.get("resourceType").and_then(Value::as_str) {
+            Some(resource_type) => Ok(resource_type == type_name
+                || type_name == "Resource"
+                || type_name == "DomainResource"),
+            None => Err(FhirPathError::Unsupported(format!(
+                "checking the type '{type_name}' of a complex element"
+            ))),
+        },
+        Value::Array(_) | Value::Null => Ok(false),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::fhir::fhirpath::parse;
+
+    /// Evaluates the expression on the resource, returning the values and their locations.
+    fn eval(expression: &str, resource: &Value) -> Vec<(Value, Option<String>)> {
+        let expr = parse(expression).unwrap();
+        evaluate(&expr, Node::element(resource, ""), resource)
+            .unwrap()
+            .into_iter()
+            .map(|node| (node.value.into_owned(), node.location))
+            .collect()
+    }
+
+    fn eval_boolean(expression: &str) -> Option<bool> {
+        let resource = json!({ "resourceType": "Patient" });
+        let expr = parse(expression).unwrap();
+        evaluate_boolean(&expr, Node::element(&resource, ""), &resource).unwrap()
+    }
+
+    fn values(expression: &str) -> Vec<Value> {
+        eval(expression, &json!({ "resourceType": "Patient" }))
+            .into_iter()
+            .map(|(value, _)| value)
+            .collect()
+    }
+
+    #[test]
+    fn three_valued_and() {
+        assert_eq!(eval_boolean("true and true"), Some(true));
+        assert_eq!(eval_boolean("true and false"), Some(false));
+        assert_eq!(eval_boolean("{} and false"), Some(false));
+        assert_eq!(eval_boolean("false and {}"), Some(false));
+        assert_eq!(eval_boolean("{} and true"), None);
+        assert_eq!(eval_boolean("{} and {}"), None);
+    }
+
+    #[test]
+    fn three_valued_or() {
+        assert_eq!(eval_boolean("false or false"), Some(false));
+        assert_eq!(eval_boolean("false or true"), Some(true));
+        assert_eq!(eval_boolean("{} or true"), Some(true));
+        assert_eq!(eval_boolean("true or {}"), Some(true));
+        assert_eq!(eval_boolean("{} or false"), None);
+        assert_eq!(eval_boolean("{} or {}"), None);
+    }
+
+    #[test]
+    fn three_valued_implies() {
+        assert_eq!(eval_boolean("true implies false"), Some(false));
+        assert_eq!(eval_boolean("true implies true"), Some(true));
+        assert_eq!(eval_boolean("false implies {}"), Some(true));
+        assert_eq!(eval_boolean("{} implies true"), Some(true));
+        assert_eq!(eval_boolean("true implies {}"), None);
+        assert_eq!(eval_boolean("{} implies false"), None);
+    }
+
+    #[test]
+    fn xor_and_not() {
+        assert_eq!(eval_boolean("true xor false"), Some(true));
+        assert_eq!(eval_boolean("true xor true"), Some(false));
+        assert_eq!(eval_boolean("{} xor true"), None);
+        assert_eq!(eval_boolean("false.not()"), Some(true));
+        assert_eq!(eval_boolean("{}.not()"), None);
+    }
+
+    #[test]
+    fn descendants() {
+        let patient = json!({
+            "resourceType": "Patient",
+            "name": [{ "family": "Curie", "given": ["Marie", "Salomea"] }],
+            "_gender": { "extension": [] },
+        });
+
+        let descendants = eval("descendants()", &patient);
+        let locations = descendants
+            .iter()
+            .map(|(_, location)| location.as_deref().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            locations,
+            [
+                "/name/0",
+                "/name/0/family",
+                "/name/0/given/0",
+                "/name/0/given/1",
+            ]
+        );
+
+        assert_eq!(
+            eval("descendants().where($this = 'Salomea')", &patient),
+            [(json!("Salomea"), Some("/name/0/given/1".to_string()))]
+        );
+    }
+
+    #[test]
+    fn choice_elements() {
+        let observation = json!({
+            "resourceType": "Observation",
+            "valueQuantity": { "value": 5, "unit": "mg" },
+        });
+
+        assert_eq!(
+            eval("value.value", &observation),
+            [(json!(5), Some("/valueQuantity/value".to_string()))]
+        );
+        assert_eq!(eval("value is Quantity", &observation)[0].0, json!(true));
+        assert_eq!(eval("value is string", &observation)[0].0, json!(false));
+        assert_eq!(
+            eval("value.ofType(Quantity).unit", &observation)[0].0,
+            json!("mg")
+        );
+        assert!(eval("value.ofType(string)", &observation).is_empty());
+
+        // An element that merely starts with the same name is not a choice type.
+        let observation = json!({ "resourceType": "Observation", "valueset": "x" });
+        assert!(eval("value", &observation).is_empty());
+    }
+
+    #[test]
+    fn arithmetic() {
+        assert_eq!(values("1 + 2 * 3"), [json!(7)]);
+        assert_eq!(values("7 div 2"), [json!(3)]);
+        assert_eq!(values("7 mod 2"), [json!(1)]);
+        assert_eq!(values("1 / 2"), [json!(0.5)]);
+        assert_eq!(values("'a' + 'b'"), [json!("ab")]);
+        assert_eq!(values("1 + {}"), Vec::<Value>::new());
+    }
+
+    #[test]
+    fn division_by_zero_is_empty() {
+        assert_eq!(values("1 / 0"), Vec::<Value>::new());
+        assert_eq!(values("1.5 / 0"), Vec::<Value>::new());
+        assert_eq!(values("1 div 0"), Vec::<Value>::new());
+        assert_eq!(values("1 mod 0"), Vec::<Value>::new());
+        assert_eq!(eval_boolean("(1 / 0).empty()"), Some(true));
+    }
+}
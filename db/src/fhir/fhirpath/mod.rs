@@ -0,0 +1,94 @@
+//! A subset of [FHIRPath](<https://hl7.org/fhirpath/>), which is used to check the
+//! invariants of resources.
+//!
+//! Supports path navigation (including choice elements like `value`), all operators
+//! except date arithmetic, and the functions that are commonly used by invariants.
+//! Expressions using anything else fail with [`FhirPathError::Unsupported`].
+
+use thiserror::Error;
+
+mod eval;
+mod parser;
+
+pub use eval::{evaluate, evaluate_boolean, Node};
+pub use parser::parse;
+
+/// Errors that can occurr while parsing or evaluating an expression.
+#[derive(Debug, Error)]
+pub enum FhirPathError {
+    #[error("invalid FHIRPath expression: {0}")]
+    Parse(String),
+
+    #[error("unsupported FHIRPath feature: {0}")]
+    Unsupported(String),
+
+    #[error("failed to evaluate FHIRPath expression: {0}")]
+    Evaluation(String),
+}
+
+/// A parsed `FHIRPath` expression.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// The empty collection `{}`.
+    Empty,
+
+    /// A string, number or boolean literal.
+    Literal(serde_json::Value),
+
+    /// `$this`, the item that is currently evaluated.
+    This,
+
+    /// An environment variable, like `%resource`.
+    Variable(String),
+
+    /// Navigation to the child elements with the given name.
+    ///
+    /// Without a base, the children of the current input are used.
+    Member(Option<Box<Expr>>, String),
+
+    /// A function call, like `exists()`.
+    Function(Option<Box<Expr>>, String, Vec<Expr>),
+
+    /// Indexing into a collection, like `name[0]`.
+    Index(Box<Expr>, Box<Expr>),
+
+    /// `-` applied to a number.
+    Negate(Box<Expr>),
+
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+
+    /// `is` or `as` with the name of a type.
+    Type(TypeOp, Box<Expr>, String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Mul,
+    Div,
+    IntDiv,
+    Mod,
+    Add,
+    Sub,
+    Concat,
+    Union,
+    Lt,
+    Gt,
+    Le,
+    Ge,
+    Eq,
+    Ne,
+    Equivalent,
+    NotEquivalent,
+    In,
+    Contains,
+    And,
+    Or,
+    Xor,
+    Implies,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TypeOp {
+    Is,
+    As,
+}
@@ -0,0 +1,483 @@
+//! Tokenizing and parsing of `FHIRPath` expressions.
+
+use serde_json::{Number, Value};
+
+use crate::fhir::fhirpath::{BinaryOp, Expr, FhirPathError, TypeOp};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    /// An identifier, which may also be a keyword like `and`.
+    Ident(String),
+
+    /// An identifier delimited by backticks, which is never a keyword.
+    Delimited(String),
+
+    String(String),
+    Number(String),
+
+    /// An environment variable, like `%resource`.
+    Variable(String),
+
+    /// A special variable, like `$this`.
+    Special(String),
+
+    Symbol(&'static str),
+}
+
+const SYMBOLS: &[&str] = &[
+    "!=", "!~", "<=", ">=", ".", ",", "(", ")", "[", "]", "{", "}", "+", "-", "*", "/", "&", "|",
+    "=", "~", "<", ">",
+];
+
+/// Parses a `FHIRPath` expression.
+pub fn parse(expression: &str) -> Result<Expr, FhirPathError> {
+    let mut parser = Parser {
+        tokens: tokenize(expression)?,
+        pos: 0,
+    };
+
+    let expr = parser.expression(0)?;
+    if let Some(token) = parser.peek() {
+        return Err(FhirPathError::Parse(format!("unexpected token {token:?}")));
+    }
+
+    Ok(expr)
+}
+
+fn tokenize(input: &str) -> Result<Vec<Token>, FhirPathError> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some(&(start, c)) = chars.peek() {
+        let rest = &input[start..];
+
+        if c.is_whitespace() {
+            chars.next();
+        } else if rest.starts_with("//") {
+            while chars.next_if(|&(_, c)| c != '\n').is_some() {}
+        } else if rest.starts_with("/*") {
+            let end = rest
+                .find("*/")
+                .ok_or_else(|| FhirPathError::Parse("unterminated comment".to_string()))?;
+            while chars.next_if(|&(i, _)| i < start + end + 2).is_some() {}
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let mut ident = String::new();
+            while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_')
+            {
+                ident.push(c);
+            }
+            tokens.push(Token::Ident(ident));
+        } else if c.is_ascii_digit() {
+            let mut number = String::new();
+            while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_digit()) {
+                number.push(c);
+            }
+
+            // Only consume the dot, if it is followed by a digit, so `1.exists()` still works.
+            let mut lookahead = chars.clone();
+            if lookahead.next().is_some_and(|(_, c)| c == '.')
+                && lookahead.next().is_some_and(|(_, c)| c.is_ascii_digit())
+            {
+                chars.next();
+                number.push('.');
+                while let Some((_, c)) = chars.next_if(|&(_, c)| c.is_ascii_digit()) {
+                    number.push(c);
+                }
+            }
+            tokens.push(Token::Number(number));
+        } else if c == '\'' || c == '`' {
+            chars.next();
+            let text = delimited(&mut chars, c)?;
+            tokens.push(if c == '\'' {
+                Token::String(text)
+            } else {
+                Token::Delimited(text)
+            });
+        } else if c == '%' || c == '$' {
+            chars.next();
+            let name = if let Some((_, quote)) = chars.next_if(|&(_, c)| c == '`' || c == '\'') {
+                delimited(&mut chars, quote)?
+            } else {
+                let mut name = String::new();
+                while let Some((_, c)) =
+                    chars.next_if(|&(_, c)| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+                {
+                    name.push(c);
+                }
+                name
+            };
+            tokens.push(if c == '%' {
+                Token::Variable(name)
+            } else {
+                Token::Special(name)
+            });
+        } else if c == '@' {
+            return Err(FhirPathError::Unsupported(
+                "date and time literals".to_string(),
+            ));
+        } else if let Some(symbol) = SYMBOLS.iter().find(|symbol| rest.starts_with(**symbol)) {
+            for _ in 0..symbol.len() {
+                chars.next();
+            }
+            tokens.push(Token::Symbol(symbol));
+        } else {
+            return Err(FhirPathError::Parse(format!("unexpected character '{c}'")));
+        }
+    }
+
+    Ok(tokens)
+}
+
+/// Reads a string or identifier up to the closing `quote`, resolving escapes.
+fn delimited(
+    chars: &mut std::iter::Peekable<std::str::CharIndices<'_>>,
+    quote: char,
+) -> Result<String, FhirPathError> {
+    let mut text = String::new();
+
+    loop {
+        match chars.next() {
+            Some((_, c)) if c == quote => return Ok(text),
+            Some((_, '\\')) => match chars.next() {
+                Some((_, 'n')) => text.push('\n'),
+                Some((_, 'r')) => text.push('\r'),
+                Some((_, 't')) => text.push('\t'),
+                Some((_, 'f')) => text.push('\u{c}'),
+                Some((_, 'u')) => {
+                    let code = (0..4)
+                        .filter_map(|_| chars.next().map(|(_, c)| c))
+                        .collect::<String>();
+                    let c = u32::from_str_radix(&code, 16)
+                        .ok()
+                        .and_then(char::from_u32)
+                        .ok_or_else(|| {
+                            FhirPathError::Parse(format!("invalid escape '\\u{code}'"))
+                        })?;
+                    text.push(c);
+                }
+                Some((_, c)) => text.push(c),
+                None => break,
+            },
+            Some((_, c)) => text.push(c),
+            None => break,
+        }
+    }
+
+    Err(FhirPathError::Parse(format!("missing closing {quote}")))
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat(&mut self, symbol: &str) -> bool {
+        if matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, symbol: &str) -> Result<(), FhirPathError> {
+        if self.eat(symbol) {
+            Ok(())
+        } else {
+            Err(FhirPathError::Parse(format!(
+                "expected '{symbol}', got {:?}",
+                self.peek()
+            )))
+        }
+    }
+
+    /// Parses binary operators with at least the given precedence.
+    fn expression(&mut self, min_precedence: u8) -> Result<Expr, FhirPathError> {
+        let mut lhs = self.unary()?;
+
+        while let Some((op, precedence)) = self.peek().and_then(binary_op) {
+            if precedence < min_precedence {
+                break;
+            }
+            self.pos += 1;
+
+            lhs = match op {
+                Operator::Type(op) => Expr::Type(op, Box::new(lhs), self.type_specifier()?),
+                Operator::Binary(op) => {
+                    let rhs = self.expression(precedence + 1)?;
+                    Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+                }
+            };
+        }
+
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, FhirPathError> {
+        if self.eat("-") {
+            return Ok(Expr::Negate(Box::new(self.unary()?)));
+        }
+        if self.eat("+") {
+            return self.unary();
+        }
+
+        let mut expr = self.term()?;
+        loop {
+            if self.eat(".") {
+                let name = self.identifier()?;
+                expr = self.invocation(Some(expr), name)?;
+            } else if self.eat("[") {
+                let index = self.expression(0)?;
+                self.expect("]")?;
+                expr = Expr::Index(Box::new(expr), Box::new(index));
+            } else {
+                return Ok(expr);
+            }
+        }
+    }
+
+    fn term(&mut self) -> Result<Expr, FhirPathError> {
+        match self.next() {
+            Some(Token::String(text)) => Ok(Expr::Literal(Value::String(text))),
+            Some(Token::Number(number)) => {
+                let number = number
+                    .parse::<i64>()
+                    .map(Number::from)
+                    .ok()
+                    .or_else(|| number.parse::<f64>().ok().and_then(Number::from_f64))
+                    .ok_or_else(|| FhirPathError::Parse(format!("invalid number '{number}'")))?;
+                Ok(Expr::Literal(Value::Number(number)))
+            }
+            Some(Token::Ident(ident)) if ident == "true" || ident == "false" => {
+                Ok(Expr::Literal(Value::Bool(ident == "true")))
+            }
+            Some(Token::Ident(name) | Token::Delimited(name)) => self.invocation(None, name),
+            Some(Token::Variable(name)) => Ok(Expr::Variable(name)),
+            Some(Token::Special(name)) if name == "this" => Ok(Expr::This),
+            Some(Token::Special(name)) => Err(FhirPathError::Unsupported(format!("${name}"))),
+            Some(Token::Symbol("(")) => {
+                let expr = self.expression(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Symbol("{")) => {
+                self.expect("}")?;
+                Ok(Expr::Empty)
+            }
+            token => Err(FhirPathError::Parse(format!("unexpected token {token:?}"))),
+        }
+    }
+
+    /// Parses a member access or function call on `base`.
+    fn invocation(&mut self, base: Option<Expr>, name: String) -> Result<Expr, FhirPathError> {
+        let base = base.map(Box::new);
+        if !self.eat("(") {
+            return Ok(Expr::Member(base, name));
+        }
+
+        let mut args = Vec::new();
+        if !self.eat(")") {
+            loop {
+                args.push(self.expression(0)?);
+                if self.eat(")") {
+                    break;
+                }
+                self.expect(",")?;
+            }
+        }
+
+        Ok(Expr::Function(base, name, args))
+    }
+
+    fn identifier(&mut self) -> Result<String, FhirPathError> {
+        match self.next() {
+            Some(Token::Ident(name) | Token::Delimited(name)) => Ok(name),
+            token => Err(FhirPathError::Parse(format!(
+                "expected an identifier, got {token:?}"
+            ))),
+        }
+    }
+
+    /// Parses a possibly qualified type name, like `FHIR.Quantity`.
+    fn type_specifier(&mut self) -> Result<String, FhirPathError> {
+        let mut name = self.identifier()?;
+        while self.eat(".") {
+            name = self.identifier()?;
+        }
+
+        Ok(name)
+    }
+}
+
+enum Operator {
+    Binary(BinaryOp),
+    Type(TypeOp),
+}
+
+/// Returns the operator of the token and its precedence, where higher binds tighter.
+fn binary_op(token: &Token) -> Option<(Operator, u8)> {
+    let (op, precedence) = match token {
+        Token::Symbol("*") => (BinaryOp::Mul, 10),
+        Token::Symbol("/") => (BinaryOp::Div, 10),
+        Token::Ident(ident) if ident == "div" => (BinaryOp::IntDiv, 10),
+        Token::Ident(ident) if ident == "mod" => (BinaryOp::Mod, 10),
+        Token::Symbol("+") => (BinaryOp::Add, 9),
+        Token::Symbol("-") => (BinaryOp::Sub, 9),
+        Token::Symbol("&") => (BinaryOp::Concat, 9),
+        Token::Ident(ident) if ident == "is" => return Some((Operator::Type(TypeOp::Is), 8)),
+        Token::Ident(ident) if ident == "as" => return Some((Operator::Type(TypeOp::As), 8)),
+        Token::Symbol("|") => (BinaryOp::Union, 7),
+        Token::Symbol("<") => (BinaryOp::Lt, 6),
+        Token::Symbol(">") => (BinaryOp::Gt, 6),
+        Token::Symbol("<=") => (BinaryOp::Le, 6),
+        Token::Symbol(">=") => (BinaryOp::Ge, 6),
+        Token::Symbol("=") => (BinaryOp::Eq, 5),
+        Token::Symbol("!=") => (BinaryOp::Ne, 5),
+        Token::Symbol("~") => (BinaryOp::Equivalent, 5),
+        Token::Symbol("!~") => (BinaryOp::NotEquivalent, 5),
+        Token::Ident(ident) if ident == "in" => (BinaryOp::In, 4),
+        Token::Ident(ident) if ident == "contains" => (BinaryOp::Contains, 4),
+        Token::Ident(ident) if ident == "and" => (BinaryOp::And, 3),
+        Token::Ident(ident) if ident == "or" => (BinaryOp::Or, 2),
+        Token::Ident(ident) if ident == "xor" => (BinaryOp::Xor, 2),
+        Token::Ident(ident) if ident == "implies" => (BinaryOp::Implies, 1),
+        _ => return None,
+    };
+
+    Some((Operator::Binary(op), precedence))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn member(name: &str) -> Expr {
+        Expr::Member(None, name.to_string())
+    }
+
+    fn literal(value: Value) -> Expr {
+        Expr::Literal(value)
+    }
+
+    fn binary(op: BinaryOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Binary(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    #[test]
+    fn arithmetic_precedence() {
+        assert_eq!(
+            parse("1 + 2 * 3").unwrap(),
+            binary(
+                BinaryOp::Add,
+                literal(json!(1)),
+                binary(BinaryOp::Mul, literal(json!(2)), literal(json!(3))),
+            )
+        );
+        assert_eq!(
+            parse("(1 + 2) * 3").unwrap(),
+            binary(
+                BinaryOp::Mul,
+                binary(BinaryOp::Add, literal(json!(1)), literal(json!(2))),
+                literal(json!(3)),
+            )
+        );
+    }
+
+    #[test]
+    fn operators_are_left_associative() {
+        assert_eq!(
+            parse("8 - 4 - 2").unwrap(),
+            binary(
+                BinaryOp::Sub,
+                binary(BinaryOp::Sub, literal(json!(8)), literal(json!(4))),
+                literal(json!(2)),
+            )
+        );
+    }
+
+    #[test]
+    fn logical_precedence() {
+        // `and` binds tighter than `or`, which binds tighter than `implies`.
+        assert_eq!(
+            parse("a implies b or c and d").unwrap(),
+            binary(
+                BinaryOp::Implies,
+                member("a"),
+                binary(
+                    BinaryOp::Or,
+                    member("b"),
+                    binary(BinaryOp::And, member("c"), member("d")),
+                ),
+            )
+        );
+
+        // Comparisons bind tighter than `and`.
+        assert_eq!(
+            parse("a = 1 and b").unwrap(),
+            binary(
+                BinaryOp::And,
+                binary(BinaryOp::Eq, member("a"), literal(json!(1))),
+                member("b"),
+            )
+        );
+    }
+
+    #[test]
+    fn invocations() {
+        assert_eq!(
+            parse("name.given.exists()").unwrap(),
+            Expr::Function(
+                Some(Box::new(Expr::Member(
+                    Some(Box::new(member("name"))),
+                    "given".to_string()
+                ))),
+                "exists".to_string(),
+                Vec::new(),
+            )
+        );
+        assert_eq!(
+            parse("name[0] is HumanName").unwrap(),
+            Expr::Type(
+                TypeOp::Is,
+                Box::new(Expr::Index(
+                    Box::new(member("name")),
+                    Box::new(literal(json!(0)))
+                )),
+                "HumanName".to_string(),
+            )
+        );
+        assert_eq!(
+            parse("-1.5").unwrap(),
+            Expr::Negate(Box::new(literal(json!(1.5))))
+        );
+    }
+
+    #[test]
+    fn keywords_can_be_delimited() {
+        assert_eq!(parse("`div`").unwrap(), member("div"));
+    }
+
+    #[test]
+    fn errors() {
+        assert!(matches!(parse("1 +"), Err(FhirPathError::Parse(_))));
+        assert!(matches!(parse("a b"), Err(FhirPathError::Parse(_))));
+        assert!(matches!(parse("'open"), Err(FhirPathError::Parse(_))));
+        assert!(matches!(
+            parse("birthDate < @2000-01-01"),
+            Err(FhirPathError::Unsupported(_))
+        ));
+    }
+}
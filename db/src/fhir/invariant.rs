@@ -0,0 +1,166 @@
+//! Checking of the invariants (constraints) of the base resources.
+//!
+//! The JSON schema can not express invariants, so their `FHIRPath` expressions are
+//! taken from the `StructureDefinition`s of the selected [`FhirVersion`], see
+//! `scripts/generate-definitions.py`. Invariants that can not be parsed or evaluated
+//! are skipped.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::fhir::{
+    fhirpath::{self, Expr, Node},
+    version::FhirVersion,
+    with_definitions, IssueSeverity, ValidationIssue,
+};
+
+/// Resource types that are not a `DomainResource`.
+const NON_DOMAIN_RESOURCES: &[&str] = &["Binary", "Bundle", "Parameters"];
+
+/// An invariant of the bundled definitions.
+///
+/// Every invariant contains a `FHIRPath` `context`, which selects the elements it
+/// applies to, starting at the resource.
+#[derive(Debug, Deserialize)]
+struct RawInvariant {
+    key: String,
+    severity: String,
+    human: String,
+    context: String,
+    expression: String,
+}
+
+/// A parsed invariant.
+#[derive(Debug)]
+pub struct Invariant {
+    key: String,
+    severity: IssueSeverity,
+    human: String,
+    context: Expr,
+    expression: Expr,
+}
+
+/// Checks the base invariants of the given resource.
+pub fn validate(resource: &Value) -> Vec<ValidationIssue> {
+    let Some(resource_type) = resource.get("resourceType").and_then(Value::as_str) else {
+        return Vec::new();
+    };
+
+    with_invariants(|invariants| {
+        let mut groups = vec!["Element", "Extension", resource_type];
+        if !NON_DOMAIN_RESOURCES.contains(&resource_type) {
+            groups.push("DomainResource");
+        }
+
+        groups
+            .into_iter()
+            .filter_map(|group| invariants.get(group))
+            .flatten()
+            .flat_map(|invariant| check(invariant, resource))
+            .collect()
+    })
+}
+
+/// Checks if the invariant with the given key is part of the base invariants.
+///
+/// These invariants are checked for every resource, so they are skipped when
+/// validating profiles.
+pub fn is_base_invariant(key: &str) -> bool {
+    with_invariants(|invariants| {
+        invariants
+            .values()
+            .flatten()
+            .any(|invariant| invariant.key == key)
+    })
+}
+
+/// Evaluates a `FHIRPath` `expression` on `context` and reports an issue if it is not
+/// `true`.
+///
+/// Returns [`None`] if the expression is satisfied, or can not be evaluated.
+pub fn check_constraint(
+    key: &str,
+    severity: IssueSeverity,
+    human: &str,
+    expression: &Expr,
+    context: Node<'_>,
+    resource: &Value,
+) -> Option<ValidationIssue> {
+    let location = context.location.clone().unwrap_or_default();
+    let satisfied = fhirpath::evaluate_boolean(expression, context, resource).ok()?;
+
+    // An empty result does not violate the invariant.
+    if satisfied != Some(false) {
+        return None;
+    }
+
+    let message = format!("{key}: {human}");
+    Some(match severity {
+        IssueSeverity::Error => ValidationIssue::error("invariant", location, message),
+        IssueSeverity::Warning => ValidationIssue::warning("invariant", location, message),
+    })
+}
+
+/// Checks the invariant on every element selected by its context.
+fn check(invariant: &Invariant, resource: &Value) -> Vec<ValidationIssue> {
+    let Ok(contexts) =
+        fhirpath::evaluate(&invariant.context, Node::element(resource, ""), resource)
+    else {
+        return Vec::new();
+    };
+
+    contexts
+        .into_iter()
+        .filter_map(|context| {
+            check_constraint(
+                &invariant.key,
+                invariant.severity,
+                &invariant.human,
+                &invariant.expression,
+                context,
+                resource,
+            )
+        })
+        .collect()
+}
+
+/// Calls `f` with the parsed base invariants of the selected version.
+fn with_invariants<R>(f: impl FnOnce(&HashMap<String, Vec<Invariant>>) -> R) -> R {
+    with_definitions(FhirVersion::current(), |definitions| {
+        f(&definitions.invariants)
+    })
+}
+
+/// Parses the bundled invariants, grouped by the type they belong to.
+///
+/// Invariants that use `FHIRPath` features which are not supported are skipped.
+pub(super) fn parse(raw: &str) -> HashMap<String, Vec<Invariant>> {
+    let parsed: HashMap<String, Vec<RawInvariant>> =
+        serde_json::from_str(raw).expect("the included invariants are invalid");
+
+    parsed
+        .into_iter()
+        .map(|(group, invariants)| {
+            let invariants = invariants
+                .into_iter()
+                .filter_map(|invariant| {
+                    Some(Invariant {
+                        severity: if invariant.severity == "warning" {
+                            IssueSeverity::Warning
+                        } else {
+                            IssueSeverity::Error
+                        },
+                        context: fhirpath::parse(&invariant.context).ok()?,
+                        expression: fhirpath::parse(&invariant.expression).ok()?,
+                        key: invariant.key,
+                        human: invariant.human,
+                    })
+                })
+                .collect();
+
+            (group, invariants)
+        })
+        .collect()
+}
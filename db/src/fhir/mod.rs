@@ -1,37 +1,325 @@
-//! This module contains the full FHIR JSON schema file.
+//! Validation of resources against the FHIR JSON schema of the selected
+//! [`FhirVersion`], and the definitions that are not part of the schema.
 
-use std::cell::OnceCell;
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    rc::Rc,
+};
 
-use fastrace::trace;
+use fastrace::{prelude::*, trace};
 use jsonschema::Validator;
-use serde_json::Value;
+use serde_json::{json, Value};
 
-static FULL_SCHEMA: &str = include_str!("../../assets/fhir.schema.json");
+pub mod fhirpath;
+pub mod invariant;
+pub mod profile;
+pub mod version;
+
+use version::FhirVersion;
 
 thread_local! {
-    // Cache the `Validator` for faster validation.
+    // Cache the compiled definitions of every FHIR version for faster validation.
     //
-    // With this we don't have to re-compile the schema every time.
-    static VALIDATOR: OnceCell<Validator> = const { OnceCell::new() };
+    // With this we don't have to re-compile the schema every time. Backends are forked
+    // from the postmaster on the same thread, so they inherit the definitions compiled
+    // by `warm_up` when the extension is part of `shared_preload_libraries`.
+    static DEFINITIONS: RefCell<HashMap<FhirVersion, Rc<CompiledDefinitions>>> =
+        RefCell::new(HashMap::new());
+}
+
+/// The definitions of a single version, parsed and compiled for validation.
+struct CompiledDefinitions {
+    /// Validates a resource against the definition of its `resourceType`.
+    validator: Validator,
+
+    /// All resource types of this version.
+    resource_types: HashSet<String>,
+
+    /// The mandatory top-level elements of every definition.
+    required: HashMap<String, Vec<String>>,
+
+    /// The top-level elements of every type that are part of its summary.
+    ///
+    /// The JSON schema does not contain the `isSummary` flags, so they are taken from
+    /// the `StructureDefinition`s.
+    summary: HashMap<String, Vec<String>>,
+
+    /// The invariants of the resources and data types, grouped by their type.
+    invariants: HashMap<String, Vec<invariant::Invariant>>,
+
+    /// The search parameters that link resource types to the compartments they are
+    /// part of, taken from the `CompartmentDefinition`s, see
+    /// [Compartments](<https://hl7.org/fhir/compartmentdefinition.html>).
+    compartments: HashMap<String, HashMap<String, Vec<String>>>,
+}
+
+impl CompiledDefinitions {
+    /// Parses the bundled definitions of the given version, and compiles its schema.
+    fn compile(version: FhirVersion) -> Self {
+        let _guard = LocalSpan::enter_with_local_parent("compile_schema")
+            .with_property(|| ("version", version.to_string()));
+
+        let definitions = version.expect_definitions();
+        let mut parsed: Value =
+            serde_json::from_str(definitions.schema).expect("the included FHIR schema is invalid");
+
+        let resource_types = parsed["discriminator"]["mapping"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(resource_type, _)| resource_type.clone())
+            .collect::<HashSet<_>>();
+
+        let required = parsed["definitions"]
+            .as_object()
+            .into_iter()
+            .flatten()
+            .map(|(name, definition)| {
+                let required = serde_json::from_value(definition["required"].clone());
+                (name.clone(), required.unwrap_or_default())
+            })
+            .collect();
+
+        // Replace the `oneOf` of all resource types by a conditional per type, which
+        // reports the errors of the resource instead of a single error for the `oneOf`.
+        // Conditionals are only supported since draft 7.
+        let dispatch = resource_types
+            .iter()
+            .map(|resource_type| {
+                json!({
+                    "if": {
+                        "properties": { "resourceType": { "const": resource_type } },
+                        "required": ["resourceType"],
+                    },
+                    "then": { "$ref": format!("#/definitions/{resource_type}") },
+                })
+            })
+            .collect();
+
+        let schema = parsed
+            .as_object_mut()
+            .expect("the included FHIR schema is not an object");
+        schema.remove("oneOf");
+        schema.remove("discriminator");
+        schema.insert("allOf".to_string(), Value::Array(dispatch));
+        schema.insert(
+            "$schema".to_string(),
+            Value::String("http://json-schema.org/draft-07/schema#".to_string()),
+        );
+
+        Self {
+            validator: jsonschema::validator_for(&parsed).expect("failed to compile FHIR schema"),
+            resource_types,
+            required,
+            summary: serde_json::from_str(definitions.summary)
+                .expect("the included summary elements are invalid"),
+            invariants: invariant::parse(definitions.invariants),
+            compartments: serde_json::from_str(definitions.compartments)
+                .expect("the included compartment definitions are invalid"),
+        }
+    }
 }
 
-/// Compiles the FHIR json schema.
+/// Calls `f` with the compiled definitions of the given version, compiling them if
+/// needed.
+fn with_definitions<R>(version: FhirVersion, f: impl FnOnce(&CompiledDefinitions) -> R) -> R {
+    let definitions = DEFINITIONS.with_borrow_mut(|definitions| {
+        definitions
+            .entry(version)
+            .or_insert_with(|| Rc::new(CompiledDefinitions::compile(version)))
+            .clone()
+    });
+
+    f(&definitions)
+}
+
+/// Compiles the definitions of the given versions, so the first validation in a
+/// backend does not have to.
 #[trace]
-pub fn compile_schema() {
-    is_valid(&Value::Null);
+pub fn warm_up(versions: &[FhirVersion]) {
+    for version in versions {
+        with_definitions(*version, |_| {});
+    }
 }
 
-/// Checks if the given JSON value matches the FHIR schema.
+/// Checks if the given JSON value matches the FHIR schema, and does not violate
+/// any of the base invariants.
 #[trace]
 pub fn is_valid(obj: &Value) -> bool {
-    VALIDATOR.with(|raw| {
-        let validator = raw.get_or_init(|| {
-            let parsed: Value =
-                serde_json::from_str(FULL_SCHEMA).expect("the included FHIR schema is invalid");
+    let schema_valid = with_definitions(FhirVersion::current(), |schema| {
+        obj.get("resourceType")
+            .and_then(Value::as_str)
+            .is_some_and(|resource_type| schema.resource_types.contains(resource_type))
+            && schema.validator.is_valid(obj)
+    });
+
+    schema_valid
+        && invariant::validate(obj)
+            .iter()
+            .all(|issue| issue.severity != IssueSeverity::Error)
+}
+
+/// How severe a [`ValidationIssue`] is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IssueSeverity {
+    /// The resource is invalid.
+    Error,
+
+    /// The resource is valid, but could not be fully checked.
+    Warning,
+}
+
+impl IssueSeverity {
+    /// The FHIR code of the severity.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            IssueSeverity::Error => "error",
+            IssueSeverity::Warning => "warning",
+        }
+    }
+}
 
-            jsonschema::validator_for(&parsed).expect("failed to compile FHIR schema")
-        });
+/// A reason why a resource does not match the FHIR schema or one of its profiles.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValidationIssue {
+    pub severity: IssueSeverity,
 
-        validator.is_valid(obj)
+    /// The [issue type](<https://hl7.org/fhir/valueset-issue-type.html>), like `structure`.
+    pub code: &'static str,
+
+    /// The JSON pointer to the invalid element, like `/name/0/given`.
+    pub location: String,
+
+    /// Describes why the element is invalid.
+    pub message: String,
+}
+
+impl ValidationIssue {
+    pub fn error(
+        code: &'static str,
+        location: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            severity: IssueSeverity::Error,
+            code,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+
+    pub fn warning(
+        code: &'static str,
+        location: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self {
+            severity: IssueSeverity::Warning,
+            code,
+            location: location.into(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Validates the given JSON value against the FHIR schema of its `resourceType`,
+/// and checks the base invariants.
+///
+/// Returns one issue per violation, or no issues if the value is valid.
+#[trace]
+pub fn validate(obj: &Value) -> Vec<ValidationIssue> {
+    let Some(resource_type) = obj.get("resourceType").and_then(Value::as_str) else {
+        return vec![ValidationIssue::error(
+            "structure",
+            "",
+            "the resource does not have a 'resourceType'",
+        )];
+    };
+
+    let version = FhirVersion::current();
+    with_definitions(version, |schema| {
+        if !schema.resource_types.contains(resource_type) {
+            return vec![ValidationIssue::error(
+                "structure",
+                "/resourceType",
+                format!("unknown resource type '{resource_type}' in FHIR {version}"),
+            )];
+        }
+
+        schema
+            .validator
+            .iter_errors(obj)
+            .map(|error| {
+                ValidationIssue::error(
+                    "structure",
+                    error.instance_path().to_string(),
+                    error.masked().to_string(),
+                )
+            })
+            .chain(invariant::validate(obj))
+            .collect()
+    })
+}
+
+/// Returns the mandatory top-level elements of the given resource type.
+#[trace]
+pub fn required_elements(resource_type: &str) -> Vec<String> {
+    with_definitions(FhirVersion::current(), |schema| {
+        schema
+            .required
+            .get(resource_type)
+            .cloned()
+            .unwrap_or_default()
+    })
+}
+
+/// Returns the top-level elements of the given resource type that are part of its summary.
+///
+/// Choice elements end with `[x]`, like `deceased[x]`.
+/// Returns [`None`] if the summary of this resource type is not part of the bundled
+/// definitions of the current version.
+#[trace]
+pub fn summary_elements(resource_type: &str) -> Option<Vec<String>> {
+    with_definitions(FhirVersion::current(), |definitions| {
+        let summary = &definitions.summary;
+        let elements = summary.get(resource_type)?;
+        Some(
+            summary["Resource"]
+                .iter()
+                .chain(elements)
+                .cloned()
+                .collect(),
+        )
+    })
+}
+
+/// Returns the search parameters that link the given resource type to a compartment.
+///
+/// An entity is part of the compartment, if one of the parameters references the
+/// compartment entity. Returns [`None`] if the resource type is not part of the compartment.
+#[trace]
+pub fn compartment_params(compartment: &str, resource_type: &str) -> Option<Vec<String>> {
+    with_definitions(FhirVersion::current(), |definitions| {
+        definitions
+            .compartments
+            .get(compartment)?
+            .get(resource_type)
+            .cloned()
+    })
+}
+
+/// Returns all resource types of a compartment, with the search parameters that link
+/// them to the compartment.
+#[trace]
+pub fn compartment_types(compartment: &str) -> Vec<(String, Vec<String>)> {
+    with_definitions(FhirVersion::current(), |definitions| {
+        definitions
+            .compartments
+            .get(compartment)
+            .into_iter()
+            .flatten()
+            .map(|(resource_type, params)| (resource_type.clone(), params.clone()))
+            .collect()
     })
 }
@@ -0,0 +1,428 @@
+//! Validation of resources against the snapshot of a
+//! [`StructureDefinition`](<https://hl7.org/fhir/structuredefinition.html>) profile.
+//!
+//! Checks the cardinality, fixed and pattern values, allowed types of choice
+//! elements and constraints, and assigns repeating elements to their slices using
+//! the `value`, `pattern`, `exists` and `type` discriminators.
+
+use serde_json::{Map, Value};
+
+use crate::fhir::{
+    fhirpath::{self, Node},
+    invariant, IssueSeverity, ValidationIssue,
+};
+
+/// Validates `resource` against the snapshot of the `profile` `StructureDefinition`.
+pub fn validate(profile: &Value, resource: &Value) -> Vec<ValidationIssue> {
+    let url = profile["url"].as_str().unwrap_or_default();
+
+    let Some(elements) = profile["snapshot"]["element"].as_array() else {
+        return vec![ValidationIssue::warning(
+            "not-supported",
+            "",
+            format!("the profile '{url}' does not have a snapshot"),
+        )];
+    };
+
+    if let Some(ty) = profile["type"].as_str() {
+        if resource["resourceType"] != ty {
+            return vec![ValidationIssue::error(
+                "invalid",
+                "/resourceType",
+                format!("the profile '{url}' only applies to '{ty}' resources"),
+            )];
+        }
+    }
+
+    let Some(root) = elements.first().map(element_id) else {
+        return Vec::new();
+    };
+
+    let mut validator = ProfileValidator {
+        url,
+        elements,
+        resource,
+        issues: Vec::new(),
+    };
+    validator.validate_children(root, resource, "");
+
+    validator.issues
+}
+
+struct ProfileValidator<'a, 'r> {
+    url: &'a str,
+    elements: &'a [Value],
+    resource: &'r Value,
+    issues: Vec<ValidationIssue>,
+}
+
+impl<'a> ProfileValidator<'a, '_> {
+    /// Validates the children of the element with the id `parent` in `value`.
+    fn validate_children(&mut self, parent: &str, value: &Value, location: &str) {
+        let Some(obj) = value.as_object() else {
+            return;
+        };
+
+        for element in self.children(parent) {
+            let name = &element_id(element)[parent.len() + 1..];
+
+            let values = if let Some(prefix) = name.strip_suffix("[x]") {
+                self.choice_values(element, prefix, obj, location)
+            } else {
+                obj.get(name)
+                    .map(|value| values_with_location(value, &format!("{location}/{name}")))
+                    .unwrap_or_default()
+            };
+
+            self.validate_values(element, &values, &format!("{location}/{name}"));
+        }
+    }
+
+    /// Validates all values of the same element, including their slices.
+    fn validate_values(&mut self, element: &Value, values: &[(&Value, String)], location: &str) {
+        self.check_cardinality(element, values.len(), location);
+
+        for (value, location) in values {
+            self.check_value(element, value, location);
+            self.validate_children(element_id(element), value, location);
+        }
+
+        if element.get("slicing").is_some() {
+            self.validate_slices(element, values, location);
+        }
+    }
+
+    /// Assigns the values of a sliced element to its slices, and validates them against
+    /// the slice they belong to.
+    fn validate_slices(&mut self, element: &Value, values: &[(&Value, String)], location: &str) {
+        let id = element_id(element);
+        let slices = self.slices(id);
+        let discriminators = element["slicing"]["discriminator"]
+            .as_array()
+            .map(Vec::as_slice)
+            .unwrap_or_default();
+        let closed = element["slicing"]["rules"] == "closed";
+
+        let mut matched = vec![Vec::new(); slices.len()];
+        for (value, value_location) in values {
+            let slice = slices
+                .iter()
+                .position(|slice| self.matches_slice(slice, discriminators, value));
+
+            match slice {
+                Some(slice) => matched[slice].push((*value, value_location.clone())),
+                None if closed => self.error(
+                    "structure",
+                    value_location,
+                    format!(
+                        "'{}' does not match any of its slices",
+                        element_path(element)
+                    ),
+                ),
+                None => {}
+            }
+        }
+
+        for (slice, values) in slices.into_iter().zip(matched) {
+            self.validate_values(slice, &values, location);
+        }
+    }
+
+    /// Checks if `value` belongs to the slice, according to all discriminators.
+    fn matches_slice(&self, slice: &Value, discriminators: &[Value], value: &Value) -> bool {
+        discriminators.iter().all(|discriminator| {
+            let path = discriminator["path"].as_str().unwrap_or("$this");
+            let (element, values) = if path == "$this" {
+                (Some(slice), vec![value])
+            } else {
+                (
+                    self.element(&format!("{}.{path}", element_id(slice))),
+                    values_at_path(value, path),
+                )
+            };
+
+            match discriminator["type"].as_str() {
+                Some("value" | "pattern") => {
+                    let Some(element) = element else {
+                        return false;
+                    };
+
+                    if let Some(fixed) = prefixed_value(element, "fixed") {
+                        values.contains(&fixed)
+                    } else if let Some(pattern) = prefixed_value(element, "pattern") {
+                        values.iter().any(|value| matches_pattern(pattern, value))
+                    } else {
+                        false
+                    }
+                }
+                Some("exists") => {
+                    let Some(element) = element else {
+                        return false;
+                    };
+
+                    if element["max"] == "0" {
+                        values.is_empty()
+                    } else if element["min"].as_u64().unwrap_or_default() > 0 {
+                        !values.is_empty()
+                    } else {
+                        true
+                    }
+                }
+                Some("type") => {
+                    let types = element.map(element_types).unwrap_or_default();
+                    values.iter().any(|value| {
+                        // Only resources carry their type, other values can't be told apart.
+                        value["resourceType"]
+                            .as_str()
+                            .is_none_or(|ty| types.is_empty() || types.contains(&ty))
+                    })
+                }
+                // `profile` discriminators would require resolving other profiles.
+                _ => true,
+            }
+        })
+    }
+
+    /// Returns the values of a choice element like `value[x]`, and checks that their
+    /// type is allowed by the profile.
+    fn choice_values<'v>(
+        &mut self,
+        element: &Value,
+        prefix: &str,
+        obj: &'v Map<String, Value>,
+        location: &str,
+    ) -> Vec<(&'v Value, String)> {
+        let types = element_types(element);
+
+        let mut values = Vec::new();
+        for (key, value) in obj {
+            let Some(ty) = key
+                .strip_prefix(prefix)
+                .filter(|ty| ty.starts_with(char::is_uppercase))
+            else {
+                continue;
+            };
+
+            let allowed =
+                types.is_empty() || types.iter().any(|allowed| allowed.eq_ignore_ascii_case(ty));
+            if !allowed {
+                self.error(
+                    "structure",
+                    &format!("{location}/{key}"),
+                    format!(
+                        "the type '{ty}' is not allowed for '{}'",
+                        element_path(element)
+                    ),
+                );
+            }
+
+            values.extend(values_with_location(value, &format!("{location}/{key}")));
+        }
+
+        values
+    }
+
+    /// Checks the number of values against the minimum and maximum cardinality.
+    fn check_cardinality(&mut self, element: &Value, count: usize, location: &str) {
+        let path = element_path(element);
+
+        let min = element["min"].as_u64().unwrap_or_default();
+        if (count as u64) < min {
+            self.error(
+                "required",
+                location,
+                format!("'{path}' requires at least {min} value(s), but has {count}"),
+            );
+        }
+
+        let max = element["max"]
+            .as_str()
+            .and_then(|max| max.parse::<u64>().ok());
+        if let Some(max) = max.filter(|max| count as u64 > *max) {
+            self.error(
+                "structure",
+                location,
+                format!("'{path}' allows at most {max} value(s), but has {count}"),
+            );
+        }
+    }
+
+    /// Checks a single value against the fixed or pattern value and the constraints
+    /// of the element.
+    ///
+    /// Constraints that are part of the base invariants, or that can not be evaluated,
+    /// are skipped.
+    fn check_value(&mut self, element: &Value, value: &Value, location: &str) {
+        for constraint in element["constraint"].as_array().into_iter().flatten() {
+            let (Some(key), Some(expression)) = (
+                constraint["key"].as_str(),
+                constraint["expression"].as_str(),
+            ) else {
+                continue;
+            };
+            if invariant::is_base_invariant(key) {
+                continue;
+            }
+            let Ok(expression) = fhirpath::parse(expression) else {
+                continue;
+            };
+
+            let severity = if constraint["severity"] == "warning" {
+                IssueSeverity::Warning
+            } else {
+                IssueSeverity::Error
+            };
+            let issue = invariant::check_constraint(
+                key,
+                severity,
+                constraint["human"].as_str().unwrap_or_default(),
+                &expression,
+                Node::element(value, location),
+                self.resource,
+            );
+
+            if let Some(mut issue) = issue {
+                issue.message = format!("{} (profile '{}')", issue.message, self.url);
+                self.issues.push(issue);
+            }
+        }
+
+        let path = element_path(element);
+
+        if let Some(fixed) = prefixed_value(element, "fixed") {
+            if value != fixed {
+                self.error(
+                    "value",
+                    location,
+                    format!("'{path}' must have the fixed value {fixed}"),
+                );
+            }
+        }
+
+        if let Some(pattern) = prefixed_value(element, "pattern") {
+            if !matches_pattern(pattern, value) {
+                self.error(
+                    "value",
+                    location,
+                    format!("'{path}' must match the pattern {pattern}"),
+                );
+            }
+        }
+    }
+
+    /// Returns the direct children of the element with the given id, without slices.
+    fn children(&self, parent: &str) -> Vec<&'a Value> {
+        self.elements
+            .iter()
+            .filter(|element| {
+                element_id(element)
+                    .strip_prefix(parent)
+                    .and_then(|rest| rest.strip_prefix('.'))
+                    .is_some_and(|name| !name.contains(['.', ':']))
+            })
+            .collect()
+    }
+
+    /// Returns the slices of the element with the given id.
+    fn slices(&self, id: &str) -> Vec<&'a Value> {
+        self.elements
+            .iter()
+            .filter(|element| {
+                element_id(element)
+                    .strip_prefix(id)
+                    .and_then(|rest| rest.strip_prefix(':'))
+                    .is_some_and(|name| !name.contains(['.', ':']))
+            })
+            .collect()
+    }
+
+    /// Returns the element with the given id.
+    fn element(&self, id: &str) -> Option<&'a Value> {
+        self.elements
+            .iter()
+            .find(|element| element_id(element) == id)
+    }
+
+    fn error(&mut self, code: &'static str, location: &str, message: String) {
+        self.issues.push(ValidationIssue::error(
+            code,
+            location,
+            format!("{message} (profile '{}')", self.url),
+        ));
+    }
+}
+
+/// The id of an element, like `Patient.identifier:ssn.system`.
+///
+/// Older `StructureDefinition`s do not have element ids, so the path is used instead.
+fn element_id(element: &Value) -> &str {
+    element["id"]
+        .as_str()
+        .or(element["path"].as_str())
+        .unwrap_or_default()
+}
+
+/// The path of an element, like `Patient.identifier.system`.
+fn element_path(element: &Value) -> &str {
+    element["path"].as_str().unwrap_or_default()
+}
+
+/// The type codes of an element, like `Quantity` or `string`.
+fn element_types(element: &Value) -> Vec<&str> {
+    element["type"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|ty| ty["code"].as_str())
+        .collect()
+}
+
+/// Returns the value of the first key with the given prefix, like `fixedUri` for `fixed`.
+fn prefixed_value<'v>(element: &'v Value, prefix: &str) -> Option<&'v Value> {
+    element.as_object()?.iter().find_map(|(key, value)| {
+        key.strip_prefix(prefix)
+            .filter(|ty| ty.starts_with(char::is_uppercase))
+            .map(|_| value)
+    })
+}
+
+/// Checks if `value` contains all elements of the `pattern`.
+fn matches_pattern(pattern: &Value, value: &Value) -> bool {
+    match (pattern, value) {
+        (Value::Object(pattern), Value::Object(value)) => pattern.iter().all(|(key, pattern)| {
+            value
+                .get(key)
+                .is_some_and(|value| matches_pattern(pattern, value))
+        }),
+        (Value::Array(pattern), Value::Array(values)) => pattern
+            .iter()
+            .all(|pattern| values.iter().any(|value| matches_pattern(pattern, value))),
+        (pattern, value) => pattern == value,
+    }
+}
+
+/// Splits repeating values into their items, each with its own location.
+fn values_with_location<'v>(value: &'v Value, location: &str) -> Vec<(&'v Value, String)> {
+    match value {
+        Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| (value, format!("{location}/{i}")))
+            .collect(),
+        value => vec![(value, location.to_string())],
+    }
+}
+
+/// Returns all values at a simple `FHIRPath` like `coding.system`.
+fn values_at_path<'v>(value: &'v Value, path: &str) -> Vec<&'v Value> {
+    path.split('.').fold(vec![value], |values, segment| {
+        values
+            .into_iter()
+            .filter_map(|value| value.get(segment))
+            .flat_map(|value| match value {
+                Value::Array(values) => values.iter().collect(),
+                value => vec![value],
+            })
+            .collect()
+    })
+}
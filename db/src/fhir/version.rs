@@ -0,0 +1,121 @@
+//! The FHIR versions that are supported by this extension.
+//!
+//! The version is selected with the `fhir.version` setting, which is usually set
+//! once per database:
+//!
+//! ```sql
+//! ALTER DATABASE fhir SET fhir.version = 'R5';
+//! ```
+//!
+//! The definitions of R4B are always bundled. The definitions of R4 and R5 are only
+//! included if the extension is built with the `r4` or `r5` feature, after they were
+//! downloaded with `db/scripts/fetch-definitions.sh`.
+
+use std::fmt;
+
+use pgrx::PostgresGucEnum;
+
+use crate::gucs;
+
+/// Includes the definitions in the given directory of `db/assets`.
+macro_rules! bundled_definitions {
+    ($dir:literal) => {
+        Definitions {
+            schema: include_str!(concat!("../../assets/", $dir, "/fhir.schema.json")),
+            summary: include_str!(concat!("../../assets/", $dir, "/summary.json")),
+            invariants: include_str!(concat!("../../assets/", $dir, "/invariants.json")),
+            compartments: include_str!(concat!("../../assets/", $dir, "/compartments.json")),
+        }
+    };
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PostgresGucEnum)]
+pub enum FhirVersion {
+    #[name = c"R4"]
+    R4,
+
+    #[name = c"R4B"]
+    R4B,
+
+    #[name = c"R5"]
+    R5,
+}
+
+impl FhirVersion {
+    /// The version selected by `fhir.version`.
+    pub fn current() -> Self {
+        gucs::FHIR_VERSION.get()
+    }
+
+    /// All versions whose definitions are part of this build.
+    pub fn bundled() -> Vec<Self> {
+        [FhirVersion::R4, FhirVersion::R4B, FhirVersion::R5]
+            .into_iter()
+            .filter(|version| version.definitions().is_some())
+            .collect()
+    }
+
+    /// The full version number, like `4.3.0`.
+    pub fn number(self) -> &'static str {
+        match self {
+            FhirVersion::R4 => "4.0.1",
+            FhirVersion::R4B => "4.3.0",
+            FhirVersion::R5 => "5.0.0",
+        }
+    }
+
+    /// The bundled definitions of this version.
+    ///
+    /// Returns [`None`] if the extension was built without the definitions of this
+    /// version.
+    pub fn definitions(self) -> Option<Definitions> {
+        match self {
+            #[cfg(feature = "r4")]
+            FhirVersion::R4 => Some(bundled_definitions!("r4")),
+            FhirVersion::R4B => Some(bundled_definitions!("r4b")),
+            #[cfg(feature = "r5")]
+            FhirVersion::R5 => Some(bundled_definitions!("r5")),
+            #[allow(unreachable_patterns)]
+            _ => None,
+        }
+    }
+
+    /// Like [`FhirVersion::definitions`], but fails if the definitions are not available.
+    pub fn expect_definitions(self) -> Definitions {
+        self.definitions().unwrap_or_else(|| {
+            panic!(
+                "FHIR version {self} is not available, the extension was built without the '{}' feature",
+                self.to_string().to_lowercase()
+            )
+        })
+    }
+}
+
+/// The definitions of a FHIR version, that are included in the extension.
+///
+/// All files are generated from the definitions of the specification by
+/// `db/scripts/fetch-definitions.sh`.
+#[derive(Debug, Clone, Copy)]
+pub struct Definitions {
+    /// The FHIR JSON schema.
+    pub schema: &'static str,
+
+    /// The top-level elements of each type that have the `isSummary` flag set.
+    pub summary: &'static str,
+
+    /// The invariants of the resources and data types, grouped by their type.
+    pub invariants: &'static str,
+
+    /// The search parameters that link resource types to the `Patient` compartment.
+    pub compartments: &'static str,
+}
+
+impl fmt::Display for FhirVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FhirVersion::R4 => "R4",
+            FhirVersion::R4B => "R4B",
+            FhirVersion::R5 => "R5",
+        })
+    }
+}
@@ -1,6 +1,13 @@
-use std::ffi::{CStr, CString};
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_void, CStr, CString},
+};
 
-use pgrx::{GucContext, GucFlags, GucRegistry, GucSetting};
+use pgrx::{
+    pg_guard, pg_sys, GucContext, GucEnum, GucFlags, GucRegistry, GucSetting, PostgresGucEnum,
+};
+
+use crate::fhir::version::FhirVersion;
 
 static JAEGER_ENABLED_PARAM: &CStr = c"fhir.jaeger_enabled";
 pub static JAEGER_ENABLED: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
@@ -8,6 +15,156 @@ pub static JAEGER_ENABLED: GucSetting<Option<CString>> = GucSetting::<Option<CSt
 static JAEGER_HOST_PARAM: &CStr = c"fhir.jaeger_host";
 pub static JAEGER_HOST: GucSetting<Option<CString>> = GucSetting::<Option<CString>>::new(None);
 
+static SEARCH_MAX_CHAIN_DEPTH_PARAM: &CStr = c"fhir.search_max_chain_depth";
+pub static SEARCH_MAX_CHAIN_DEPTH: GucSetting<i32> = GucSetting::<i32>::new(3);
+
+static VALIDATE_PROFILES_PARAM: &CStr = c"fhir.validate_profiles";
+pub static VALIDATE_PROFILES: GucSetting<bool> = GucSetting::<bool>::new(false);
+
+static FHIR_VERSION_PARAM: &CStr = c"fhir.version";
+pub static FHIR_VERSION: GucSetting<FhirVersion> = GucSetting::<FhirVersion>::new(FhirVersion::R4B);
+
+static VALIDATION_MODE_PARAM: &CStr = c"fhir.validation_mode";
+pub static VALIDATION_MODE: GucSetting<ValidationMode> =
+    GucSetting::<ValidationMode>::new(ValidationMode::Strict);
+
+static VALIDATION_MODE_OVERRIDES_PARAM: &CStr = c"fhir.validation_mode_overrides";
+pub static VALIDATION_MODE_OVERRIDES: GucSetting<Option<CString>> =
+    GucSetting::<Option<CString>>::new(None);
+
+/// How invalid resources are handled when they are stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PostgresGucEnum)]
+pub enum ValidationMode {
+    /// Invalid resources are rejected.
+    #[name = c"strict"]
+    Strict,
+
+    /// Invalid resources are stored, and their errors are recorded in the
+    /// `fhir.validation_issue` table.
+    #[name = c"warn"]
+    Warn,
+
+    /// Resources are not validated.
+    #[name = c"off"]
+    Off,
+}
+
+impl ValidationMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ValidationMode::Strict => "strict",
+            ValidationMode::Warn => "warn",
+            ValidationMode::Off => "off",
+        }
+    }
+}
+
+thread_local! {
+    /// The parsed value of `fhir.validation_mode_overrides`, updated by its assign hook.
+    static PARSED_OVERRIDES: RefCell<Vec<(String, ValidationMode)>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Returns the validation mode of the given resource type.
+///
+/// `fhir.validation_mode_overrides` contains a comma separated list of
+/// `<resource type>=<mode>` pairs, like `Observation=warn,Binary=off`, which take
+/// precedence over `fhir.validation_mode`.
+pub fn validation_mode(resource_type: &str) -> ValidationMode {
+    PARSED_OVERRIDES
+        .with_borrow(|overrides| {
+            overrides
+                .iter()
+                .find(|(ty, _)| ty == resource_type)
+                .map(|(_, mode)| *mode)
+        })
+        .unwrap_or_else(|| VALIDATION_MODE.get())
+}
+
+fn parse_overrides(overrides: &str) -> Result<Vec<(String, ValidationMode)>, String> {
+    overrides
+        .split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let Some((ty, mode)) = entry.split_once('=') else {
+                return Err(format!(
+                    "Invalid entry '{entry}', expected '<resource type>=<mode>'."
+                ));
+            };
+            let mode = match mode.trim().to_lowercase().as_str() {
+                "strict" => ValidationMode::Strict,
+                "warn" => ValidationMode::Warn,
+                "off" => ValidationMode::Off,
+                mode => {
+                    return Err(format!(
+                        "Invalid validation mode '{mode}', expected 'strict', 'warn' or 'off'."
+                    ));
+                }
+            };
+
+            Ok((ty.trim().to_string(), mode))
+        })
+        .collect()
+}
+
+/// Rejects invalid values of `fhir.validation_mode_overrides` when they are set.
+#[pg_guard]
+unsafe extern "C-unwind" fn check_validation_mode_overrides(
+    newval: *mut *mut c_char,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource::Type,
+) -> bool {
+    let value = unsafe { *newval };
+    if value.is_null() {
+        return true;
+    }
+
+    match parse_overrides(&unsafe { CStr::from_ptr(value) }.to_string_lossy()) {
+        Ok(_) => true,
+        Err(detail) => {
+            let detail = CString::new(detail).unwrap_or_default();
+            unsafe { pg_sys::GUC_check_errdetail_string = pg_sys::pstrdup(detail.as_ptr()) };
+            false
+        }
+    }
+}
+
+#[pg_guard]
+unsafe extern "C-unwind" fn assign_validation_mode_overrides(
+    newval: *const c_char,
+    _extra: *mut c_void,
+) {
+    let overrides = if newval.is_null() {
+        Vec::new()
+    } else {
+        // The value has already been accepted by the check hook.
+        parse_overrides(&unsafe { CStr::from_ptr(newval) }.to_string_lossy()).unwrap_or_default()
+    };
+
+    PARSED_OVERRIDES.set(overrides);
+}
+
+/// Rejects the FHIR versions whose definitions are not part of this build.
+#[pg_guard]
+unsafe extern "C-unwind" fn check_fhir_version(
+    newval: *mut i32,
+    _extra: *mut *mut c_void,
+    _source: pg_sys::GucSource::Type,
+) -> bool {
+    let version = FhirVersion::from_ordinal(unsafe { *newval });
+    if version.definitions().is_some() {
+        return true;
+    }
+
+    let detail = CString::new(format!(
+        "The extension was built without the '{}' feature.",
+        version.to_string().to_lowercase()
+    ))
+    .unwrap_or_default();
+    unsafe { pg_sys::GUC_check_errdetail_string = pg_sys::pstrdup(detail.as_ptr()) };
+    false
+}
+
 pub fn init() {
     GucRegistry::define_string_guc(
         JAEGER_ENABLED_PARAM,
@@ -26,4 +183,64 @@ pub fn init() {
         GucContext::Userset,
         GucFlags::SUPERUSER_ONLY,
     );
+
+    GucRegistry::define_int_guc(
+        SEARCH_MAX_CHAIN_DEPTH_PARAM,
+        c"Maximum search chain depth",
+        c"Maximum number of chained and _has references in a single search parameter",
+        &SEARCH_MAX_CHAIN_DEPTH,
+        0,
+        16,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    GucRegistry::define_bool_guc(
+        VALIDATE_PROFILES_PARAM,
+        c"Validate profiles",
+        c"Validates resources against the StructureDefinitions listed in their meta.profile",
+        &VALIDATE_PROFILES,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    // SAFETY: the hook only looks up the bundled definitions and never longjmps past
+    // Rust frames.
+    unsafe {
+        GucRegistry::define_enum_guc_with_hooks(
+            FHIR_VERSION_PARAM,
+            c"FHIR version",
+            c"The FHIR version (R4, R4B or R5) whose schema is used to validate resources",
+            &FHIR_VERSION,
+            GucContext::Userset,
+            GucFlags::default(),
+            Some(check_fhir_version),
+            None,
+            None,
+        );
+    }
+
+    GucRegistry::define_enum_guc(
+        VALIDATION_MODE_PARAM,
+        c"Validation mode",
+        c"Whether invalid resources are rejected (strict), stored with their issues (warn) or not validated (off)",
+        &VALIDATION_MODE,
+        GucContext::Userset,
+        GucFlags::default(),
+    );
+
+    // SAFETY: the hooks only parse the new value and never longjmp past Rust frames.
+    unsafe {
+        GucRegistry::define_string_guc_with_hooks(
+            VALIDATION_MODE_OVERRIDES_PARAM,
+            c"Validation mode overrides",
+            c"Comma separated list of <resource type>=<mode> pairs, which override fhir.validation_mode",
+            &VALIDATION_MODE_OVERRIDES,
+            GucContext::Userset,
+            GucFlags::default(),
+            Some(check_validation_mode_overrides),
+            Some(assign_validation_mode_overrides),
+            None,
+        );
+    }
 }
@@ -3,6 +3,8 @@ use std::cell::RefCell;
 use fastrace::{local::LocalParentGuard, prelude::SpanContext, Span};
 use pgrx::{pg_sys::ffi::pg_guard_ffi_boundary, prelude::*};
 
+use crate::reporter;
+
 static mut PREV_EXECUTOR_START_HOOK: pg_sys::ExecutorStart_hook_type = None;
 static mut PREV_EXECUTOR_END_HOOK: pg_sys::ExecutorEnd_hook_type = None;
 
@@ -12,6 +14,8 @@ thread_local! {
 
 #[pg_guard]
 unsafe extern "C-unwind" fn executor_start_hook(query: *mut pg_sys::QueryDesc, data: i32) {
+    reporter::ensure_started();
+
     EXECUTION_SPAN.with(|span| {
         let mut span = span.borrow_mut();
 
@@ -0,0 +1,73 @@
+//! Gathering of indexable values for canonical (conformance) entities.
+//!
+//! See [Canonical resources](<https://hl7.org/fhir/canonicalresource.html>).
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::{index::IndexedKeyType, models::CanonicalResource};
+
+/// The entities that are identified by a canonical `url`.
+const CANONICAL_ENTITIES: &[&str] = &[
+    "ActivityDefinition",
+    "CapabilityStatement",
+    "CodeSystem",
+    "CompartmentDefinition",
+    "ConceptMap",
+    "EventDefinition",
+    "GraphDefinition",
+    "ImplementationGuide",
+    "Library",
+    "Measure",
+    "MessageDefinition",
+    "OperationDefinition",
+    "PlanDefinition",
+    "Questionnaire",
+    "SearchParameter",
+    "StructureDefinition",
+    "StructureMap",
+    "TerminologyCapabilities",
+    "ValueSet",
+];
+
+/// Returns whether `entity` is a canonical entity.
+pub fn is_canonical(entity: &str) -> bool {
+    CANONICAL_ENTITIES.contains(&entity)
+}
+
+pub fn find_search_index_for_key(key: &str) -> Option<IndexedKeyType> {
+    Some(match key {
+        "url" => IndexedKeyType::Uri,
+        "version" => IndexedKeyType::Text,
+        _ => return None,
+    })
+}
+
+pub fn text_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>> {
+    let mut keys = HashMap::new();
+
+    let Ok(resource) = serde_json::from_value::<CanonicalResource>(data.clone()) else {
+        return keys;
+    };
+
+    if let Some(version) = resource.version {
+        keys.insert("version", vec![version]);
+    }
+
+    keys
+}
+
+pub fn uri_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>> {
+    let mut keys = HashMap::new();
+
+    let Ok(resource) = serde_json::from_value::<CanonicalResource>(data.clone()) else {
+        return keys;
+    };
+
+    if let Some(url) = resource.url {
+        keys.insert("url", vec![url]);
+    }
+
+    keys
+}
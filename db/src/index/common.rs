@@ -0,0 +1,53 @@
+//! Gathering of indexable values that are shared by all entities.
+//!
+//! See [Resource search parameters](<https://hl7.org/fhir/resource.html#search>).
+
+use std::collections::HashMap;
+
+use serde_json::Value;
+
+use crate::{
+    index::{token_values, IndexedKeyType},
+    models::Meta,
+};
+
+pub fn find_search_index_for_key(key: &str) -> Option<IndexedKeyType> {
+    Some(match key {
+        "_id" => IndexedKeyType::Id,
+        "_lastUpdated" => IndexedKeyType::LastUpdated,
+        "_content" | "_text" => IndexedKeyType::FullText,
+        "_tag" | "_profile" | "_security" | "_source" => IndexedKeyType::Text,
+        _ => return None,
+    })
+}
+
+pub fn text_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>> {
+    let mut keys = HashMap::new();
+
+    let Some(meta) = data.get("meta") else {
+        return keys;
+    };
+    let Ok(meta) = serde_json::from_value::<Meta>(meta.clone()) else {
+        return keys;
+    };
+
+    let tags = token_values(meta.tag.iter().flatten());
+    if !tags.is_empty() {
+        keys.insert("_tag", tags);
+    }
+
+    let security = token_values(meta.security.iter().flatten());
+    if !security.is_empty() {
+        keys.insert("_security", security);
+    }
+
+    if let Some(profile) = meta.profile.filter(|v| !v.is_empty()) {
+        keys.insert("_profile", profile);
+    }
+
+    if let Some(source) = meta.source {
+        keys.insert("_source", vec![source]);
+    }
+
+    keys
+}
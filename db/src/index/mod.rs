@@ -1,23 +1,106 @@
 //! Responsible for generating indexable values from FHIR entities.
+//!
+//! Entities that don't match the models, which can be stored in the `warn` and `off`
+//! validation modes, are not indexed by the affected indexers.
 
-use std::collections::HashMap;
+use std::{collections::HashMap, str::FromStr as _};
 
 use fastrace::trace;
 use pgrx::{
     datum::{Date, DatumWithOid},
-    Uuid,
+    warning, Uuid,
 };
 use serde_json::Value;
 
-use crate::spi;
+use crate::{
+    models::{Coding, Quantity, Reference},
+    spi,
+};
 
+mod canonical;
+mod common;
+mod observation;
 mod patient;
+mod provenance;
 
 /// The type of an indexed key.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IndexedKeyType {
     Text,
     Date,
+    /// A reference to another entity, like `Patient/<id>`.
+    Reference,
+    /// An URI, like the canonical `url` of a `ValueSet`.
+    Uri,
+    /// The id of the entity, stored in the `entity` table.
+    Id,
+    /// The last time the entity was changed, stored in the `entity` table.
+    LastUpdated,
+    /// A `tsvector` of the text of the entity, stored in the `entity` table.
+    FullText,
+    /// A token and a quantity of the same element, like the code and value of an
+    /// observation component.
+    ///
+    /// The token is stored in the `token` column, and the quantity is the `value`.
+    Composite,
+}
+
+impl IndexedKeyType {
+    /// The relation that stores values of this type.
+    ///
+    /// Every relation has the `entity_id`, `entity`, `key` and `value` columns.
+    pub fn relation(self) -> &'static str {
+        match self {
+            IndexedKeyType::Text => r#""fhir"."entity_index_text""#,
+            IndexedKeyType::Date => r#""fhir"."entity_index_date""#,
+            IndexedKeyType::Reference => r#""fhir"."entity_index_reference""#,
+            IndexedKeyType::Uri => r#""fhir"."entity_index_uri""#,
+            IndexedKeyType::Composite => r#""fhir"."entity_index_composite""#,
+            IndexedKeyType::Id => {
+                r#"(
+                    SELECT "id" AS "entity_id", "resource_type" AS "entity", '_id' AS "key", "id" AS "value"
+                    FROM "fhir"."entity"
+                )"#
+            }
+            IndexedKeyType::LastUpdated => {
+                r#"(
+                    SELECT "id" AS "entity_id", "resource_type" AS "entity", '_lastUpdated' AS "key", "last_updated" AS "value"
+                    FROM "fhir"."entity"
+                )"#
+            }
+            IndexedKeyType::FullText => {
+                r#"(
+                    SELECT "id" AS "entity_id", "resource_type" AS "entity", '_content' AS "key", "content" AS "value"
+                    FROM "fhir"."entity"
+                    UNION ALL
+                    SELECT "id", "resource_type", '_text', "narrative"
+                    FROM "fhir"."entity"
+                )"#
+            }
+        }
+    }
+
+    /// Whether every entity has exactly one value of this type.
+    pub fn is_single_valued(self) -> bool {
+        matches!(
+            self,
+            IndexedKeyType::Id | IndexedKeyType::LastUpdated | IndexedKeyType::FullText
+        )
+    }
+
+    /// The Postgres type of the values of this type.
+    ///
+    /// Full text values are sorted by their rank, so their type is the type of the rank.
+    pub fn sql_type(self) -> &'static str {
+        match self {
+            IndexedKeyType::Text | IndexedKeyType::Reference | IndexedKeyType::Uri => "text",
+            IndexedKeyType::Date => "date",
+            IndexedKeyType::Id => "uuid",
+            IndexedKeyType::LastUpdated => "timestamptz",
+            IndexedKeyType::FullText => "real",
+            IndexedKeyType::Composite => "numeric",
+        }
+    }
 }
 
 /// Collection of values that must be inserted into the index tables.
@@ -28,6 +111,19 @@ pub struct IndexableValues {
     entity: String,
     text: Option<HashMap<&'static str, Vec<String>>>,
     date: Option<HashMap<&'static str, Vec<Date>>>,
+    reference: Option<HashMap<&'static str, Vec<String>>>,
+    uri: Option<HashMap<&'static str, Vec<String>>>,
+    composite: Option<HashMap<&'static str, Vec<CompositeValue>>>,
+}
+
+/// A single value of a composite search parameter.
+///
+/// The token and the value are always taken from the same element, e.g. the same
+/// observation component.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompositeValue {
+    pub token: String,
+    pub value: f64,
 }
 
 impl IndexableValues {
@@ -54,6 +150,32 @@ impl IndexableValues {
         Ok(())
     }
 
+    fn insert_composite_values(
+        entity: &str,
+        id: Uuid,
+        vals: HashMap<&'static str, Vec<CompositeValue>>,
+    ) -> spi::Result<()> {
+        for (key, values) in vals {
+            for value in values {
+                spi::run_with_args(
+                    r#"
+                    INSERT INTO "fhir"."entity_index_composite" ("entity_id", "entity", "key", "token", "value")
+                    VALUES ($1, $2, $3, $4, $5);
+                    "#,
+                    &[
+                        id.into(),
+                        entity.into(),
+                        key.into(),
+                        value.token.into(),
+                        value.value.into(),
+                    ],
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
     /// Inserts all indexable values into the database.
     #[trace]
     pub fn insert(self, id: Uuid) -> spi::Result<()> {
@@ -65,6 +187,18 @@ impl IndexableValues {
             Self::insert_values("date", &self.entity, id, date_values)?;
         }
 
+        if let Some(reference_values) = self.reference.filter(|v| !v.is_empty()) {
+            Self::insert_values("reference", &self.entity, id, reference_values)?;
+        }
+
+        if let Some(uri_values) = self.uri.filter(|v| !v.is_empty()) {
+            Self::insert_values("uri", &self.entity, id, uri_values)?;
+        }
+
+        if let Some(composite_values) = self.composite.filter(|v| !v.is_empty()) {
+            Self::insert_composite_values(&self.entity, id, composite_values)?;
+        }
+
         Ok(())
     }
 }
@@ -76,38 +210,150 @@ impl IndexableValues {
 ///
 /// The list key + value combinations will then be inserted into the
 /// `entity_index_text` table.
+///
+/// The values of the common search parameters are collected for every entity.
+#[trace]
+fn text_index_values_for(entity: &str, data: &Value) -> HashMap<&'static str, Vec<String>> {
+    let mut values = common::text_index_values_for(data);
+    values.extend(match entity {
+        "Patient" => patient::text_index_values_for(data),
+        "Observation" => observation::text_index_values_for(data),
+        entity if canonical::is_canonical(entity) => canonical::text_index_values_for(data),
+        _ => HashMap::new(),
+    });
+
+    values
+}
+
+/// Generates a list of date index values for the given entity.
 #[trace]
-fn text_index_values_for<'d>(
+fn date_index_values_for<'d>(
+    entity: &str,
+    data: &Value,
+) -> Option<HashMap<&'static str, Vec<Date>>> {
+    Some(match entity {
+        "Patient" => patient::date_index_values_for(data),
+        "Observation" => observation::date_index_values_for(data),
+        "Provenance" => provenance::date_index_values_for(data),
+        _ => return None,
+    })
+}
+
+/// Generates a list of reference index values for the given entity.
+///
+/// References are stored the way they are written in the entity, e.g. `Patient/<id>`.
+#[trace]
+fn reference_index_values_for(
     entity: &str,
     data: &Value,
 ) -> Option<HashMap<&'static str, Vec<String>>> {
     Some(match entity {
-        "Patient" => patient::text_index_values_for(data),
+        "Patient" => patient::reference_index_values_for(data),
+        "Observation" => observation::reference_index_values_for(data),
+        "Provenance" => provenance::reference_index_values_for(data),
         _ => return None,
     })
 }
 
-/// Generates a list of date index values for the given entity.
+/// Generates a list of URI index values for the given entity.
 #[trace]
-fn date_index_values_for<'d>(
+fn uri_index_values_for(entity: &str, data: &Value) -> Option<HashMap<&'static str, Vec<String>>> {
+    canonical::is_canonical(entity).then(|| canonical::uri_index_values_for(data))
+}
+
+/// Generates a list of composite index values for the given entity.
+#[trace]
+fn composite_index_values_for(
     entity: &str,
     data: &Value,
-) -> Option<HashMap<&'static str, Vec<Date>>> {
+) -> Option<HashMap<&'static str, Vec<CompositeValue>>> {
     Some(match entity {
-        "Patient" => patient::date_index_values_for(data),
+        "Observation" => observation::composite_index_values_for(data),
         _ => return None,
     })
 }
 
+/// Parses the date part of a FHIR `date`, `dateTime` or `instant` value.
+fn parse_date(key: &str, value: &str) -> Option<Date> {
+    let date = value.get(..10).unwrap_or(value);
+
+    if let Ok(date) = Date::from_str(date) {
+        Some(date)
+    } else {
+        warning!("invalid date value for {key}: {value}");
+        None
+    }
+}
+
+/// Collects the token values of the given codings.
+///
+/// Every coding is indexed as `code` and `system|code`, so it can be searched
+/// with and without specifying the system.
+fn token_values<'c>(codings: impl IntoIterator<Item = &'c Coding>) -> Vec<String> {
+    let mut values = Vec::new();
+
+    for coding in codings {
+        let Some(code) = &coding.code else {
+            continue;
+        };
+
+        values.push(code.clone());
+        if let Some(system) = &coding.system {
+            values.push(format!("{system}|{code}"));
+        }
+    }
+
+    values
+}
+
+/// Collects the composite values of a token and a quantity of the same element.
+///
+/// Every token value is paired with the quantity, see [`token_values`].
+/// Quantities without a value are skipped.
+fn token_quantity_values<'c>(
+    codings: impl IntoIterator<Item = &'c Coding>,
+    quantity: Option<&Quantity>,
+) -> Vec<CompositeValue> {
+    let Some(value) = quantity
+        .and_then(|q| q.value.as_ref())
+        .and_then(serde_json::Number::as_f64)
+    else {
+        return Vec::new();
+    };
+
+    token_values(codings)
+        .into_iter()
+        .map(|token| CompositeValue { token, value })
+        .collect()
+}
+
+/// Collects the values of all references that point to other resources.
+///
+/// References to contained resources (`#id`) and logical references without
+/// a `reference` are skipped, because they can not be resolved.
+fn reference_values<'r>(references: impl IntoIterator<Item = &'r Reference>) -> Vec<String> {
+    references
+        .into_iter()
+        .filter_map(|r| r.reference.clone())
+        .filter(|r| !r.starts_with('#'))
+        .collect()
+}
+
 /// Collects all indexable values for the given entity.
 #[trace]
 pub fn collect_index_values_for(entity: &str, data: &Value) -> IndexableValues {
-    let text = text_index_values_for(entity, data);
+    let text = Some(text_index_values_for(entity, data));
     let date = date_index_values_for(entity, data);
+    let reference = reference_index_values_for(entity, data);
+    let uri = uri_index_values_for(entity, data);
+    let composite = composite_index_values_for(entity, data);
 
     IndexableValues {
         text,
         date,
+        reference,
+        uri,
+        composite,
         entity: entity.to_string(),
     }
 }
@@ -118,8 +364,15 @@ pub fn collect_index_values_for(entity: &str, data: &Value) -> IndexableValues {
 /// which indicates which table must be queried, and what the type of the value must be.
 #[trace]
 pub fn find_search_index_for_key(entity: &str, key: &str) -> Option<IndexedKeyType> {
+    if let Some(index_type) = common::find_search_index_for_key(key) {
+        return Some(index_type);
+    }
+
     match entity {
         "Patient" => patient::find_search_index_for_key(key),
+        "Observation" => observation::find_search_index_for_key(key),
+        "Provenance" => provenance::find_search_index_for_key(key),
+        entity if canonical::is_canonical(entity) => canonical::find_search_index_for_key(key),
         _ => None,
     }
 }
@@ -0,0 +1,165 @@
+//! Gathering of indexable values for the `Observation` entity.
+
+use std::collections::HashMap;
+
+use pgrx::datum::Date;
+use serde_json::Value;
+
+use crate::{
+    index::{
+        parse_date, reference_values, token_quantity_values, token_values, CompositeValue,
+        IndexedKeyType,
+    },
+    models::Observation,
+};
+
+pub fn find_search_index_for_key(key: &str) -> Option<IndexedKeyType> {
+    Some(match key {
+        "code" | "status" => IndexedKeyType::Text,
+        "date" => IndexedKeyType::Date,
+        "subject" | "patient" | "encounter" | "performer" | "has-member" | "derived-from" => {
+            IndexedKeyType::Reference
+        }
+        "code-value-quantity" | "component-code-value-quantity" | "combo-code-value-quantity" => {
+            IndexedKeyType::Composite
+        }
+        _ => return None,
+    })
+}
+
+pub fn date_index_values_for(data: &Value) -> HashMap<&'static str, Vec<Date>> {
+    let mut keys = HashMap::new();
+
+    let Ok(observation) = serde_json::from_value::<Observation>(data.clone()) else {
+        return keys;
+    };
+
+    if let Some(date) = observation
+        .effective_date_time
+        .and_then(|v| parse_date("date", &v))
+    {
+        keys.insert("date", vec![date]);
+    }
+
+    keys
+}
+
+pub fn text_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>> {
+    let mut keys = HashMap::new();
+
+    let Ok(observation) = serde_json::from_value::<Observation>(data.clone()) else {
+        return keys;
+    };
+
+    if let Some(v) = observation.status {
+        keys.insert("status", vec![v]);
+    }
+
+    let codes = token_values(
+        observation
+            .code
+            .iter()
+            .flat_map(|c| c.coding.iter().flatten()),
+    );
+    if !codes.is_empty() {
+        keys.insert("code", codes);
+    }
+
+    keys
+}
+
+pub fn reference_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>> {
+    let mut keys = HashMap::new();
+
+    let Ok(observation) = serde_json::from_value::<Observation>(data.clone()) else {
+        return keys;
+    };
+
+    let subject = reference_values(&observation.subject);
+    let patient = subject
+        .iter()
+        .filter(|r| r.starts_with("Patient/"))
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let values = [
+        ("subject", subject),
+        ("patient", patient),
+        ("encounter", reference_values(&observation.encounter)),
+        (
+            "performer",
+            reference_values(observation.performer.iter().flatten()),
+        ),
+        (
+            "has-member",
+            reference_values(observation.has_member.iter().flatten()),
+        ),
+        (
+            "derived-from",
+            reference_values(observation.derived_from.iter().flatten()),
+        ),
+    ];
+
+    for (key, references) in values {
+        if !references.is_empty() {
+            keys.insert(key, references);
+        }
+    }
+
+    keys
+}
+
+/// Collects the values of the `code-value-quantity` composite parameters.
+///
+/// Every value pairs a code with the quantity of the same observation or component,
+/// so a search only matches if the code and the value are part of the same component.
+pub fn composite_index_values_for(data: &Value) -> HashMap<&'static str, Vec<CompositeValue>> {
+    let mut keys = HashMap::new();
+
+    let Ok(observation) = serde_json::from_value::<Observation>(data.clone()) else {
+        return keys;
+    };
+
+    let values = token_quantity_values(
+        observation
+            .code
+            .iter()
+            .flat_map(|c| c.coding.iter().flatten()),
+        observation.value_quantity.as_ref(),
+    );
+
+    let component_values = observation
+        .component
+        .iter()
+        .flatten()
+        .flat_map(|component| {
+            token_quantity_values(
+                component
+                    .code
+                    .iter()
+                    .flat_map(|c| c.coding.iter().flatten()),
+                component.value_quantity.as_ref(),
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let combo_values = values
+        .iter()
+        .chain(&component_values)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let values = [
+        ("code-value-quantity", values),
+        ("component-code-value-quantity", component_values),
+        ("combo-code-value-quantity", combo_values),
+    ];
+
+    for (key, values) in values {
+        if !values.is_empty() {
+            keys.insert(key, values);
+        }
+    }
+
+    keys
+}
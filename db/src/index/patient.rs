@@ -5,12 +5,16 @@ use std::{collections::HashMap, str::FromStr as _};
 use pgrx::{datum::Date, warning};
 use serde_json::Value;
 
-use crate::{index::IndexedKeyType, models::Patient};
+use crate::{
+    index::{reference_values, IndexedKeyType},
+    models::Patient,
+};
 
 pub fn find_search_index_for_key(key: &str) -> Option<IndexedKeyType> {
     Some(match key {
         "birth_date" => IndexedKeyType::Date,
-        "gender" | "name" => IndexedKeyType::Text,
+        "gender" | "name" | "family" | "given" => IndexedKeyType::Text,
+        "link" => IndexedKeyType::Reference,
         _ => return None,
     })
 }
@@ -18,7 +22,9 @@ pub fn find_search_index_for_key(key: &str) -> Option<IndexedKeyType> {
 pub fn date_index_values_for(data: &Value) -> HashMap<&'static str, Vec<Date>> {
     let mut keys = HashMap::new();
 
-    let patient: Patient = serde_json::from_value(data.clone()).expect("invalid patient data");
+    let Ok(patient) = serde_json::from_value::<Patient>(data.clone()) else {
+        return keys;
+    };
 
     if let Some(v) = patient.birth_date {
         if let Ok(date) = Date::from_str(v.as_str()) {
@@ -34,7 +40,9 @@ pub fn date_index_values_for(data: &Value) -> HashMap<&'static str, Vec<Date>> {
 pub fn text_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>> {
     let mut keys = HashMap::new();
 
-    let patient: Patient = serde_json::from_value(data.clone()).expect("invalid patient data");
+    let Ok(patient) = serde_json::from_value::<Patient>(data.clone()) else {
+        return keys;
+    };
 
     if let Some(v) = patient.gender {
         keys.insert("gender", vec![v.to_string()]);
@@ -42,7 +50,12 @@ pub fn text_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>>
 
     // re-construct the full name of the patient
     let mut full_name = String::new();
+    let mut family = Vec::new();
+    let mut given = Vec::new();
     for name in patient.name.unwrap_or_default() {
+        family.extend(name.family.iter().map(|v| v.to_lowercase()));
+        given.extend(name.given.iter().flatten().map(|v| v.to_lowercase()));
+
         let parts = name
             .prefix
             .iter()
@@ -61,5 +74,34 @@ pub fn text_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>>
         keys.insert("name", vec![full_name.trim().to_lowercase()]);
     }
 
+    if !family.is_empty() {
+        keys.insert("family", family);
+    }
+
+    if !given.is_empty() {
+        keys.insert("given", given);
+    }
+
+    keys
+}
+
+pub fn reference_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>> {
+    let mut keys = HashMap::new();
+
+    let Ok(patient) = serde_json::from_value::<Patient>(data.clone()) else {
+        return keys;
+    };
+
+    let link = reference_values(
+        patient
+            .link
+            .iter()
+            .flatten()
+            .filter_map(|link| link.other.as_ref()),
+    );
+    if !link.is_empty() {
+        keys.insert("link", link);
+    }
+
     keys
 }
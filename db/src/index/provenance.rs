@@ -0,0 +1,63 @@
+//! Gathering of indexable values for the `Provenance` entity.
+
+use std::collections::HashMap;
+
+use pgrx::datum::Date;
+use serde_json::Value;
+
+use crate::{
+    index::{parse_date, reference_values, IndexedKeyType},
+    models::Provenance,
+};
+
+pub fn find_search_index_for_key(key: &str) -> Option<IndexedKeyType> {
+    Some(match key {
+        "recorded" => IndexedKeyType::Date,
+        "target" | "patient" | "agent" => IndexedKeyType::Reference,
+        _ => return None,
+    })
+}
+
+pub fn date_index_values_for(data: &Value) -> HashMap<&'static str, Vec<Date>> {
+    let mut keys = HashMap::new();
+
+    let Ok(provenance) = serde_json::from_value::<Provenance>(data.clone()) else {
+        return keys;
+    };
+
+    if let Some(date) = provenance.recorded.and_then(|v| parse_date("recorded", &v)) {
+        keys.insert("recorded", vec![date]);
+    }
+
+    keys
+}
+
+pub fn reference_index_values_for(data: &Value) -> HashMap<&'static str, Vec<String>> {
+    let mut keys = HashMap::new();
+
+    let Ok(provenance) = serde_json::from_value::<Provenance>(data.clone()) else {
+        return keys;
+    };
+
+    let target = reference_values(provenance.target.iter().flatten());
+    let patient = target
+        .iter()
+        .filter(|r| r.starts_with("Patient/"))
+        .cloned()
+        .collect::<Vec<_>>();
+    let agent = reference_values(
+        provenance
+            .agent
+            .iter()
+            .flatten()
+            .filter_map(|a| a.who.as_ref()),
+    );
+
+    for (key, references) in [("target", target), ("patient", patient), ("agent", agent)] {
+        if !references.is_empty() {
+            keys.insert(key, references);
+        }
+    }
+
+    keys
+}
@@ -1,9 +1,13 @@
 #![deny(clippy::pedantic)]
 #![allow(clippy::needless_pass_by_value)] // pgrx only allows value passing
 
-use fastrace::{collector::Config, prelude::*};
+use std::time::Instant;
+
+use fastrace::prelude::*;
 use pgrx::prelude::*;
 
+use crate::fhir::version::FhirVersion;
+
 mod api;
 mod fhir;
 mod gucs;
@@ -11,8 +15,10 @@ mod hooks;
 mod index;
 mod macros;
 mod models;
+mod reporter;
 mod schema;
 mod spi;
+mod terminology;
 
 ::pgrx::pg_module_magic!(name, version);
 
@@ -26,34 +32,25 @@ extension_sql!(
 #[pg_guard]
 pub unsafe extern "C-unwind" fn _PG_init() {
     gucs::init();
+    hooks::register_hooks();
 
-    let jaeger_enabled = gucs::JAEGER_ENABLED
-        .get()
-        .is_some_and(|s| s.to_str().unwrap() == "true");
-    let jaeger_host = gucs::JAEGER_HOST.get();
-
-    if jaeger_enabled {
-        if let Some(host) = jaeger_host {
-            // FIXME: do not crash the whole database if Jeager host is invalid
-
-            let host = host.to_str().unwrap();
-            let reporter =
-                fastrace_jaeger::JaegerReporter::new(host.parse().unwrap(), "fhir_extension")
-                    .unwrap();
-
-            fastrace::set_reporter(reporter, Config::default());
+    if pg_sys::process_shared_preload_libraries_in_progress {
+        // In the postmaster, the `fhir.version` of the databases is not known yet, so
+        // all versions are compiled. The backends inherit them when they are forked.
+        // Traces can't be exported from here, so only the duration is logged.
+        let start = Instant::now();
+        fhir::warm_up(&FhirVersion::bundled());
+        log!(
+            "compiled the bundled FHIR definitions in {:?}",
+            start.elapsed()
+        );
+    } else {
+        reporter::ensure_started();
 
-            info!("starting tracing export to Jaeger {host}");
-        } else {
-            warning!("fhir.jaeger_enabled is set, but fhir.jaeger_host is not set");
-        }
+        let root_span = Span::root("extension-init", SpanContext::random());
+        let _guard = root_span.set_local_parent();
+        fhir::warm_up(&[FhirVersion::current()]);
     }
-
-    let root_span = Span::root("extension-init", SpanContext::random());
-    let _guard = root_span.set_local_parent();
-
-    hooks::register_hooks();
-    fhir::compile_schema();
 }
 
 #[cfg(test)]
@@ -62,7 +59,7 @@ pub mod pg_test {
 
     #[must_use]
     pub fn postgresql_conf_options() -> Vec<&'static str> {
-        vec![]
+        vec!["shared_preload_libraries = 'fhir'"]
     }
 }
 
@@ -107,6 +104,87 @@ mod tests {
         assert_eq!(history.0, got_data.0);
     }
 
+    #[pg_test]
+    fn fhir_get_summary() {
+        let id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()]).unwrap();
+
+        let summary =
+            Spi::get_one_with_args::<JsonB>("SELECT fhir_get('Patient', $1, 'true')", &[id.into()])
+                .unwrap()
+                .unwrap();
+        assert!(summary.0.get("name").is_some());
+        assert!(summary.0.get("language").is_none());
+        assert_eq!(summary.0["meta"]["tag"][0]["code"], "SUBSETTED");
+
+        let elements = Spi::get_one_with_args::<JsonB>(
+            "SELECT fhir_get('Patient', $1, elements => 'gender')",
+            &[id.into()],
+        )
+        .unwrap()
+        .unwrap();
+        assert!(elements.0.get("gender").is_some());
+        assert!(elements.0.get("name").is_none());
+    }
+
+    #[pg_test]
+    fn fhir_get_summary_already_subsetted() {
+        let mut data = patient();
+        data.0["meta"]["tag"] = serde_json::json!([{
+            "system": "http://terminology.hl7.org/CodeSystem/v3-ObservationValue",
+            "code": "SUBSETTED",
+        }]);
+        let id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[data.into()]).unwrap();
+
+        let summary =
+            Spi::get_one_with_args::<JsonB>("SELECT fhir_get('Patient', $1, 'true')", &[id.into()])
+                .unwrap()
+                .unwrap();
+        assert_eq!(summary.0["meta"]["tag"].as_array().unwrap().len(), 1);
+    }
+
+    #[pg_test(error = "the summary elements of resource type 'Practitioner' are not known")]
+    fn fhir_get_summary_unknown_type() {
+        let practitioner = JsonB(serde_json::json!({
+            "resourceType": "Practitioner",
+            "name": [{ "family": "House" }],
+        }));
+        let id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[practitioner.into()]).unwrap();
+
+        Spi::run_with_args("SELECT fhir_get('Practitioner', $1, 'true')", &[id.into()]).unwrap();
+    }
+
+    #[pg_test]
+    fn fhir_get_summary_unknown_type_sqlstate() {
+        Spi::run(
+            r#"
+            CREATE FUNCTION pg_temp.summary_sqlstate("id" UUID) RETURNS TEXT
+            LANGUAGE plpgsql AS $$
+            BEGIN
+                PERFORM fhir_get('Practitioner', "id", 'true');
+                RETURN NULL;
+            EXCEPTION WHEN OTHERS THEN
+                RETURN SQLSTATE;
+            END
+            $$;
+            "#,
+        )
+        .unwrap();
+
+        let practitioner = JsonB(serde_json::json!({
+            "resourceType": "Practitioner",
+            "name": [{ "family": "House" }],
+        }));
+        let id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[practitioner.into()]).unwrap();
+
+        let sqlstate =
+            Spi::get_one_with_args::<String>("SELECT pg_temp.summary_sqlstate($1)", &[id.into()])
+                .unwrap();
+        assert_eq!(sqlstate.as_deref(), Some("22023"));
+    }
+
     #[pg_test(error = "the given entity does not have a 'resourceType'")]
     fn insert_without_resource_type() {
         let mut data = patient();
@@ -142,4 +220,1049 @@ mod tests {
         .unwrap()
         .unwrap();
     }
+
+    #[pg_test]
+    fn fhir_search_common_params() {
+        let id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()]).unwrap();
+
+        let found = Spi::get_one_with_args::<Uuid>(
+            "SELECT id FROM fhir_search('Patient', '_id', '=', $1::text)",
+            &[id.unwrap().to_string().into()],
+        )
+        .unwrap();
+        assert_eq!(found, id);
+
+        let found = Spi::get_one::<Uuid>(
+            "SELECT id FROM fhir_search('Patient', '_profile', '=', 'http://hl7.org/fhir/uv/ips/StructureDefinition/Patient-uv-ips')",
+        )
+        .unwrap();
+        assert_eq!(found, id);
+
+        let found = Spi::get_one::<Uuid>(
+            "SELECT id FROM fhir_search('Patient', '_lastUpdated', '>=', current_date)",
+        )
+        .unwrap();
+        assert_eq!(found, id);
+    }
+
+    #[pg_test]
+    fn fhir_search_system() {
+        let patient_id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()])
+            .unwrap()
+            .unwrap();
+        Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[observation(patient_id).into()])
+            .unwrap();
+
+        let total = Spi::get_one::<i64>(
+            "SELECT fhir_search_system_total(NULL, '_lastUpdated', '>=', current_date)",
+        )
+        .unwrap();
+        assert_eq!(total, Some(2));
+
+        let resource_type = Spi::get_one::<String>(
+            "SELECT resource_type FROM fhir_search_system(ARRAY['Patient'], '_lastUpdated', '>=', current_date)",
+        )
+        .unwrap();
+        assert_eq!(resource_type.as_deref(), Some("Patient"));
+    }
+
+    #[pg_test(error = "unknown search key: 'gender'")]
+    fn fhir_search_system_unknown_key() {
+        Spi::run("SELECT * FROM fhir_search_system(NULL, 'gender', '=', 'female')").unwrap();
+    }
+
+    #[pg_test]
+    fn fhir_search_composite() {
+        let data = JsonB(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "85354-9" }] },
+            "component": [
+                {
+                    "code": { "coding": [{ "system": "http://loinc.org", "code": "8480-6" }] },
+                    "valueQuantity": { "value": 150, "unit": "mmHg" },
+                },
+                {
+                    "code": { "coding": [{ "system": "http://loinc.org", "code": "8462-4" }] },
+                    "valueQuantity": { "value": 90, "unit": "mmHg" },
+                },
+            ],
+        }));
+        let id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[data.into()]).unwrap();
+
+        let found = Spi::get_one::<Uuid>(
+            "SELECT id FROM fhir_search('Observation', 'component-code-value-quantity', '=', 'http://loinc.org|8480-6$gt140')",
+        )
+        .unwrap();
+        assert_eq!(found, id);
+
+        // The diastolic pressure is not above 140, even though the systolic one is.
+        let count = Spi::get_one::<i64>(
+            "SELECT count(*) FROM fhir_search('Observation', 'component-code-value-quantity', '=', '8462-4$gt140')",
+        )
+        .unwrap();
+        assert_eq!(count, Some(0));
+    }
+
+    #[pg_test]
+    fn fhir_search_uri() {
+        let data = JsonB(serde_json::json!({
+            "resourceType": "ValueSet",
+            "url": "http://example.org/fhir/ValueSet/blood-pressure",
+            "status": "active",
+        }));
+        let id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[data.into()]).unwrap();
+
+        let found = Spi::get_one::<Uuid>(
+            "SELECT id FROM fhir_search('ValueSet', 'url', '=', 'http://example.org/fhir/ValueSet/blood-pressure')",
+        )
+        .unwrap();
+        assert_eq!(found, id);
+
+        let found = Spi::get_one::<Uuid>(
+            "SELECT id FROM fhir_search('ValueSet', 'url:below', '=', 'http://example.org/fhir/')",
+        )
+        .unwrap();
+        assert_eq!(found, id);
+
+        let found = Spi::get_one::<Uuid>(
+            "SELECT id FROM fhir_search('ValueSet', 'url:above', '=', 'http://example.org/fhir/ValueSet/blood-pressure/_history/1')",
+        )
+        .unwrap();
+        assert_eq!(found, id);
+
+        let count = Spi::get_one::<i64>(
+            "SELECT count(*) FROM fhir_search('ValueSet', 'url:below', '=', 'http://example.org/fhir/Value')",
+        )
+        .unwrap();
+        assert_eq!(count, Some(0));
+    }
+
+    #[pg_test]
+    fn fhir_search_compartment() {
+        let patient_id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()])
+            .unwrap()
+            .unwrap();
+        let other_id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()])
+            .unwrap()
+            .unwrap();
+        let observation_id = Spi::get_one_with_args::<Uuid>(
+            "SELECT fhir_put($1)",
+            &[observation(patient_id).into()],
+        )
+        .unwrap();
+        Spi::run_with_args("SELECT fhir_put($1)", &[observation(other_id).into()]).unwrap();
+
+        let found = Spi::get_one_with_args::<Uuid>(
+            "SELECT id FROM fhir_search('Patient', $1, 'Observation', 'status', '=', 'final')",
+            &[patient_id.into()],
+        )
+        .unwrap();
+        assert_eq!(found, observation_id);
+
+        let total = Spi::get_one_with_args::<i64>(
+            "SELECT fhir_search_total('Patient', $1, 'Patient', 'gender', '=', 'female')",
+            &[patient_id.into()],
+        )
+        .unwrap();
+        assert_eq!(total, Some(1));
+    }
+
+    #[pg_test(error = "resource type 'ValueSet' is not part of the 'Patient' compartment")]
+    fn fhir_search_compartment_unknown_type() {
+        Spi::run(
+            "SELECT * FROM fhir_search('Patient', gen_random_uuid(), 'ValueSet', 'url', '=', 'http://example.org')",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn fhir_patient_everything() {
+        let practitioner = JsonB(serde_json::json!({
+            "resourceType": "Practitioner",
+            "name": [{ "family": "House" }],
+        }));
+        let practitioner_id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[practitioner.into()])
+                .unwrap()
+                .unwrap();
+        let patient_id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()])
+            .unwrap()
+            .unwrap();
+
+        let mut data = observation(patient_id);
+        data.0["performer"] =
+            serde_json::json!([{ "reference": format!("Practitioner/{practitioner_id}") }]);
+        Spi::run_with_args("SELECT fhir_put($1)", &[data.into()]).unwrap();
+
+        let modes = Spi::get_one_with_args::<Vec<String>>(
+            "SELECT array_agg(resource_type || ':' || mode ORDER BY resource_type) FROM fhir_patient_everything($1)",
+            &[patient_id.into()],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(
+            modes,
+            ["Observation:match", "Patient:match", "Practitioner:include"]
+        );
+
+        let count = Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM fhir_patient_everything($1, types => ARRAY['Observation'])",
+            &[patient_id.into()],
+        )
+        .unwrap();
+        assert_eq!(count, Some(1));
+
+        let last = Spi::get_one_with_args::<String>(
+            "SELECT cursor FROM fhir_patient_everything($1) ORDER BY idx DESC LIMIT 1",
+            &[patient_id.into()],
+        )
+        .unwrap();
+        let before = Spi::get_one_with_args::<Vec<String>>(
+            "SELECT array_agg(resource_type ORDER BY idx) FROM fhir_patient_everything($1, count => 2, cursor => $2, backwards => true)",
+            &[patient_id.into(), last.into()],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(before.len(), 2);
+    }
+
+    #[pg_test]
+    fn fhir_compartment_unindexed_types() {
+        let types =
+            Spi::get_one::<Vec<String>>("SELECT fhir_compartment_unindexed_types('Patient')")
+                .unwrap()
+                .unwrap();
+        assert!(types.contains(&"Condition".to_string()));
+        assert!(!types.contains(&"Observation".to_string()));
+        assert!(!types.contains(&"Patient".to_string()));
+    }
+
+    #[pg_test]
+    fn fhir_validate() {
+        let outcome = Spi::get_one_with_args::<JsonB>(
+            "SELECT fhir_validate('Patient', $1)",
+            &[patient().into()],
+        )
+        .unwrap()
+        .unwrap();
+        assert!(errors(&outcome).is_empty());
+
+        let mut data = patient();
+        data.0["gender"] = serde_json::json!(42);
+        data.0["name"][0]["given"] = serde_json::json!("Marie");
+        let outcome =
+            Spi::get_one_with_args::<JsonB>("SELECT fhir_validate('Patient', $1)", &[data.into()])
+                .unwrap()
+                .unwrap();
+        assert_eq!(outcome.0["resourceType"], "OperationOutcome");
+
+        let mut locations = errors(&outcome)
+            .into_iter()
+            .map(|issue| issue["location"][0].as_str().unwrap().to_string())
+            .collect::<Vec<_>>();
+        locations.sort();
+        locations.dedup();
+        assert_eq!(locations, ["/gender", "/name/0/given"]);
+    }
+
+    #[pg_test]
+    fn fhir_validate_profile() {
+        Spi::run_with_args("SELECT fhir_put($1)", &[male_patient_profile().into()]).unwrap();
+
+        let outcome = Spi::get_one_with_args::<JsonB>(
+            "SELECT fhir_validate('Patient', $1, 'http://example.org/StructureDefinition/male-patient')",
+            &[patient().into()],
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(errors(&outcome)[0]["location"][0], "/gender");
+
+        let outcome = Spi::get_one_with_args::<JsonB>(
+            "SELECT fhir_validate('Patient', $1, 'http://example.org/StructureDefinition/unknown')",
+            &[patient().into()],
+        )
+        .unwrap()
+        .unwrap();
+        assert!(errors(&outcome).is_empty());
+        assert!(outcome.0["issue"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|issue| issue["code"] == "not-found"));
+    }
+
+    #[pg_test]
+    fn fhir_validate_invariants() {
+        let mut data = patient();
+        data.0["contact"] = serde_json::json!([{ "gender": "male" }]);
+        let outcome =
+            Spi::get_one_with_args::<JsonB>("SELECT fhir_validate('Patient', $1)", &[data.into()])
+                .unwrap()
+                .unwrap();
+
+        let errors = errors(&outcome);
+        assert_eq!(errors.len(), 1);
+        assert_eq!(errors[0]["code"], "invariant");
+        assert_eq!(errors[0]["location"][0], "/contact/0");
+        assert!(errors[0]["diagnostics"]
+            .as_str()
+            .unwrap()
+            .starts_with("pat-1: "));
+
+        // The missing narrative is only a warning.
+        assert!(outcome.0["issue"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .any(|issue| issue["severity"] == "warning"
+                && issue["diagnostics"]
+                    .as_str()
+                    .unwrap()
+                    .starts_with("dom-6: ")));
+    }
+
+    #[pg_test(
+        error = "the given entity is invalid: org-1: The organization SHALL at least have a name or an identifier, and possibly more than one"
+    )]
+    fn insert_organization_invariant() {
+        let organization = JsonB(serde_json::json!({
+            "resourceType": "Organization",
+            "active": true,
+        }));
+        Spi::run_with_args("SELECT fhir_put($1)", &[organization.into()]).unwrap();
+    }
+
+    #[pg_test(error = "the given entity is invalid: /gender: value is not of type \"string\"")]
+    fn insert_invalid_patient() {
+        let mut data = patient();
+        data.0["gender"] = serde_json::json!(42);
+        Spi::run_with_args("SELECT fhir_put($1)", &[data.into()]).unwrap();
+    }
+
+    #[pg_test]
+    fn insert_invalid_patient_outcome() {
+        Spi::run(
+            r#"
+            CREATE FUNCTION pg_temp.rejection("entity" JSONB) RETURNS JSONB
+            LANGUAGE plpgsql AS $$
+            DECLARE
+                detail TEXT;
+            BEGIN
+                PERFORM fhir_put("entity");
+                RETURN NULL;
+            EXCEPTION WHEN check_violation THEN
+                GET STACKED DIAGNOSTICS detail = PG_EXCEPTION_DETAIL;
+                RETURN detail::jsonb;
+            END
+            $$;
+            "#,
+        )
+        .unwrap();
+
+        let mut data = patient();
+        data.0["gender"] = serde_json::json!(42);
+        let outcome =
+            Spi::get_one_with_args::<JsonB>("SELECT pg_temp.rejection($1)", &[data.into()])
+                .unwrap()
+                .unwrap();
+
+        assert_eq!(outcome.0["resourceType"], "OperationOutcome");
+        assert_eq!(outcome.0["issue"][0]["severity"], "error");
+        assert_eq!(outcome.0["issue"][0]["location"][0], "/gender");
+    }
+
+    #[pg_test]
+    fn validation_mode_warn() {
+        Spi::run("SET fhir.validation_mode = 'warn'").unwrap();
+
+        let mut data = patient();
+        data.0["gender"] = serde_json::json!(42);
+        let id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[data.into()])
+            .unwrap()
+            .unwrap();
+
+        let location = Spi::get_one_with_args::<String>(
+            "SELECT location FROM fhir.validation_issue WHERE entity_id = $1",
+            &[id.into()],
+        )
+        .unwrap();
+        assert_eq!(location.as_deref(), Some("/gender"));
+    }
+
+    #[pg_test]
+    fn validation_mode_off_skips_index() {
+        Spi::run("SET fhir.validation_mode = 'off'").unwrap();
+
+        let data = JsonB(serde_json::json!({
+            "resourceType": "Observation",
+            "meta": { "tag": "not-a-list" },
+            "status": 42,
+            "subject": "Patient/123",
+        }));
+        let id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[data.into()])
+            .unwrap()
+            .unwrap();
+
+        let count = Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM fhir.entity_index_text WHERE entity_id = $1",
+            &[id.into()],
+        )
+        .unwrap();
+        assert_eq!(count, Some(0));
+    }
+
+    #[pg_test]
+    fn validation_mode_overrides() {
+        Spi::run("SET fhir.validation_mode_overrides = 'Patient=off, Observation=warn'").unwrap();
+
+        let mode = Spi::get_one::<String>("SELECT fhir_validation_mode('Observation')").unwrap();
+        assert_eq!(mode.as_deref(), Some("warn"));
+        let mode = Spi::get_one::<String>("SELECT fhir_validation_mode('Provenance')").unwrap();
+        assert_eq!(mode.as_deref(), Some("strict"));
+
+        let mut data = patient();
+        data.0["gender"] = serde_json::json!(42);
+        Spi::run_with_args("SELECT fhir_put($1)", &[data.into()]).unwrap();
+
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM fhir.validation_issue").unwrap();
+        assert_eq!(count, Some(0));
+    }
+
+    #[pg_test(
+        error = "invalid value for parameter \"fhir.validation_mode_overrides\": \"Patient=lenient\""
+    )]
+    fn validation_mode_invalid_override() {
+        Spi::run("SET fhir.validation_mode_overrides = 'Patient=lenient'").unwrap();
+    }
+
+    #[pg_test]
+    fn fhir_version() {
+        let version = Spi::get_one::<String>("SELECT fhir_version()").unwrap();
+        assert_eq!(version.as_deref(), Some("4.3.0"));
+    }
+
+    #[pg_test]
+    fn fhir_bundled_versions() {
+        let versions = Spi::get_one::<Vec<String>>(
+            "SELECT array_agg(name ORDER BY name) FROM fhir_bundled_versions()",
+        )
+        .unwrap()
+        .unwrap();
+
+        let mut expected = vec!["R4B"];
+        if cfg!(feature = "r4") {
+            expected.insert(0, "R4");
+        }
+        if cfg!(feature = "r5") {
+            expected.push("R5");
+        }
+        assert_eq!(versions, expected);
+    }
+
+    #[cfg(feature = "r4")]
+    #[pg_test]
+    fn fhir_version_r4() {
+        Spi::run("SET fhir.version = 'R4'").unwrap();
+
+        let version = Spi::get_one::<String>("SELECT fhir_version()").unwrap();
+        assert_eq!(version.as_deref(), Some("4.0.1"));
+        Spi::run_with_args("SELECT fhir_put($1)", &[patient().into()]).unwrap();
+    }
+
+    #[cfg(feature = "r5")]
+    #[pg_test]
+    fn fhir_version_r5() {
+        Spi::run("SET fhir.version = 'R5'").unwrap();
+
+        let version = Spi::get_one::<String>("SELECT fhir_version()").unwrap();
+        assert_eq!(version.as_deref(), Some("5.0.0"));
+        Spi::run_with_args("SELECT fhir_put($1)", &[patient().into()]).unwrap();
+    }
+
+    #[cfg(not(feature = "r5"))]
+    #[pg_test(error = "invalid value for parameter \"fhir.version\": \"R5\"")]
+    fn fhir_version_not_bundled() {
+        Spi::run("SET fhir.version = 'R5'").unwrap();
+    }
+
+    /// Returns the issues of an `OperationOutcome` with the severity `error`.
+    fn errors(outcome: &JsonB) -> Vec<&serde_json::Value> {
+        outcome.0["issue"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .filter(|issue| issue["severity"] == "error")
+            .collect()
+    }
+
+    #[pg_test(
+        error = "the given entity is invalid: /gender: 'Patient.gender' must have the fixed value \"male\" (profile 'http://example.org/StructureDefinition/male-patient')"
+    )]
+    fn insert_with_invalid_profile() {
+        Spi::run_with_args("SELECT fhir_put($1)", &[male_patient_profile().into()]).unwrap();
+        Spi::run("SET fhir.validate_profiles = on").unwrap();
+
+        let mut data = patient();
+        data.0["meta"]["profile"] =
+            serde_json::json!(["http://example.org/StructureDefinition/male-patient"]);
+        Spi::run_with_args("SELECT fhir_put($1)", &[data.into()]).unwrap();
+    }
+
+    #[pg_test(
+        error = "resource type 'Condition' can't be searched in the 'Patient' compartment, because its 'patient' parameter is not indexed"
+    )]
+    fn fhir_search_compartment_not_indexed() {
+        Spi::run(
+            "SELECT * FROM fhir_search('Patient', gen_random_uuid(), 'Condition', '_id', '=', gen_random_uuid()::text)",
+        )
+        .unwrap();
+    }
+
+    #[pg_test]
+    fn fhir_transaction() {
+        let patient_url = "urn:uuid:61ebe359-bfdc-4613-8bf2-c5e300945f0a";
+        let mut observation = observation(Uuid::from_bytes([0; 16]));
+        observation.0["subject"] = serde_json::json!({ "reference": patient_url });
+
+        let bundle = JsonB(serde_json::json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [
+                {
+                    "fullUrl": patient_url,
+                    "resource": patient().0,
+                    "request": { "method": "POST", "url": "Patient" },
+                },
+                {
+                    "resource": observation.0,
+                    "request": { "method": "POST", "url": "Observation" },
+                },
+            ],
+        }));
+        let response =
+            Spi::get_one_with_args::<JsonB>("SELECT fhir_transaction($1)", &[bundle.into()])
+                .unwrap()
+                .unwrap();
+        assert_eq!(response.0["type"], "transaction-response");
+        assert_eq!(response.0["entry"][1]["response"]["status"], "201 Created");
+
+        let location = response.0["entry"][0]["response"]["location"]
+            .as_str()
+            .unwrap();
+        let count = Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM fhir_search('Patient', split_part($1, '/', 2)::uuid, 'Observation', 'status', '=', 'final')",
+            &[location.into()],
+        )
+        .unwrap();
+        assert_eq!(count, Some(1));
+    }
+
+    #[pg_test]
+    fn fhir_transaction_invalid_entry() {
+        let bundle = JsonB(serde_json::json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [
+                {
+                    "resource": patient().0,
+                    "request": { "method": "POST", "url": "Patient" },
+                },
+                {
+                    "resource": { "resourceType": "Patient", "active": "yes" },
+                    "request": { "method": "POST", "url": "Patient" },
+                },
+            ],
+        }));
+        let response =
+            Spi::get_one_with_args::<JsonB>("SELECT fhir_transaction($1)", &[bundle.into()])
+                .unwrap()
+                .unwrap();
+        assert_eq!(response.0["resourceType"], "OperationOutcome");
+
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM fhir.entity").unwrap();
+        assert_eq!(count, Some(0));
+    }
+
+    #[pg_test]
+    fn fhir_transaction_conditional_create() {
+        let bundle = JsonB(serde_json::json!({
+            "resourceType": "Bundle",
+            "type": "transaction",
+            "entry": [{
+                "resource": patient().0,
+                "request": {
+                    "method": "POST",
+                    "url": "Patient",
+                    "ifNoneExist": "identifier=http://example.org|123",
+                },
+            }],
+        }));
+        let response =
+            Spi::get_one_with_args::<JsonB>("SELECT fhir_transaction($1)", &[bundle.into()])
+                .unwrap()
+                .unwrap();
+        assert_eq!(response.0["resourceType"], "OperationOutcome");
+        assert_eq!(response.0["issue"][0]["code"], "not-supported");
+
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM fhir.entity").unwrap();
+        assert_eq!(count, Some(0));
+    }
+
+    #[pg_test]
+    fn fhir_transaction_batch_isolation() {
+        Spi::run(
+            r"
+            CREATE FUNCTION reject_observation() RETURNS trigger AS $$
+            BEGIN
+                RAISE EXCEPTION 'observations are read-only';
+            END;
+            $$ LANGUAGE plpgsql;
+
+            CREATE TRIGGER reject_observation BEFORE INSERT ON fhir.entity
+            FOR EACH ROW WHEN (NEW.resource_type = 'Observation')
+            EXECUTE FUNCTION reject_observation();
+            ",
+        )
+        .unwrap();
+
+        let bundle = JsonB(serde_json::json!({
+            "resourceType": "Bundle",
+            "type": "batch",
+            "entry": [
+                {
+                    "resource": patient().0,
+                    "request": { "method": "POST", "url": "Patient" },
+                },
+                {
+                    "resource": observation(Uuid::from_bytes([0; 16])).0,
+                    "request": { "method": "POST", "url": "Observation" },
+                },
+                {
+                    "request": { "method": "DELETE", "url": "Patient/123" },
+                },
+            ],
+        }));
+        let response =
+            Spi::get_one_with_args::<JsonB>("SELECT fhir_transaction($1)", &[bundle.into()])
+                .unwrap()
+                .unwrap();
+        assert_eq!(response.0["type"], "batch-response");
+        assert_eq!(response.0["entry"][0]["response"]["status"], "201 Created");
+
+        let failed = &response.0["entry"][1]["response"];
+        assert_eq!(failed["status"], "500 Internal Server Error");
+        assert_eq!(
+            failed["outcome"]["issue"][0]["diagnostics"],
+            "observations are read-only"
+        );
+
+        let unsupported = &response.0["entry"][2]["response"];
+        assert_eq!(unsupported["status"], "400 Bad Request");
+        assert_eq!(unsupported["outcome"]["issue"][0]["code"], "not-supported");
+
+        let count = Spi::get_one::<i64>("SELECT count(*) FROM fhir.entity").unwrap();
+        assert_eq!(count, Some(1));
+    }
+
+    #[pg_test]
+    fn fhir_search_full_text() {
+        let mut data = patient();
+        data.0["text"] = serde_json::json!({
+            "status": "generated",
+            "div": "<div xmlns=\"http://www.w3.org/1999/xhtml\">Diagnosed with <b>diabetes</b></div>",
+        });
+        let id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[data.into()]).unwrap();
+
+        let found = Spi::get_one::<Uuid>(
+            "SELECT id FROM fhir_search('Patient', '_content', '=', 'marie', '-_content')",
+        )
+        .unwrap();
+        assert_eq!(found, id);
+
+        let found =
+            Spi::get_one::<Uuid>("SELECT id FROM fhir_search('Patient', '_text', '=', 'diabetes')")
+                .unwrap();
+        assert_eq!(found, id);
+    }
+
+    #[pg_test]
+    fn fhir_search_explain() {
+        let explain = Spi::get_one::<JsonB>(
+            "SELECT fhir_search_explain('Patient', 'gender', '=', 'female', 'family')",
+        )
+        .unwrap()
+        .unwrap();
+
+        assert!(explain.0["sql"]
+            .as_str()
+            .unwrap()
+            .contains("entity_index_text"));
+        assert!(explain.0["relations"]
+            .as_array()
+            .unwrap()
+            .contains(&"fhir.entity_index_text".into()));
+        assert!(explain.0["plan"][0]["Plan"].is_object());
+    }
+
+    #[pg_test]
+    fn fhir_search_sort() {
+        let first = patient();
+        let mut second = patient();
+        second.0["name"][0]["family"] = "Adler".into();
+
+        let first_id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[first.into()]).unwrap();
+        let second_id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[second.into()]).unwrap();
+
+        let sorted = |sort: &str| {
+            Spi::get_one_with_args::<Uuid>(
+                "SELECT id FROM fhir_search('Patient', 'gender', '=', 'female', $1) ORDER BY idx LIMIT 1",
+                &[sort.into()],
+            )
+            .unwrap()
+        };
+
+        assert_eq!(sorted("family"), second_id);
+        assert_eq!(sorted("-family"), first_id);
+    }
+
+    #[pg_test]
+    fn fhir_search_cursor() {
+        let first_id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()]).unwrap();
+        let second_id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()]).unwrap();
+
+        let (id, cursor) = Spi::get_two::<Uuid, String>(
+            "SELECT id, cursor FROM fhir_search('Patient', 'gender', '=', 'female', count => 1)",
+        )
+        .unwrap();
+        assert_eq!(id, first_id);
+
+        let id = Spi::get_one_with_args::<Uuid>(
+            "SELECT id FROM fhir_search('Patient', 'gender', '=', 'female', count => 1, cursor => $1)",
+            &[cursor.into()],
+        )
+        .unwrap();
+        assert_eq!(id, second_id);
+    }
+
+    #[pg_test]
+    fn fhir_search_cursor_sorted() {
+        let mut adler = patient();
+        adler.0["name"][0]["family"] = "Adler".into();
+        let mut zweig = patient();
+        zweig.0["name"][0]["family"] = "Zweig".into();
+        let mut unnamed = patient();
+        unnamed.0.as_object_mut().unwrap().remove("name");
+
+        let ids = [zweig, adler, unnamed].map(|patient| {
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient.into()])
+                .unwrap()
+                .unwrap()
+        });
+
+        // Pages of a single entity, sorted descending, with the missing value last.
+        let page = |cursor: Option<&str>, backwards: bool| {
+            Spi::get_two_with_args::<Uuid, String>(
+                "SELECT id, cursor FROM fhir_search('Patient', 'gender', '=', 'female', '-family', 1, $1, $2)",
+                &[cursor.into(), backwards.into()],
+            )
+            .unwrap()
+        };
+
+        let mut cursor = None;
+        for id in ids {
+            let (next_id, next_cursor) = page(cursor.as_deref(), false);
+            assert_eq!(next_id, Some(id));
+            cursor = next_cursor;
+        }
+        let rest = Spi::get_one_with_args::<i64>(
+            "SELECT count(*) FROM fhir_search('Patient', 'gender', '=', 'female', '-family', 1, $1)",
+            &[cursor.as_deref().into()],
+        )
+        .unwrap();
+        assert_eq!(rest, Some(0));
+
+        let (previous_id, _) = page(cursor.as_deref(), true);
+        assert_eq!(previous_id, Some(ids[1]));
+    }
+
+    fn male_patient_profile() -> JsonB {
+        JsonB(serde_json::json!({
+            "resourceType": "StructureDefinition",
+            "url": "http://example.org/StructureDefinition/male-patient",
+            "name": "MalePatient",
+            "status": "draft",
+            "kind": "resource",
+            "abstract": false,
+            "type": "Patient",
+            "snapshot": {
+                "element": [
+                    { "id": "Patient", "path": "Patient", "min": 0, "max": "*" },
+                    { "id": "Patient.name", "path": "Patient.name", "min": 1, "max": "*" },
+                    {
+                        "id": "Patient.gender",
+                        "path": "Patient.gender",
+                        "min": 1,
+                        "max": "1",
+                        "fixedCode": "male",
+                    },
+                ],
+            },
+        }))
+    }
+
+    fn observation(patient_id: Uuid) -> JsonB {
+        JsonB(serde_json::json!({
+            "resourceType": "Observation",
+            "status": "final",
+            "code": { "coding": [{ "system": "http://loinc.org", "code": "8867-4" }] },
+            "subject": { "reference": format!("Patient/{patient_id}") },
+        }))
+    }
+
+    #[pg_test]
+    fn fhir_search_include() {
+        let patient_id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()]).unwrap();
+        Spi::run_with_args(
+            "SELECT fhir_put($1)",
+            &[observation(patient_id.unwrap()).into()],
+        )
+        .unwrap();
+
+        let (id, mode) = Spi::get_two::<Uuid, String>(
+            "SELECT id, mode FROM fhir_search('Observation', 'status', '=', 'final', includes => ARRAY['_include=Observation:subject']) WHERE resource_type = 'Patient'",
+        )
+        .unwrap();
+        assert_eq!(id, patient_id);
+        assert_eq!(mode.as_deref(), Some("include"));
+    }
+
+    #[pg_test]
+    fn fhir_search_reference() {
+        let patient_id = Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()])
+            .unwrap()
+            .unwrap();
+        let observation_id = Spi::get_one_with_args::<Uuid>(
+            "SELECT fhir_put($1)",
+            &[observation(patient_id).into()],
+        )
+        .unwrap();
+
+        let id = Spi::get_one_with_args::<Uuid>(
+            "SELECT id FROM fhir_search('Observation', 'subject', '=', $1)",
+            &[format!("Patient/{patient_id}").into()],
+        )
+        .unwrap();
+        assert_eq!(id, observation_id);
+    }
+
+    #[pg_test(error = "invalid search value: 'yesterday'")]
+    fn fhir_search_invalid_date() {
+        Spi::run("SELECT fhir_search('Patient', 'birth_date', '=', 'yesterday')").unwrap();
+    }
+
+    #[pg_test]
+    fn fhir_search_chain() {
+        let patient_id =
+            Spi::get_one_with_args::<Uuid>("SELECT fhir_put($1)", &[patient().into()]).unwrap();
+        let observation_id = Spi::get_one_with_args::<Uuid>(
+            "SELECT fhir_put($1)",
+            &[observation(patient_id.unwrap()).into()],
+        )
+        .unwrap();
+
+        let id = Spi::get_one::<Uuid>(
+            "SELECT id FROM fhir_search('Observation', 'subject:Patient.family', '=', 'lux-brennard')",
+        )
+        .unwrap();
+        assert_eq!(id, observation_id);
+
+        let id = Spi::get_one::<Uuid>(
+            "SELECT id FROM fhir_search('Patient', '_has:Observation:subject:code', '=', 'http://loinc.org|8867-4')",
+        )
+        .unwrap();
+        assert_eq!(id, patient_id);
+    }
+
+    #[pg_test(error = "search keys can follow at most 0 references")]
+    fn fhir_search_chain_too_deep() {
+        Spi::run("SET fhir.search_max_chain_depth = 0").unwrap();
+        Spi::run("SELECT fhir_search('Observation', 'subject:Patient.family', '=', 'lux')")
+            .unwrap();
+    }
+
+    fn code_system() -> JsonB {
+        JsonB(serde_json::json!({
+            "resourceType": "CodeSystem",
+            "url": "http://example.org/CodeSystem/conditions",
+            "version": "1",
+            "name": "Conditions",
+            "status": "active",
+            "content": "complete",
+            "concept": [{
+                "code": "diabetes",
+                "display": "Diabetes mellitus",
+                "concept": [
+                    { "code": "type-1", "display": "Type 1 diabetes" },
+                    { "code": "type-2", "display": "Type 2 diabetes" },
+                ],
+            }, {
+                "code": "asthma",
+                "display": "Asthma",
+            }],
+        }))
+    }
+
+    #[pg_test]
+    fn fhir_code_lookup() {
+        Spi::run_with_args("SELECT fhir_put($1)", &[code_system().into()]).unwrap();
+
+        let lookup = Spi::get_one::<JsonB>(
+            "SELECT fhir_code_lookup('http://example.org/CodeSystem/conditions', 'type-1')",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(lookup.0["parameter"][0]["valueString"], "Conditions");
+        assert_eq!(lookup.0["parameter"][2]["valueString"], "Type 1 diabetes");
+        assert_eq!(lookup.0["parameter"][3]["part"][1]["valueCode"], "diabetes");
+
+        let unknown = Spi::get_one::<JsonB>(
+            "SELECT fhir_code_lookup('http://example.org/CodeSystem/conditions', 'flu')",
+        )
+        .unwrap();
+        assert!(unknown.is_none());
+    }
+
+    #[pg_test]
+    fn fhir_validate_code() {
+        Spi::run_with_args("SELECT fhir_put($1)", &[code_system().into()]).unwrap();
+        let valueset = serde_json::json!({
+            "resourceType": "ValueSet",
+            "url": "http://example.org/ValueSet/diabetes",
+            "status": "active",
+            "compose": {
+                "include": [{
+                    "system": "http://example.org/CodeSystem/conditions",
+                    "filter": [{ "property": "concept", "op": "is-a", "value": "diabetes" }],
+                }],
+                "exclude": [{
+                    "system": "http://example.org/CodeSystem/conditions",
+                    "concept": [{ "code": "type-2" }],
+                }],
+            },
+        });
+        Spi::run_with_args("SELECT fhir_put($1)", &[JsonB(valueset).into()]).unwrap();
+
+        let validate = |code: &str| {
+            Spi::get_one_with_args::<JsonB>(
+                "SELECT fhir_validate_code('http://example.org/ValueSet/diabetes', 'http://example.org/CodeSystem/conditions', $1)",
+                &[code.into()],
+            )
+            .unwrap()
+            .unwrap()
+            .0
+        };
+
+        let result = validate("type-1");
+        assert_eq!(result["parameter"][0]["valueBoolean"], true);
+        assert_eq!(result["parameter"][1]["valueString"], "Type 1 diabetes");
+        assert_eq!(validate("type-2")["parameter"][0]["valueBoolean"], false);
+        assert_eq!(validate("asthma")["parameter"][0]["valueBoolean"], false);
+
+        let unknown = Spi::get_one::<JsonB>(
+            "SELECT fhir_validate_code('http://example.org/ValueSet/unknown', 'http://example.org/CodeSystem/conditions', 'type-1')",
+        )
+        .unwrap();
+        assert!(unknown.is_none());
+    }
+
+    #[pg_test]
+    fn fhir_expand() {
+        Spi::run_with_args("SELECT fhir_put($1)", &[code_system().into()]).unwrap();
+        let valueset = serde_json::json!({
+            "resourceType": "ValueSet",
+            "url": "http://example.org/ValueSet/conditions",
+            "status": "active",
+            "compose": {
+                "include": [{ "system": "http://example.org/CodeSystem/conditions" }],
+                "exclude": [{
+                    "system": "http://example.org/CodeSystem/conditions",
+                    "filter": [{ "property": "concept", "op": "descendent-of", "value": "diabetes" }],
+                }],
+            },
+        });
+        Spi::run_with_args("SELECT fhir_put($1)", &[JsonB(valueset).into()]).unwrap();
+
+        let expanded = Spi::get_one::<JsonB>(
+            "SELECT fhir_expand('http://example.org/ValueSet/conditions', count => 1)",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(expanded.0["expansion"]["total"], 2);
+        assert_eq!(expanded.0["expansion"]["contains"][0]["code"], "asthma");
+        assert_eq!(
+            expanded.0["expansion"]["contains"]
+                .as_array()
+                .unwrap()
+                .len(),
+            1
+        );
+
+        let filtered = Spi::get_one::<JsonB>(
+            "SELECT fhir_expand('http://example.org/ValueSet/conditions', filter => 'diab')",
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(filtered.0["expansion"]["total"], 1);
+        assert_eq!(filtered.0["expansion"]["contains"][0]["code"], "diabetes");
+
+        let cached = Spi::get_one::<i64>("SELECT count(*) FROM fhir.expansion").unwrap();
+        assert_eq!(cached, Some(1));
+
+        Spi::run_with_args("SELECT fhir_put($1)", &[code_system().into()]).unwrap();
+        let cached = Spi::get_one::<i64>("SELECT count(*) FROM fhir.expansion").unwrap();
+        assert_eq!(cached, Some(0));
+    }
+
+    #[pg_test]
+    fn fhir_expand_invalidation() {
+        Spi::run_with_args("SELECT fhir_put($1)", &[code_system().into()]).unwrap();
+        for (url, set) in [
+            (
+                "http://example.org/ValueSet/conditions",
+                serde_json::json!({ "system": "http://example.org/CodeSystem/conditions" }),
+            ),
+            (
+                "http://example.org/ValueSet/imported",
+                serde_json::json!({ "valueSet": ["http://example.org/ValueSet/conditions"] }),
+            ),
+        ] {
+            let valueset = serde_json::json!({
+                "resourceType": "ValueSet",
+                "url": url,
+                "status": "active",
+                "compose": { "include": [set] },
+            });
+            Spi::run_with_args("SELECT fhir_put($1)", &[JsonB(valueset).into()]).unwrap();
+        }
+
+        Spi::run("SELECT fhir_expand('http://example.org/ValueSet/imported')").unwrap();
+        let cached = || Spi::get_one::<i64>("SELECT count(*) FROM fhir.expansion").unwrap();
+        assert_eq!(cached(), Some(1));
+
+        let mut other = code_system();
+        other.0["url"] = "http://example.org/CodeSystem/other".into();
+        Spi::run_with_args("SELECT fhir_put($1)", &[other.into()]).unwrap();
+        assert_eq!(cached(), Some(1));
+
+        // The imported value set depends on the code system as well.
+        Spi::run_with_args("SELECT fhir_put($1)", &[code_system().into()]).unwrap();
+        assert_eq!(cached(), Some(0));
+    }
 }
@@ -51,4 +51,182 @@ pub struct Patient {
     pub gender: Option<AdministrativeGender>,
     pub name: Option<Vec<HumanName>>,
     pub birth_date: Option<String>,
+    pub link: Option<Vec<PatientLink>>,
+}
+
+/// [Patient.link](<https://hl7.org/fhir/patient-definitions.html#Patient.link>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PatientLink {
+    pub other: Option<Reference>,
+}
+
+/// [Reference](<https://hl7.org/fhir/references.html#Reference>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::struct_field_names)] // names are given by the FHIR specification
+pub struct Reference {
+    pub reference: Option<String>,
+    #[serde(rename = "type")]
+    pub type_: Option<String>,
+    pub display: Option<String>,
+}
+
+/// [Coding](<https://hl7.org/fhir/datatypes.html#Coding>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Coding {
+    pub system: Option<String>,
+    pub code: Option<String>,
+    pub display: Option<String>,
+}
+
+/// [CodeableConcept](<https://hl7.org/fhir/datatypes.html#CodeableConcept>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodeableConcept {
+    pub coding: Option<Vec<Coding>>,
+    pub text: Option<String>,
+}
+
+/// [Quantity](<https://hl7.org/fhir/datatypes.html#Quantity>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Quantity {
+    pub value: Option<serde_json::Number>,
+    pub unit: Option<String>,
+    pub system: Option<String>,
+    pub code: Option<String>,
+}
+
+/// [Observation.component](<https://hl7.org/fhir/observation-definitions.html#Observation.component>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ObservationComponent {
+    pub code: Option<CodeableConcept>,
+    pub value_quantity: Option<Quantity>,
+}
+
+/// [Observation](<https://hl7.org/fhir/observation.html>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Observation {
+    pub status: Option<String>,
+    pub code: Option<CodeableConcept>,
+    pub subject: Option<Reference>,
+    pub encounter: Option<Reference>,
+    pub performer: Option<Vec<Reference>>,
+    pub has_member: Option<Vec<Reference>>,
+    pub derived_from: Option<Vec<Reference>>,
+    pub effective_date_time: Option<String>,
+    pub value_quantity: Option<Quantity>,
+    pub component: Option<Vec<ObservationComponent>>,
+}
+
+/// [Provenance.agent](<https://hl7.org/fhir/provenance-definitions.html#Provenance.agent>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProvenanceAgent {
+    pub who: Option<Reference>,
+}
+
+/// [Provenance](<https://hl7.org/fhir/provenance.html>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Provenance {
+    pub target: Option<Vec<Reference>>,
+    pub agent: Option<Vec<ProvenanceAgent>>,
+    pub recorded: Option<String>,
+}
+
+/// [CanonicalResource](<https://hl7.org/fhir/canonicalresource.html>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CanonicalResource {
+    pub url: Option<String>,
+    pub version: Option<String>,
+}
+
+/// [Meta](<https://hl7.org/fhir/resource.html#Meta>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Meta {
+    pub source: Option<String>,
+    pub profile: Option<Vec<String>>,
+    pub security: Option<Vec<Coding>>,
+    pub tag: Option<Vec<Coding>>,
+}
+
+/// [CodeSystem.concept.property](<https://hl7.org/fhir/codesystem-definitions.html#CodeSystem.concept.property>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConceptProperty {
+    pub code: Option<String>,
+    pub value_code: Option<String>,
+}
+
+/// [CodeSystem.concept](<https://hl7.org/fhir/codesystem-definitions.html#CodeSystem.concept>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodeSystemConcept {
+    pub code: Option<String>,
+    pub display: Option<String>,
+    pub definition: Option<String>,
+    pub property: Option<Vec<ConceptProperty>>,
+    pub concept: Option<Vec<CodeSystemConcept>>,
+}
+
+/// [CodeSystem](<https://hl7.org/fhir/codesystem.html>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CodeSystem {
+    pub url: Option<String>,
+    pub version: Option<String>,
+    pub concept: Option<Vec<CodeSystemConcept>>,
+}
+
+/// [ValueSet.compose.include.concept](<https://hl7.org/fhir/valueset-definitions.html#ValueSet.compose.include.concept>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConceptReference {
+    pub code: Option<String>,
+    pub display: Option<String>,
+}
+
+/// [ValueSet.compose.include.filter](<https://hl7.org/fhir/valueset-definitions.html#ValueSet.compose.include.filter>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ConceptSetFilter {
+    pub property: Option<String>,
+    pub op: Option<String>,
+    pub value: Option<String>,
+}
+
+/// [ValueSet.compose.include](<https://hl7.org/fhir/valueset-definitions.html#ValueSet.compose.include>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConceptSet {
+    pub system: Option<String>,
+    pub version: Option<String>,
+    pub concept: Option<Vec<ConceptReference>>,
+    pub filter: Option<Vec<ConceptSetFilter>>,
+    pub value_set: Option<Vec<String>>,
+}
+
+/// [ValueSet.compose](<https://hl7.org/fhir/valueset-definitions.html#ValueSet.compose>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueSetCompose {
+    pub include: Option<Vec<ConceptSet>>,
+    pub exclude: Option<Vec<ConceptSet>>,
+}
+
+/// [ValueSet.expansion.contains](<https://hl7.org/fhir/valueset-definitions.html#ValueSet.expansion.contains>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueSetContains {
+    pub system: Option<String>,
+    pub code: Option<String>,
+    pub display: Option<String>,
+    pub contains: Option<Vec<ValueSetContains>>,
+}
+
+/// [ValueSet.expansion](<https://hl7.org/fhir/valueset-definitions.html#ValueSet.expansion>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueSetExpansion {
+    pub contains: Option<Vec<ValueSetContains>>,
+}
+
+/// [ValueSet](<https://hl7.org/fhir/valueset.html>)
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ValueSet {
+    pub url: Option<String>,
+    pub version: Option<String>,
+    pub compose: Option<ValueSetCompose>,
+    pub expansion: Option<ValueSetExpansion>,
 }
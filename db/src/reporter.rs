@@ -0,0 +1,53 @@
+//! Export of the recorded traces to Jaeger.
+
+use std::cell::Cell;
+
+use fastrace::collector::Config;
+use pgrx::{info, warning};
+
+use crate::gucs;
+
+thread_local! {
+    /// Whether this backend already tried to start the reporter.
+    static STARTED: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Starts to export traces to Jaeger, if it is enabled by `fhir.jaeger_enabled`.
+///
+/// The reporter runs in a background thread, which does not survive the fork of a
+/// backend from the postmaster, so it is started lazily by every backend. Calling
+/// this on the first query makes sure that the settings of the database and the role
+/// are already applied. Later changes of the settings have no effect.
+pub fn ensure_started() {
+    if STARTED.replace(true) {
+        return;
+    }
+
+    let jaeger_enabled = gucs::JAEGER_ENABLED
+        .get()
+        .is_some_and(|s| s.to_str() == Ok("true"));
+    if !jaeger_enabled {
+        return;
+    }
+
+    let Some(host) = gucs::JAEGER_HOST.get() else {
+        warning!("fhir.jaeger_enabled is set, but fhir.jaeger_host is not set");
+        return;
+    };
+    let host = host.to_string_lossy();
+
+    let reporter = host
+        .parse()
+        .map_err(|error: std::net::AddrParseError| error.to_string())
+        .and_then(|addr| {
+            fastrace_jaeger::JaegerReporter::new(addr, "fhir_extension")
+                .map_err(|error| error.to_string())
+        });
+    match reporter {
+        Ok(reporter) => {
+            fastrace::set_reporter(reporter, Config::default());
+            info!("starting tracing export to Jaeger {host}");
+        }
+        Err(error) => warning!("can't export traces to Jaeger {host}: {error}"),
+    }
+}
@@ -3,20 +3,92 @@
 
 use pgrx::extension_sql;
 
+// `ts_config` maps the `language` of an entity to the text search configuration
+// that is used for the `_content` and `_text` search parameters.
+// Entities without a language are treated as english.
+extension_sql!(
+    r#"
+CREATE FUNCTION "fhir"."ts_config"("language" TEXT) RETURNS regconfig
+LANGUAGE sql IMMUTABLE PARALLEL SAFE AS $$
+    SELECT CASE lower(split_part(coalesce("language", 'en'), '-', 1))
+        WHEN 'da' THEN 'danish'::regconfig
+        WHEN 'de' THEN 'german'::regconfig
+        WHEN 'en' THEN 'english'::regconfig
+        WHEN 'es' THEN 'spanish'::regconfig
+        WHEN 'fi' THEN 'finnish'::regconfig
+        WHEN 'fr' THEN 'french'::regconfig
+        WHEN 'hu' THEN 'hungarian'::regconfig
+        WHEN 'it' THEN 'italian'::regconfig
+        WHEN 'nl' THEN 'dutch'::regconfig
+        WHEN 'no' THEN 'norwegian'::regconfig
+        WHEN 'pt' THEN 'portuguese'::regconfig
+        WHEN 'ro' THEN 'romanian'::regconfig
+        WHEN 'ru' THEN 'russian'::regconfig
+        WHEN 'sv' THEN 'swedish'::regconfig
+        WHEN 'tr' THEN 'turkish'::regconfig
+        ELSE 'simple'::regconfig
+    END
+$$;
+    "#,
+    name = "ts_config",
+);
+
+// `content` contains all strings of the entity, `narrative` only the text of the
+// narrative (`text.div`), without any HTML tags.
+//
+// Entities are validated by `fhir_put` and `fhir_transaction` before they are
+// inserted, according to `fhir.validation_mode`. There is no CHECK constraint,
+// because the result depends on the settings of the session.
 extension_sql!(
     r#"
 CREATE TABLE "fhir"."entity" (
     "id" UUID PRIMARY KEY,
     "resource_type" TEXT NOT NULL,
     "data" JSONB NOT NULL,
+    "last_updated" TIMESTAMPTZ NOT NULL DEFAULT now(),
 
-    CONSTRAINT "valid_schema" CHECK ("public"."fhir_is_valid"("resource_type", "data"))
+    "content" TSVECTOR GENERATED ALWAYS AS (
+        jsonb_to_tsvector("fhir"."ts_config"("data" ->> 'language'), "data", '["string"]')
+    ) STORED,
+    "narrative" TSVECTOR GENERATED ALWAYS AS (
+        to_tsvector(
+            "fhir"."ts_config"("data" ->> 'language'),
+            regexp_replace(coalesce("data" #>> '{text,div}', ''), '<[^>]*>', ' ', 'g')
+        )
+    ) STORED
 );
 
 CREATE INDEX "entity_resource_type_idx" ON "fhir"."entity" ("resource_type");
+CREATE INDEX "entity_last_updated_idx" ON "fhir"."entity" ("resource_type", "last_updated");
+CREATE INDEX "entity_system_last_updated_idx" ON "fhir"."entity" ("last_updated");
+CREATE INDEX "entity_content_idx" ON "fhir"."entity" USING GIN ("content");
+CREATE INDEX "entity_narrative_idx" ON "fhir"."entity" USING GIN ("narrative");
     "#,
     name = "entity_table",
-    requires = [fhir_is_valid]
+    requires = ["ts_config"]
+);
+
+// The `validation_issue` table contains the errors of entities that were stored in
+// the `warn` validation mode, instead of being rejected.
+//
+// `location` is the JSON pointer to the invalid element, and `code` the issue type.
+extension_sql!(
+    r#"
+CREATE TABLE "fhir"."validation_issue" (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    entity_id UUID NOT NULL REFERENCES "fhir"."entity" ("id") ON DELETE CASCADE,
+    entity TEXT NOT NULL,
+    code TEXT NOT NULL,
+    location TEXT NOT NULL,
+    message TEXT NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX "validation_issue_entity_id_idx" ON "fhir"."validation_issue" ("entity_id");
+CREATE INDEX "validation_issue_entity_idx" ON "fhir"."validation_issue" ("entity", "created_at");
+    "#,
+    name = "validation_issue",
+    requires = ["entity_table"]
 );
 
 extension_sql!(
@@ -95,3 +167,163 @@ CREATE INDEX "entity_index_date_key_idx" ON "fhir"."entity_index_date" ("entity"
     name = "entity_index_date",
     requires = ["entity_table"]
 );
+
+// The `index_reference` table is used to search for entities by references to other entities.
+//
+// `value` is the reference as written in the entity, e.g. `Patient/<id>`.
+// `target_type` and `target_id` are only set, if the reference points to an entity
+// of this server, and are used to resolve `_include` and `_revinclude` parameters.
+extension_sql!(
+    r#"
+CREATE TABLE "fhir"."entity_index_reference" (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    entity_id UUID NOT NULL REFERENCES "fhir"."entity" ("id") ON DELETE CASCADE,
+    entity TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL,
+    target_type TEXT GENERATED ALWAYS AS (
+        substring(value FROM '^([A-Za-z]+)/[0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12}$')
+    ) STORED,
+    target_id UUID GENERATED ALWAYS AS (
+        substring(value FROM '^[A-Za-z]+/([0-9a-fA-F]{8}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{4}-[0-9a-fA-F]{12})$')::uuid
+    ) STORED
+);
+
+CREATE INDEX "entity_index_reference_entity_id_idx" ON "fhir"."entity_index_reference" ("entity_id");
+CREATE INDEX "entity_index_reference_key_value_idx" ON "fhir"."entity_index_reference" ("entity", "key", "value");
+CREATE INDEX "entity_index_reference_key_idx" ON "fhir"."entity_index_reference" ("entity", "key");
+CREATE INDEX "entity_index_reference_target_id_idx" ON "fhir"."entity_index_reference" ("target_id");
+    "#,
+    name = "entity_index_reference",
+    requires = ["entity_table"]
+);
+
+// The `index_uri` table is used to search for entities by URIs, like canonical URLs.
+extension_sql!(
+    r#"
+CREATE TABLE "fhir"."entity_index_uri" (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    entity_id UUID NOT NULL REFERENCES "fhir"."entity" ("id") ON DELETE CASCADE,
+    entity TEXT NOT NULL,
+    key TEXT NOT NULL,
+    value TEXT NOT NULL
+);
+
+CREATE INDEX "entity_index_uri_entity_id_idx" ON "fhir"."entity_index_uri" ("entity_id");
+CREATE INDEX "entity_index_uri_key_value_idx" ON "fhir"."entity_index_uri" ("entity", "key", "value");
+    "#,
+    name = "entity_index_uri",
+    requires = ["entity_table"]
+);
+
+// The `index_composite` table is used to search for entities by composite parameters,
+// whose components must match the same element, like `code-value-quantity`.
+//
+// Every row contains the values of a single element, e.g. of one observation component.
+// `token` is the value of the token component, and `value` the value of the quantity component.
+extension_sql!(
+    r#"
+CREATE TABLE "fhir"."entity_index_composite" (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    entity_id UUID NOT NULL REFERENCES "fhir"."entity" ("id") ON DELETE CASCADE,
+    entity TEXT NOT NULL,
+    key TEXT NOT NULL,
+    token TEXT NOT NULL,
+    value NUMERIC NOT NULL
+);
+
+CREATE INDEX "entity_index_composite_entity_id_idx" ON "fhir"."entity_index_composite" ("entity_id");
+CREATE INDEX "entity_index_composite_key_token_value_idx" ON "fhir"."entity_index_composite" ("entity", "key", "token", "value");
+    "#,
+    name = "entity_index_composite",
+    requires = ["entity_table"]
+);
+
+// The `concept` table contains the concepts of all `CodeSystem` entities, which are
+// used by the terminology operations like `$lookup` and `$validate-code`.
+//
+// `parent` is the code of the parent concept in the hierarchy of the code system.
+extension_sql!(
+    r#"
+CREATE TABLE "fhir"."concept" (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    entity_id UUID NOT NULL REFERENCES "fhir"."entity" ("id") ON DELETE CASCADE,
+    system TEXT NOT NULL,
+    version TEXT,
+    code TEXT NOT NULL,
+    display TEXT,
+    definition TEXT,
+    parent TEXT
+);
+
+CREATE INDEX "concept_entity_id_idx" ON "fhir"."concept" ("entity_id");
+CREATE INDEX "concept_system_code_idx" ON "fhir"."concept" ("system", "code");
+CREATE INDEX "concept_system_parent_idx" ON "fhir"."concept" ("system", "parent");
+    "#,
+    name = "concept",
+    requires = ["entity_table"]
+);
+
+// The `expansion` table caches the expansions of `ValueSet` entities, which are
+// computed by `$expand`. The concepts of an expansion are stored in
+// `expansion_concept`, in the order of the expansion.
+//
+// `depends_on` contains the canonical urls of the code systems and value sets that
+// the expansion was computed from, including imported value sets. When a `CodeSystem`
+// or `ValueSet` changes, the expansions that depend on its old or new url are removed,
+// as well as the expansion of the changed value set itself.
+extension_sql!(
+    r#"
+CREATE TABLE "fhir"."expansion" (
+    valueset_id UUID PRIMARY KEY REFERENCES "fhir"."entity" ("id") ON DELETE CASCADE,
+    depends_on TEXT[] NOT NULL DEFAULT '{}',
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX "expansion_depends_on_idx" ON "fhir"."expansion" USING GIN ("depends_on");
+
+CREATE TABLE "fhir"."expansion_concept" (
+    id BIGINT PRIMARY KEY GENERATED ALWAYS AS IDENTITY,
+    valueset_id UUID NOT NULL REFERENCES "fhir"."expansion" ("valueset_id") ON DELETE CASCADE,
+    system TEXT NOT NULL,
+    version TEXT,
+    code TEXT NOT NULL,
+    display TEXT
+);
+
+CREATE INDEX "expansion_concept_valueset_id_idx" ON "fhir"."expansion_concept" ("valueset_id", "id");
+CREATE INDEX "expansion_concept_display_gin_idx" ON "fhir"."expansion_concept" USING GIN ("display" gin_trgm_ops);
+
+CREATE FUNCTION "fhir"."invalidate_expansions"() RETURNS trigger
+LANGUAGE plpgsql AS $$
+DECLARE
+    urls TEXT[] := '{}';
+BEGIN
+    IF TG_OP IN ('UPDATE', 'DELETE') THEN
+        urls := urls || (OLD."data" ->> 'url');
+        DELETE FROM "fhir"."expansion" WHERE "valueset_id" = OLD."id";
+    END IF;
+    IF TG_OP IN ('INSERT', 'UPDATE') THEN
+        urls := urls || (NEW."data" ->> 'url');
+    END IF;
+
+    DELETE FROM "fhir"."expansion" WHERE "depends_on" && urls;
+    RETURN NULL;
+END
+$$;
+
+CREATE TRIGGER "expansion_invalidate_trigger"
+AFTER INSERT OR UPDATE ON "fhir"."entity"
+FOR EACH ROW
+WHEN (NEW."resource_type" IN ('CodeSystem', 'ValueSet'))
+EXECUTE FUNCTION "fhir"."invalidate_expansions"();
+
+CREATE TRIGGER "expansion_invalidate_delete_trigger"
+AFTER DELETE ON "fhir"."entity"
+FOR EACH ROW
+WHEN (OLD."resource_type" IN ('CodeSystem', 'ValueSet'))
+EXECUTE FUNCTION "fhir"."invalidate_expansions"();
+    "#,
+    name = "expansion",
+    requires = ["entity_table"]
+);
@@ -2,8 +2,14 @@
 //!
 //! Functions are wrapped to allow recording of traces using [`fasttrace`].
 
+use std::panic::AssertUnwindSafe;
+
 use fastrace::trace;
-use pgrx::{datum::DatumWithOid, Spi};
+use pgrx::{
+    datum::DatumWithOid,
+    pg_sys::{self, panic::CaughtError},
+    PgTryBuilder, Spi,
+};
 
 pub use pgrx::spi::Result;
 
@@ -11,3 +17,45 @@ pub use pgrx::spi::Result;
 pub fn run_with_args<'mcx>(query: &str, args: &[DatumWithOid<'mcx>]) -> Result<()> {
     Spi::run_with_args(query, args)
 }
+
+/// Runs `f` in a subtransaction, like a `SAVEPOINT`.
+///
+/// If `f` fails, the changes of the subtransaction are rolled back and the error
+/// message is returned, without aborting the surrounding transaction.
+#[trace]
+pub fn subtransaction<T>(f: impl FnOnce() -> Result<T>) -> std::result::Result<T, String> {
+    // SAFETY: the subtransaction is always released or rolled back below, after which
+    // the memory context and resource owner of the caller are restored.
+    let (memory_context, resource_owner) = unsafe {
+        let memory_context = pg_sys::CurrentMemoryContext;
+        let resource_owner = pg_sys::CurrentResourceOwner;
+        pg_sys::BeginInternalSubTransaction(std::ptr::null());
+        pg_sys::MemoryContextSwitchTo(memory_context);
+        (memory_context, resource_owner)
+    };
+
+    // `f` is not used after it failed, so it doesn't matter if its state is broken.
+    let result = PgTryBuilder::new(AssertUnwindSafe(|| f().map_err(|error| error.to_string())))
+        .catch_others(|error| {
+            let (CaughtError::PostgresError(report)
+            | CaughtError::ErrorReport(report)
+            | CaughtError::RustPanic {
+                ereport: report, ..
+            }) = error;
+            Err(report.message().to_string())
+        })
+        .execute();
+
+    // SAFETY: the subtransaction started above is still the current one.
+    unsafe {
+        if result.is_ok() {
+            pg_sys::ReleaseCurrentSubTransaction();
+        } else {
+            pg_sys::RollbackAndReleaseCurrentSubTransaction();
+        }
+        pg_sys::MemoryContextSwitchTo(memory_context);
+        pg_sys::CurrentResourceOwner = resource_owner;
+    }
+
+    result
+}
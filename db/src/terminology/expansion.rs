@@ -0,0 +1,181 @@
+//! The cache of value set expansions.
+//!
+//! Expansions are computed on the first `$expand` of a value set, and stored in the
+//! `fhir.expansion` and `fhir.expansion_concept` tables. Every expansion records the
+//! canonical urls of the code systems and value sets it depends on, including the
+//! imported ones, and is removed when one of them, or the value set itself, is changed.
+
+use fastrace::{prelude::*, trace};
+use pgrx::{prelude::*, Uuid};
+use serde_json::Value;
+
+use crate::{
+    spi,
+    terminology::valueset::{self, ExpandedConcept},
+};
+
+/// A page of a cached expansion.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpansionPage {
+    /// The number of concepts that match the filter.
+    pub total: i64,
+    /// The time the expansion was computed.
+    pub timestamp: String,
+    pub concepts: Vec<ExpandedConcept>,
+}
+
+/// Makes sure the expansion of the value set with the given entity id is cached.
+///
+/// Returns an error message if the value set can't be expanded.
+#[trace]
+pub fn ensure_cached(id: Uuid, valueset: &Value) -> spi::Result<Result<(), String>> {
+    let cached = Spi::get_one_with_args::<bool>(
+        r#"SELECT EXISTS (SELECT 1 FROM "fhir"."expansion" WHERE "valueset_id" = $1)"#,
+        &[id.into()],
+    )?;
+    if cached == Some(true) {
+        return Ok(Ok(()));
+    }
+
+    let concepts = match valueset::expand(valueset)? {
+        Ok(concepts) => concepts,
+        Err(message) => return Ok(Err(message)),
+    };
+
+    let dependencies = valueset::dependencies(valueset)?;
+    let (systems, versions, codes, displays): (Vec<_>, Vec<_>, Vec<_>, Vec<_>) = concepts
+        .into_iter()
+        .map(|concept| {
+            (
+                concept.system,
+                concept.version,
+                concept.code,
+                concept.display,
+            )
+        })
+        .collect();
+
+    Spi::connect_mut(|client| {
+        // Another backend may have expanded the same value set in the meantime.
+        let inserted = client
+            .update(
+                r#"
+                INSERT INTO "fhir"."expansion" ("valueset_id", "depends_on") VALUES ($1, $2)
+                ON CONFLICT DO NOTHING
+                RETURNING "valueset_id"
+                "#,
+                None,
+                &[id.into(), dependencies.into()],
+            )?
+            .len();
+        if inserted == 0 {
+            return Ok(Ok(()));
+        }
+
+        client.update(
+            r#"
+            INSERT INTO "fhir"."expansion_concept" ("valueset_id", "system", "version", "code", "display")
+            SELECT $1, "system", "version", "code", "display"
+            FROM unnest($2::text[], $3::text[], $4::text[], $5::text[])
+                WITH ORDINALITY AS "concept" ("system", "version", "code", "display", "position")
+            ORDER BY "position"
+            "#,
+            None,
+            &[
+                id.into(),
+                systems.into(),
+                versions.into(),
+                codes.into(),
+                displays.into(),
+            ],
+        )?;
+
+        Ok(Ok(()))
+    })
+}
+
+/// Returns a page of the cached expansion of the value set.
+///
+/// `filter` matches the concepts whose display contains the text, or contains a word
+/// similar to it (see `pg_trgm`).
+pub fn page(
+    id: Uuid,
+    filter: Option<&str>,
+    offset: i64,
+    count: Option<i64>,
+) -> spi::Result<ExpansionPage> {
+    let pattern = filter.map(|filter| {
+        let escaped = filter
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_");
+        format!("%{escaped}%")
+    });
+
+    let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+    Spi::connect(|client| {
+        let header = client
+            .select(
+                r#"
+                SELECT
+                    to_json("expansion"."created_at") #>> '{}' AS "timestamp",
+                    (
+                        SELECT count(*) FROM "fhir"."expansion_concept" "concept"
+                        WHERE
+                            "concept"."valueset_id" = $1
+                            AND ($2::text IS NULL OR "concept"."display" ILIKE $3 OR $2 <% "concept"."display")
+                    ) AS "total"
+                FROM "fhir"."expansion" "expansion"
+                WHERE "expansion"."valueset_id" = $1
+                "#,
+                Some(1),
+                &[id.into(), filter.into(), pattern.as_deref().into()],
+            )?
+            .next();
+        let (timestamp, total) = match header {
+            Some(row) => (
+                row["timestamp"].value::<String>()?.unwrap_or_default(),
+                row["total"].value::<i64>()?.unwrap_or_default(),
+            ),
+            None => (String::new(), 0),
+        };
+
+        let concepts = client
+            .select(
+                r#"
+                SELECT "system", "version", "code", "display"
+                FROM "fhir"."expansion_concept"
+                WHERE
+                    "valueset_id" = $1
+                    AND ($2::text IS NULL OR "display" ILIKE $3 OR $2 <% "display")
+                ORDER BY "id"
+                OFFSET $4
+                LIMIT $5
+                "#,
+                None,
+                &[
+                    id.into(),
+                    filter.into(),
+                    pattern.as_deref().into(),
+                    offset.into(),
+                    count.into(),
+                ],
+            )?
+            .map(|row| {
+                Ok(ExpandedConcept {
+                    system: row["system"].value::<String>()?.unwrap_or_default(),
+                    version: row["version"].value()?,
+                    code: row["code"].value::<String>()?.unwrap_or_default(),
+                    display: row["display"].value()?,
+                })
+            })
+            .collect::<spi::Result<Vec<_>>>()?;
+
+        Ok(ExpansionPage {
+            total,
+            timestamp,
+            concepts,
+        })
+    })
+}
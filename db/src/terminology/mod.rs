@@ -0,0 +1,276 @@
+//! Terminology support, based on the stored `CodeSystem` and `ValueSet` entities.
+//!
+//! The concepts of every `CodeSystem` are copied into the `fhir.concept` table when
+//! the entity is stored, so they can be looked up by their system and code. The
+//! membership of a code in a value set is computed from its `compose` rules, while
+//! full expansions are cached in the `fhir.expansion` table, see [`expansion`].
+
+use std::collections::HashSet;
+
+use fastrace::{prelude::*, trace};
+use pgrx::{prelude::*, Uuid};
+
+use crate::{
+    models::{CodeSystem, CodeSystemConcept},
+    spi,
+};
+
+pub mod expansion;
+pub mod valueset;
+
+/// A single concept of a code system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Concept {
+    pub code: String,
+    pub display: Option<String>,
+    pub definition: Option<String>,
+    /// The code of the parent concept, either given by nesting the concepts or by
+    /// the `parent` property.
+    pub parent: Option<String>,
+}
+
+/// The concepts of a `CodeSystem`, that must be inserted into the `concept` table.
+pub struct CodeSystemConcepts {
+    system: String,
+    version: Option<String>,
+    concepts: Vec<Concept>,
+}
+
+/// Collects all concepts of the given `CodeSystem`.
+///
+/// Returns [`None`] if the code system has no `url`, since its concepts could never
+/// be referenced.
+pub fn collect_concepts(data: &serde_json::Value) -> Option<CodeSystemConcepts> {
+    let code_system: CodeSystem = serde_json::from_value(data.clone()).ok()?;
+
+    let mut concepts = Vec::new();
+    flatten_concepts(
+        code_system.concept.as_deref().unwrap_or_default(),
+        None,
+        &mut concepts,
+    );
+
+    Some(CodeSystemConcepts {
+        system: code_system.url?,
+        version: code_system.version,
+        concepts,
+    })
+}
+
+fn flatten_concepts(concepts: &[CodeSystemConcept], parent: Option<&str>, out: &mut Vec<Concept>) {
+    for concept in concepts {
+        let Some(code) = &concept.code else {
+            continue;
+        };
+
+        let parent_property = concept
+            .property
+            .iter()
+            .flatten()
+            .find(|property| property.code.as_deref() == Some("parent"))
+            .and_then(|property| property.value_code.clone());
+
+        out.push(Concept {
+            code: code.clone(),
+            display: concept.display.clone(),
+            definition: concept.definition.clone(),
+            parent: parent.map(ToString::to_string).or(parent_property),
+        });
+
+        flatten_concepts(
+            concept.concept.as_deref().unwrap_or_default(),
+            Some(code),
+            out,
+        );
+    }
+}
+
+impl CodeSystemConcepts {
+    /// Inserts the concepts of the code system with the given entity id.
+    #[trace]
+    pub fn insert(self, id: Uuid) -> spi::Result<()> {
+        for concept in self.concepts {
+            spi::run_with_args(
+                r#"
+                INSERT INTO "fhir"."concept" ("entity_id", "system", "version", "code", "display", "definition", "parent")
+                VALUES ($1, $2, $3, $4, $5, $6, $7);
+                "#,
+                &[
+                    id.into(),
+                    self.system.as_str().into(),
+                    self.version.as_deref().into(),
+                    concept.code.into(),
+                    concept.display.into(),
+                    concept.definition.into(),
+                    concept.parent.into(),
+                ],
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks if any `CodeSystem` with the given url (and version) is stored.
+pub fn is_known_system(system: &str, version: Option<&str>) -> spi::Result<bool> {
+    let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+    Ok(Spi::get_one_with_args::<bool>(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM "fhir"."concept"
+            WHERE "system" = $1 AND ($2::text IS NULL OR "version" = $2)
+        )
+        "#,
+        &[system.into(), version.into()],
+    )?
+    .unwrap_or_default())
+}
+
+/// Looks up a concept by its system and code.
+///
+/// If multiple versions of the code system are stored, the concept of the most
+/// recent one is returned.
+pub fn find_concept(
+    system: &str,
+    version: Option<&str>,
+    code: &str,
+) -> spi::Result<Option<Concept>> {
+    let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+    Spi::connect(|client| {
+        let Some(row) = client
+            .select(
+                r#"
+                SELECT "concept"."code", "concept"."display", "concept"."definition", "concept"."parent"
+                FROM "fhir"."concept" "concept"
+                JOIN "fhir"."entity" "entity" ON "entity"."id" = "concept"."entity_id"
+                WHERE
+                    "concept"."system" = $1
+                    AND ($2::text IS NULL OR "concept"."version" = $2)
+                    AND "concept"."code" = $3
+                ORDER BY "entity"."last_updated" DESC
+                "#,
+                Some(1),
+                &[system.into(), version.into(), code.into()],
+            )?
+            .next()
+        else {
+            return Ok(None);
+        };
+
+        Ok(Some(Concept {
+            code: row["code"].value::<String>()?.unwrap_or_default(),
+            display: row["display"].value()?,
+            definition: row["definition"].value()?,
+            parent: row["parent"].value()?,
+        }))
+    })
+}
+
+/// Returns all concepts of a code system, ordered by their code.
+///
+/// If multiple versions of the code system are stored, the concepts of the most
+/// recent one are returned.
+pub fn system_concepts(system: &str, version: Option<&str>) -> spi::Result<Vec<Concept>> {
+    let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+    Spi::connect(|client| {
+        client
+            .select(
+                r#"
+                SELECT DISTINCT ON ("concept"."code")
+                    "concept"."code", "concept"."display", "concept"."definition", "concept"."parent"
+                FROM "fhir"."concept" "concept"
+                JOIN "fhir"."entity" "entity" ON "entity"."id" = "concept"."entity_id"
+                WHERE "concept"."system" = $1 AND ($2::text IS NULL OR "concept"."version" = $2)
+                ORDER BY "concept"."code", "entity"."last_updated" DESC
+                "#,
+                None,
+                &[system.into(), version.into()],
+            )?
+            .map(|row| {
+                Ok(Concept {
+                    code: row["code"].value::<String>()?.unwrap_or_default(),
+                    display: row["display"].value()?,
+                    definition: row["definition"].value()?,
+                    parent: row["parent"].value()?,
+                })
+            })
+            .collect()
+    })
+}
+
+/// Returns the codes of the given concept and all of its descendants.
+pub fn descendants(
+    system: &str,
+    version: Option<&str>,
+    code: &str,
+) -> spi::Result<HashSet<String>> {
+    let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+    Spi::connect(|client| {
+        client
+            .select(
+                r#"
+                WITH RECURSIVE "descendant" ("code") AS (
+                    SELECT $3::text
+                    UNION
+                    SELECT "concept"."code"
+                    FROM "fhir"."concept" "concept"
+                    JOIN "descendant" ON "concept"."parent" = "descendant"."code"
+                    WHERE "concept"."system" = $1 AND ($2::text IS NULL OR "concept"."version" = $2)
+                )
+                SELECT "code" FROM "descendant"
+                "#,
+                None,
+                &[system.into(), version.into(), code.into()],
+            )?
+            .filter_map(|row| row["code"].value::<String>().transpose())
+            .collect()
+    })
+}
+
+/// Returns the codes of the direct children of the given concept.
+pub fn child_codes(system: &str, version: Option<&str>, code: &str) -> spi::Result<Vec<String>> {
+    let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+    Spi::connect(|client| {
+        client
+            .select(
+                r#"
+                SELECT DISTINCT "code"
+                FROM "fhir"."concept"
+                WHERE "system" = $1 AND ($2::text IS NULL OR "version" = $2) AND "parent" = $3
+                ORDER BY "code"
+                "#,
+                None,
+                &[system.into(), version.into(), code.into()],
+            )?
+            .filter_map(|row| row["code"].value::<String>().transpose())
+            .collect()
+    })
+}
+
+/// Checks if `code` is subsumed by `ancestor`, i.e. it is the same concept or one of
+/// its descendants.
+pub fn is_a(system: &str, version: Option<&str>, code: &str, ancestor: &str) -> spi::Result<bool> {
+    let _guard = LocalSpan::enter_with_local_parent("spi_select");
+
+    Ok(Spi::get_one_with_args::<bool>(
+        r#"
+        WITH RECURSIVE "ancestor" ("code", "parent") AS (
+            SELECT "code", "parent" FROM "fhir"."concept"
+            WHERE "system" = $1 AND ($2::text IS NULL OR "version" = $2) AND "code" = $3
+            UNION
+            SELECT "concept"."code", "concept"."parent"
+            FROM "fhir"."concept" "concept"
+            JOIN "ancestor" ON "concept"."code" = "ancestor"."parent"
+            WHERE "concept"."system" = $1 AND ($2::text IS NULL OR "concept"."version" = $2)
+        )
+        SELECT EXISTS (SELECT 1 FROM "ancestor" WHERE "code" = $4)
+        "#,
+        &[system.into(), version.into(), code.into(), ancestor.into()],
+    )?
+    .unwrap_or_default())
+}
@@ -0,0 +1,474 @@
+//! Membership of codes in value sets, and the expansion of value sets.
+//!
+//! See [Value Set Composition](<https://hl7.org/fhir/valueset.html#compositions>).
+
+use std::collections::{BTreeSet, HashSet};
+
+use serde_json::Value;
+
+use crate::{
+    api::common::find_canonical,
+    models::{ConceptSet, ConceptSetFilter, ValueSet, ValueSetContains},
+    spi, terminology,
+};
+
+/// The maximum depth of nested `valueSet` imports, to stop cyclic imports.
+const MAX_IMPORT_DEPTH: usize = 8;
+
+/// Whether a code is part of a value set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Membership {
+    /// The code is part of the value set, with the display given by the value set.
+    Member(Option<String>),
+    NotMember,
+    /// The membership could not be decided, e.g. because a code system or imported
+    /// value set is not stored.
+    Unknown(String),
+}
+
+/// Checks if the code of the given system is part of the value set.
+///
+/// Uses the `expansion` of the value set if there is one, otherwise the `compose`
+/// rules are evaluated using the stored code systems.
+pub fn contains(valueset: &Value, system: &str, code: &str) -> spi::Result<Membership> {
+    contains_nested(valueset, system, code, 0)
+}
+
+fn contains_nested(
+    valueset: &Value,
+    system: &str,
+    code: &str,
+    depth: usize,
+) -> spi::Result<Membership> {
+    let Ok(valueset) = serde_json::from_value::<ValueSet>(valueset.clone()) else {
+        return Ok(Membership::Unknown("the value set is invalid".to_string()));
+    };
+    let url = valueset.url.as_deref().unwrap_or_default();
+
+    if depth > MAX_IMPORT_DEPTH {
+        return Ok(Membership::Unknown(format!(
+            "the imports of value set '{url}' are nested too deeply"
+        )));
+    }
+
+    if let Some(contains) = valueset.expansion.and_then(|expansion| expansion.contains) {
+        return Ok(expansion_contains(&contains, system, code)
+            .map_or(Membership::NotMember, |entry| {
+                Membership::Member(entry.display.clone())
+            }));
+    }
+
+    let Some(compose) = valueset.compose else {
+        return Ok(Membership::Unknown(format!(
+            "value set '{url}' has neither a compose nor an expansion"
+        )));
+    };
+
+    let mut membership = Membership::NotMember;
+    for include in compose.include.iter().flatten() {
+        match set_contains(include, system, code, depth)? {
+            Membership::NotMember => {}
+            Membership::Unknown(message) => membership = Membership::Unknown(message),
+            member @ Membership::Member(_) => {
+                membership = member;
+                break;
+            }
+        }
+    }
+
+    if !matches!(membership, Membership::Member(_)) {
+        return Ok(membership);
+    }
+
+    for exclude in compose.exclude.iter().flatten() {
+        match set_contains(exclude, system, code, depth)? {
+            Membership::NotMember => {}
+            Membership::Member(_) => return Ok(Membership::NotMember),
+            unknown @ Membership::Unknown(_) => return Ok(unknown),
+        }
+    }
+
+    Ok(membership)
+}
+
+/// Searches the code in the `contains` of an expansion.
+fn expansion_contains<'a>(
+    contains: &'a [ValueSetContains],
+    system: &str,
+    code: &str,
+) -> Option<&'a ValueSetContains> {
+    contains.iter().find_map(|entry| {
+        if entry.system.as_deref() == Some(system) && entry.code.as_deref() == Some(code) {
+            return Some(entry);
+        }
+
+        expansion_contains(entry.contains.as_deref().unwrap_or_default(), system, code)
+    })
+}
+
+/// Checks if the code is part of a single `include` or `exclude` of a value set.
+///
+/// The code must be part of all imported `valueSet`s, and match the `system`, the
+/// listed `concept`s and all `filter`s.
+fn set_contains(
+    set: &ConceptSet,
+    system: &str,
+    code: &str,
+    depth: usize,
+) -> spi::Result<Membership> {
+    for url in set.value_set.iter().flatten() {
+        let Some(imported) = find_canonical("ValueSet", url)? else {
+            return Ok(Membership::Unknown(format!("unknown value set '{url}'")));
+        };
+
+        match contains_nested(&imported, system, code, depth + 1)? {
+            Membership::Member(_) => {}
+            other => return Ok(other),
+        }
+    }
+
+    let Some(set_system) = set.system.as_deref() else {
+        return Ok(Membership::Member(None));
+    };
+    if set_system != system {
+        return Ok(Membership::NotMember);
+    }
+
+    if let Some(concepts) = &set.concept {
+        return Ok(concepts
+            .iter()
+            .find(|concept| concept.code.as_deref() == Some(code))
+            .map_or(Membership::NotMember, |concept| {
+                Membership::Member(concept.display.clone())
+            }));
+    }
+
+    let version = set.version.as_deref();
+    if !terminology::is_known_system(system, version)? {
+        return Ok(Membership::Unknown(format!(
+            "unknown code system '{system}'"
+        )));
+    }
+    if terminology::find_concept(system, version, code)?.is_none() {
+        return Ok(Membership::NotMember);
+    }
+
+    for filter in set.filter.iter().flatten() {
+        match matches_filter(filter, system, version, code)? {
+            Some(true) => {}
+            Some(false) => return Ok(Membership::NotMember),
+            None => return Ok(Membership::Unknown(unsupported_filter(filter))),
+        }
+    }
+
+    Ok(Membership::Member(None))
+}
+
+/// Checks if the code matches the filter.
+///
+/// Only the hierarchical filters on the `concept` property are supported, returns
+/// [`None`] for all other filters.
+fn matches_filter(
+    filter: &ConceptSetFilter,
+    system: &str,
+    version: Option<&str>,
+    code: &str,
+) -> spi::Result<Option<bool>> {
+    let Some((op, value)) = hierarchy_filter(filter) else {
+        return Ok(None);
+    };
+
+    Ok(Some(match op {
+        HierarchyOp::IsA => terminology::is_a(system, version, code, value)?,
+        HierarchyOp::DescendentOf => {
+            code != value && terminology::is_a(system, version, code, value)?
+        }
+        HierarchyOp::IsNotA => !terminology::is_a(system, version, code, value)?,
+    }))
+}
+
+/// The supported filter operators on the hierarchy of a code system.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HierarchyOp {
+    IsA,
+    DescendentOf,
+    IsNotA,
+}
+
+/// Returns the operator and the code of a filter on the `concept` property.
+fn hierarchy_filter(filter: &ConceptSetFilter) -> Option<(HierarchyOp, &str)> {
+    if filter.property.as_deref() != Some("concept") {
+        return None;
+    }
+
+    let op = match filter.op.as_deref()? {
+        "is-a" => HierarchyOp::IsA,
+        "descendent-of" => HierarchyOp::DescendentOf,
+        "is-not-a" => HierarchyOp::IsNotA,
+        _ => return None,
+    };
+
+    Some((op, filter.value.as_deref()?))
+}
+
+fn unsupported_filter(filter: &ConceptSetFilter) -> String {
+    format!(
+        "unsupported filter '{} {} {}'",
+        filter.property.as_deref().unwrap_or_default(),
+        filter.op.as_deref().unwrap_or_default(),
+        filter.value.as_deref().unwrap_or_default(),
+    )
+}
+
+/// A single concept of an expanded value set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExpandedConcept {
+    pub system: String,
+    pub version: Option<String>,
+    pub code: String,
+    pub display: Option<String>,
+}
+
+impl ExpandedConcept {
+    fn key(&self) -> (&str, &str) {
+        (&self.system, &self.code)
+    }
+}
+
+/// Expands the value set into the list of all its concepts.
+///
+/// Uses the `expansion` of the value set if there is one, otherwise the `compose`
+/// rules are evaluated using the stored code systems. Returns an error message if the
+/// value set can't be expanded, e.g. because a code system is not stored.
+pub fn expand(valueset: &Value) -> spi::Result<Result<Vec<ExpandedConcept>, String>> {
+    expand_nested(valueset, 0)
+}
+
+/// The canonical urls of the code systems and value sets the expansion of the value
+/// set depends on, without their versions.
+///
+/// This includes the dependencies of all imported value sets.
+pub fn dependencies(valueset: &Value) -> spi::Result<Vec<String>> {
+    let mut urls = BTreeSet::new();
+    collect_dependencies(valueset, &mut urls, 0)?;
+    Ok(urls.into_iter().collect())
+}
+
+fn collect_dependencies(
+    valueset: &Value,
+    urls: &mut BTreeSet<String>,
+    depth: usize,
+) -> spi::Result<()> {
+    let Ok(valueset) = serde_json::from_value::<ValueSet>(valueset.clone()) else {
+        return Ok(());
+    };
+    let Some(compose) = valueset.compose else {
+        return Ok(());
+    };
+    if depth > MAX_IMPORT_DEPTH {
+        return Ok(());
+    }
+
+    let without_version = |url: &str| url.split_once('|').map_or(url, |(url, _)| url).to_string();
+
+    for set in compose
+        .include
+        .iter()
+        .chain(compose.exclude.iter())
+        .flatten()
+    {
+        if let Some(system) = set.system.as_deref() {
+            urls.insert(without_version(system));
+        }
+
+        for url in set.value_set.iter().flatten() {
+            if !urls.insert(without_version(url)) {
+                continue;
+            }
+            if let Some(imported) = find_canonical("ValueSet", url)? {
+                collect_dependencies(&imported, urls, depth + 1)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn expand_nested(
+    valueset: &Value,
+    depth: usize,
+) -> spi::Result<Result<Vec<ExpandedConcept>, String>> {
+    let Ok(valueset) = serde_json::from_value::<ValueSet>(valueset.clone()) else {
+        return Ok(Err("the value set is invalid".to_string()));
+    };
+    let url = valueset.url.as_deref().unwrap_or_default();
+
+    if depth > MAX_IMPORT_DEPTH {
+        return Ok(Err(format!(
+            "the imports of value set '{url}' are nested too deeply"
+        )));
+    }
+
+    if let Some(contains) = valueset.expansion.and_then(|expansion| expansion.contains) {
+        let mut concepts = Vec::new();
+        flatten_contains(&contains, &mut concepts);
+        return Ok(Ok(concepts));
+    }
+
+    let Some(compose) = valueset.compose else {
+        return Ok(Err(format!(
+            "value set '{url}' has neither a compose nor an expansion"
+        )));
+    };
+
+    let mut concepts = Vec::new();
+    let mut seen = HashSet::new();
+    for include in compose.include.iter().flatten() {
+        let included = match set_expand(include, depth)? {
+            Ok(included) => included,
+            Err(message) => return Ok(Err(message)),
+        };
+
+        for concept in included {
+            if seen.insert((concept.system.clone(), concept.code.clone())) {
+                concepts.push(concept);
+            }
+        }
+    }
+
+    for exclude in compose.exclude.iter().flatten() {
+        let excluded = match set_expand(exclude, depth)? {
+            Ok(excluded) => excluded,
+            Err(message) => return Ok(Err(message)),
+        };
+
+        let excluded = excluded
+            .iter()
+            .map(ExpandedConcept::key)
+            .collect::<HashSet<_>>();
+        concepts.retain(|concept| !excluded.contains(&concept.key()));
+    }
+
+    Ok(Ok(concepts))
+}
+
+fn flatten_contains(contains: &[ValueSetContains], out: &mut Vec<ExpandedConcept>) {
+    for entry in contains {
+        if let (Some(system), Some(code)) = (&entry.system, &entry.code) {
+            out.push(ExpandedConcept {
+                system: system.clone(),
+                version: None,
+                code: code.clone(),
+                display: entry.display.clone(),
+            });
+        }
+
+        flatten_contains(entry.contains.as_deref().unwrap_or_default(), out);
+    }
+}
+
+/// Expands a single `include` or `exclude` of a value set.
+///
+/// The concepts of the `system` are intersected with all imported `valueSet`s.
+fn set_expand(set: &ConceptSet, depth: usize) -> spi::Result<Result<Vec<ExpandedConcept>, String>> {
+    let mut imported: Option<Vec<ExpandedConcept>> = None;
+    for url in set.value_set.iter().flatten() {
+        let Some(valueset) = find_canonical("ValueSet", url)? else {
+            return Ok(Err(format!("unknown value set '{url}'")));
+        };
+
+        let expanded = match expand_nested(&valueset, depth + 1)? {
+            Ok(expanded) => expanded,
+            Err(message) => return Ok(Err(message)),
+        };
+
+        imported = Some(match imported {
+            None => expanded,
+            Some(mut previous) => {
+                let keys = expanded
+                    .iter()
+                    .map(ExpandedConcept::key)
+                    .collect::<HashSet<_>>();
+                previous.retain(|concept| keys.contains(&concept.key()));
+                previous
+            }
+        });
+    }
+
+    let Some(system) = set.system.as_deref() else {
+        return Ok(Ok(imported.unwrap_or_default()));
+    };
+
+    let mut concepts = match system_expand(set, system)? {
+        Ok(concepts) => concepts,
+        Err(message) => return Ok(Err(message)),
+    };
+
+    if let Some(imported) = imported {
+        let keys = imported
+            .iter()
+            .map(ExpandedConcept::key)
+            .collect::<HashSet<_>>();
+        concepts.retain(|concept| keys.contains(&concept.key()));
+    }
+
+    Ok(Ok(concepts))
+}
+
+/// Expands the listed `concept`s of a code system, or all of its concepts that
+/// match the `filter`s.
+fn system_expand(
+    set: &ConceptSet,
+    system: &str,
+) -> spi::Result<Result<Vec<ExpandedConcept>, String>> {
+    let version = set.version.as_deref();
+    let expanded = |code: String, display: Option<String>| ExpandedConcept {
+        system: system.to_string(),
+        version: version.map(ToString::to_string),
+        code,
+        display,
+    };
+
+    if let Some(listed) = &set.concept {
+        let mut concepts = Vec::new();
+        for concept in listed {
+            let Some(code) = &concept.code else {
+                continue;
+            };
+
+            let display = match &concept.display {
+                Some(display) => Some(display.clone()),
+                None => terminology::find_concept(system, version, code)?
+                    .and_then(|concept| concept.display),
+            };
+            concepts.push(expanded(code.clone(), display));
+        }
+
+        return Ok(Ok(concepts));
+    }
+
+    if !terminology::is_known_system(system, version)? {
+        return Ok(Err(format!("unknown code system '{system}'")));
+    }
+
+    let mut concepts = terminology::system_concepts(system, version)?;
+    for filter in set.filter.iter().flatten() {
+        let Some((op, value)) = hierarchy_filter(filter) else {
+            return Ok(Err(unsupported_filter(filter)));
+        };
+
+        let descendants = terminology::descendants(system, version, value)?;
+        concepts.retain(|concept| match op {
+            HierarchyOp::IsA => descendants.contains(&concept.code),
+            HierarchyOp::DescendentOf => {
+                concept.code != value && descendants.contains(&concept.code)
+            }
+            HierarchyOp::IsNotA => !descendants.contains(&concept.code),
+        });
+    }
+
+    Ok(Ok(concepts
+        .into_iter()
+        .map(|concept| expanded(concept.code, concept.display))
+        .collect()))
+}